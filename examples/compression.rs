@@ -0,0 +1,93 @@
+//! Prints how large typical payloads are once encrypted, with and without
+//! deflate compression. Run with `cargo run --release --example compression`.
+//!
+//! The messenger is only built as a binary, so the modules needed to seal a
+//! message are compiled in from its sources.
+#![allow(dead_code)]
+
+#[path = "../src/address.rs"]
+mod address;
+#[path = "../src/block.rs"]
+mod block;
+#[path = "../src/chunk.rs"]
+mod chunk;
+#[path = "../src/directory.rs"]
+mod directory;
+#[path = "../src/formats.rs"]
+mod formats;
+#[path = "../src/identity.rs"]
+mod identity;
+#[path = "../src/keys.rs"]
+mod keys;
+#[path = "../src/message.rs"]
+mod message;
+#[path = "../src/payload.rs"]
+mod payload;
+
+use rand::RngCore;
+use std::time::{Duration, Instant};
+
+// `address` refers to blocks from the crate root, as in the binary.
+use block::Block;
+use identity::Identity;
+use keys::{KeyAlgorithm, PrivateKey};
+use message::Message;
+use payload::{Attachment, Compression, Payload};
+
+/// Encrypts `payload` to `identity` itself, returning the ciphertext size and
+/// how long encryption took.
+fn encrypted_size(
+    identity: &Identity,
+    payload: &Payload,
+    compression: Compression,
+) -> (usize, Duration) {
+    let mut message = Message::new(&identity.public_key, identity, payload.clone());
+    let started = Instant::now();
+    message
+        .encrypt_with(&identity.public_key, compression)
+        .expect("Unable to encrypt message");
+
+    (message.ciphertext.len(), started.elapsed())
+}
+
+fn main() {
+    let private_key = PrivateKey::generate(KeyAlgorithm::Rsa).expect("Unable to generate key");
+    let identity = Identity::new("benchmark", private_key);
+
+    let conversation = "Are we still meeting at the usual place tomorrow? Let me know. ".repeat(64);
+    let log: String = (0..256)
+        .map(|i| format!("{:04} node {} accepted block {}\n", i, i % 7, i * 31))
+        .collect();
+    let mut noise = vec![0; 4096];
+    rand::thread_rng().fill_bytes(&mut noise);
+    let payloads = [
+        ("short text", Payload::from("See you at eight.")),
+        ("conversation", Payload::from(conversation.as_str())),
+        ("log file", Payload::from(log.as_str())),
+        (
+            "random bytes",
+            Payload::Attachment(
+                Attachment::new("application/octet-stream", "noise.bin", noise)
+                    .expect("Unable to attach data"),
+            ),
+        ),
+    ];
+
+    println!(
+        "{:<14} {:>10} {:>10} {:>10} {:>8} {:>12}",
+        "payload", "raw", "hex", "deflate", "of raw", "deflate in"
+    );
+    for (name, payload) in &payloads {
+        let (raw, _) = encrypted_size(&identity, payload, Compression::None);
+        let (compressed, elapsed) = encrypted_size(&identity, payload, Compression::Deflate);
+        println!(
+            "{:<14} {:>10} {:>10} {:>10} {:>7.1}% {:>12?}",
+            name,
+            raw,
+            raw * 2,
+            compressed,
+            compressed as f64 * 100.0 / raw as f64,
+            elapsed
+        );
+    }
+}
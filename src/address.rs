@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::directory::KeyRecord;
+use crate::keys::PublicKey;
+use crate::message::decode_public_key;
+use crate::Block;
+
+/// Bytes of the public key hash kept in an address.
+pub const ADDRESS_HASH_SIZE: usize = 20;
+const ADDRESS_VERSION: u8 = 0x1b;
+const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddressError {
+    /// The text is not valid base58.
+    InvalidEncoding,
+    InvalidLength(usize),
+    UnknownVersion(u8),
+    /// The checksum does not match, usually because of a typo.
+    InvalidChecksum,
+    /// The address is valid but its public key has not been seen yet.
+    Unknown(Address),
+    /// The key, or the latest key it was rotated to, has been revoked.
+    Revoked(Address),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::InvalidEncoding => write!(f, "The address is not valid base58"),
+            AddressError::InvalidLength(length) => {
+                write!(f, "The address decodes to {} bytes", length)
+            }
+            AddressError::UnknownVersion(version) => {
+                write!(f, "Unknown address version {}", version)
+            }
+            AddressError::InvalidChecksum => write!(f, "The address checksum does not match"),
+            AddressError::Unknown(address) => {
+                write!(f, "No public key is known for {}", address)
+            }
+            AddressError::Revoked(address) => write!(f, "The key of {} has been revoked", address),
+        }
+    }
+}
+
+/// A short identifier for a public key: the first 20 bytes of the SHA-256 of
+/// its `PublicKey::hash_bytes`, which for RSA is the DER encoding. Written as
+/// base58 of a version byte, the hash and a four byte checksum, so mistyped
+/// addresses are rejected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address([u8; ADDRESS_HASH_SIZE]);
+
+impl Address {
+    pub fn from_public_key(public_key: &PublicKey) -> Self {
+        let digest = Sha256::digest(&public_key.hash_bytes());
+
+        let mut hash = [0u8; ADDRESS_HASH_SIZE];
+        hash.copy_from_slice(&digest[..ADDRESS_HASH_SIZE]);
+
+        Address(hash)
+    }
+
+    /// The address of a key in the encoded form used by `Message::to` and
+    /// `Message::from`.
+    pub fn from_encoded_key(encoded: &str) -> Option<Self> {
+        decode_public_key(encoded).map(|key| Address::from_public_key(&key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let digest = Sha256::digest(&Sha256::digest(data));
+
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&digest[..CHECKSUM_SIZE]);
+
+    checksum
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes: Vec<u8> = Vec::with_capacity(1 + ADDRESS_HASH_SIZE + CHECKSUM_SIZE);
+        bytes.push(ADDRESS_VERSION);
+        bytes.extend_from_slice(&self.0);
+        let checksum = checksum(&bytes);
+        bytes.extend_from_slice(&checksum);
+
+        write!(f, "{}", bs58::encode(bytes).into_string())
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = bs58::decode(s.trim())
+            .into_vec()
+            .map_err(|_| AddressError::InvalidEncoding)?;
+
+        if bytes.len() != 1 + ADDRESS_HASH_SIZE + CHECKSUM_SIZE {
+            return Err(AddressError::InvalidLength(bytes.len()));
+        }
+        if bytes[0] != ADDRESS_VERSION {
+            return Err(AddressError::UnknownVersion(bytes[0]));
+        }
+
+        let (body, check) = bytes.split_at(1 + ADDRESS_HASH_SIZE);
+        if checksum(body) != check {
+            return Err(AddressError::InvalidChecksum);
+        }
+
+        let mut hash = [0u8; ADDRESS_HASH_SIZE];
+        hash.copy_from_slice(&body[1..]);
+
+        Ok(Address(hash))
+    }
+}
+
+/// Maps addresses back to the full public keys needed to encrypt to them.
+/// Keys are learned from local identities and from the senders and authors
+/// of blocks on the chain.
+pub struct AddressBook {
+    keys: HashMap<Address, PublicKey>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        AddressBook {
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Records `public_key` and returns its address.
+    pub fn insert(&mut self, public_key: &PublicKey) -> Address {
+        let address = Address::from_public_key(public_key);
+        self.keys.insert(address, public_key.clone());
+
+        address
+    }
+
+    pub fn get(&self, address: &Address) -> Option<&PublicKey> {
+        self.keys.get(address)
+    }
+
+    /// Learns the key of the block author, of every message sender and
+    /// recipient, and of every key named by a record in `block`.
+    pub fn learn_block(&mut self, block: &Block) {
+        let registered = block.records.iter().flat_map(KeyRecord::public_keys);
+        let encoded_keys = std::iter::once(&block.author_public_key)
+            .chain(
+                block
+                    .data
+                    .iter()
+                    .flat_map(|message| [&message.from, &message.to]),
+            )
+            .chain(registered);
+
+        for encoded in encoded_keys {
+            if let Some(public_key) = decode_public_key(encoded) {
+                self.insert(&public_key);
+            }
+        }
+    }
+
+    /// Resolves a recipient given either as an address or as an encoded key.
+    pub fn resolve(&self, recipient: &str) -> Result<PublicKey, AddressError> {
+        if let Some(public_key) = decode_public_key(recipient.trim()) {
+            return Ok(public_key);
+        }
+
+        let address: Address = recipient.parse()?;
+        match self.get(&address) {
+            Some(public_key) => Ok(public_key.clone()),
+            None => Err(AddressError::Unknown(address)),
+        }
+    }
+
+    /// The encoded key for `address`, as used by `Message::to`.
+    pub fn encoded_key(&self, address: &Address) -> Option<String> {
+        self.get(address).map(PublicKey::encode)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,174 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Misbehavior score at which a peer is disconnected and its address banned.
+pub const BAN_THRESHOLD: u32 = 100;
+/// How long a peer that reached `BAN_THRESHOLD` stays banned.
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Protocol violations a peer is scored for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// The peer sent a block that fails `Block::validate_block`.
+    InvalidBlock,
+    /// The peer announced a frame larger than `MAX_FRAME_SIZE`.
+    OversizedFrame,
+    /// A frame from the peer could not be decoded.
+    MalformedMessage,
+    /// The peer sent an object this node did not ask for.
+    UnrequestedData,
+}
+
+impl Misbehavior {
+    /// Points added to the peer's score. Violations that cannot happen by
+    /// accident ban the peer at once.
+    pub fn score(self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock => BAN_THRESHOLD,
+            Misbehavior::OversizedFrame => 50,
+            Misbehavior::MalformedMessage => 20,
+            Misbehavior::UnrequestedData => 10,
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Misbehavior::InvalidBlock => write!(f, "sent an invalid block"),
+            Misbehavior::OversizedFrame => write!(f, "sent an oversized frame"),
+            Misbehavior::MalformedMessage => write!(f, "sent a malformed message"),
+            Misbehavior::UnrequestedData => write!(f, "sent data that was not requested"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub ip: IpAddr,
+    /// Seconds since the Unix epoch at which the ban ends.
+    pub until: u64,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub enum BanError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// The ban list file could not be parsed.
+    Malformed(PathBuf),
+    NotBanned(IpAddr),
+}
+
+impl fmt::Display for BanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            BanError::Malformed(path) => write!(f, "{} is not a valid ban list", path.display()),
+            BanError::NotBanned(ip) => write!(f, "{} is not banned", ip),
+        }
+    }
+}
+
+impl std::error::Error for BanError {}
+
+/// Addresses the node refuses connections from, saved as JSON so bans
+/// survive restarts. Expired bans are dropped when the list is saved.
+pub struct BanList {
+    path: PathBuf,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// Opens the ban list stored at `path`, which is created on the first
+    /// `save`.
+    pub fn open(path: &Path) -> Result<Self, BanError> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(BanList {
+                    path: path.to_path_buf(),
+                    bans: Vec::new(),
+                })
+            }
+            Err(error) => {
+                return Err(BanError::Io {
+                    path: path.to_path_buf(),
+                    error,
+                })
+            }
+        };
+
+        let bans =
+            serde_json::from_str(&json).map_err(|_| BanError::Malformed(path.to_path_buf()))?;
+
+        Ok(BanList {
+            path: path.to_path_buf(),
+            bans,
+        })
+    }
+
+    /// Writes the bans that have not expired by `now`.
+    pub fn save(&mut self, now: u64) -> Result<(), BanError> {
+        self.bans.retain(|ban| ban.until > now);
+        let json = serde_json::to_string_pretty(&self.bans).expect("Unable to serialize bans");
+
+        let temp_path = self.path.with_extension("tmp");
+        std::fs::write(&temp_path, json).map_err(|error| BanError::Io {
+            path: temp_path.clone(),
+            error,
+        })?;
+        std::fs::rename(&temp_path, &self.path).map_err(|error| BanError::Io {
+            path: self.path.clone(),
+            error,
+        })
+    }
+
+    /// Bans `ip` for `duration` from `now`. Banning an address that is
+    /// already banned keeps whichever ban ends later.
+    pub fn ban(&mut self, ip: IpAddr, duration: Duration, reason: &str, now: u64) -> &Ban {
+        let until = now.saturating_add(duration.as_secs());
+        let index = match self.bans.iter().position(|ban| ban.ip == ip) {
+            Some(index) => {
+                let ban = &mut self.bans[index];
+                if until > ban.until {
+                    ban.until = until;
+                    ban.reason = String::from(reason);
+                }
+                index
+            }
+            None => {
+                self.bans.push(Ban {
+                    ip,
+                    until,
+                    reason: String::from(reason),
+                });
+                self.bans.len() - 1
+            }
+        };
+
+        &self.bans[index]
+    }
+
+    pub fn unban(&mut self, ip: IpAddr) -> Result<Ban, BanError> {
+        match self.bans.iter().position(|ban| ban.ip == ip) {
+            Some(index) => Ok(self.bans.remove(index)),
+            None => Err(BanError::NotBanned(ip)),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: u64) -> bool {
+        self.bans.iter().any(|ban| ban.ip == ip && ban.until > now)
+    }
+
+    /// Every ban still in effect at `now`.
+    pub fn bans(&self, now: u64) -> impl Iterator<Item = &Ban> {
+        self.bans.iter().filter(move |ban| ban.until > now)
+    }
+}
@@ -1,54 +1,130 @@
-use crate::message::{Message, RsaPublicHelpers};
-use rsa::RsaPublicKey;
+use crate::address::Address;
+use crate::directory::{Directory, KeyRecord};
+use crate::keys::{PrivateKey, PublicKey};
+use crate::message::{LegacyMessage, Message};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
 
 const BLOCK_NONCE: &str = "4249";
+/// Largest serialized block accepted by `validate_block`, in bytes.
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+/// Room left in a block for everything that isn't a message.
+pub const BLOCK_OVERHEAD: usize = 4 * 1024;
+/// Seed of the key the genesis block is authored by. It is public, as the
+/// genesis block only has to be the same on every node.
+const GENESIS_SEED: [u8; 32] = *b"biddy blockchain messenger gen 0";
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Block {
     pub node_id: u32,
     pub previous_hash: Option<String>,
     pub hash: String,
-    pub data: Message,
+    pub data: Vec<Message>,
+    /// Public key records published in this block.
+    pub records: Vec<KeyRecord>,
     pub author_public_key: String,
     seed: u32,
 }
 
+/// A block as saved in chain parts written before blocks held several
+/// messages and key records.
+#[derive(Deserialize)]
+pub struct LegacyBlock {
+    pub node_id: u32,
+    pub previous_hash: Option<String>,
+    pub hash: String,
+    pub data: LegacyMessage,
+    pub author_public_key: String,
+    seed: u32,
+}
+
+impl From<LegacyBlock> for Block {
+    /// Keeps the block's hash and linkage. The hash was built from the old
+    /// message encoding, so `computed_hash` won't match it.
+    fn from(legacy: LegacyBlock) -> Self {
+        Block {
+            node_id: legacy.node_id,
+            previous_hash: legacy.previous_hash,
+            hash: legacy.hash,
+            data: vec![Message::from(legacy.data)],
+            records: Vec::new(),
+            author_public_key: legacy.author_public_key,
+            seed: legacy.seed,
+        }
+    }
+}
+
 impl Block {
     pub fn new(
-        data: Message,
-        author: &RsaPublicKey,
+        data: Vec<Message>,
+        author: &PublicKey,
         previous_block_hash: Option<String>,
         node_id: u32,
     ) -> Block {
         let seed: u32 = 0;
         Block {
             hash: Block::generate_block_hash(
-                &hex::encode(author.print_key()),
+                &author.encode(),
                 &data,
+                &[],
                 &previous_block_hash,
                 &seed,
                 &node_id,
             ),
             previous_hash: previous_block_hash,
             data,
-            author_public_key: hex::encode(author.print_key()),
+            records: Vec::new(),
+            author_public_key: author.encode(),
             seed,
             node_id,
         }
     }
 
+    /// The empty block every chain starts from, so that all nodes agree on
+    /// the genesis hash they exchange in the handshake.
+    pub fn genesis() -> Block {
+        static GENESIS: OnceLock<Block> = OnceLock::new();
+        GENESIS
+            .get_or_init(|| {
+                let author = PrivateKey::from_ed25519_seed(GENESIS_SEED).public_key();
+                let mut block = Block::new(Vec::new(), &author, None, 0);
+                block.finalize();
+                block
+            })
+            .clone()
+    }
+
+    /// Attaches key records to the block, updating its hash.
+    pub fn with_records(mut self, records: Vec<KeyRecord>) -> Self {
+        self.records = records;
+        self.hash = Block::generate_block_hash(
+            &self.author_public_key,
+            &self.data,
+            &self.records,
+            &self.previous_hash,
+            &self.seed,
+            &self.node_id,
+        );
+        self
+    }
+
     fn generate_block_hash(
         author: &str,
-        data: &Message,
+        data: &[Message],
+        records: &[KeyRecord],
         previous_hash: &Option<String>,
         seed: &u32,
         node_id: &u32,
     ) -> String {
         let mut hasher = Sha256::new();
         let mut string_to_hash: String = format!("{}", author);
-        string_to_hash += &data.to_string();
+        for message in data {
+            string_to_hash += &message.digest();
+        }
+        for record in records {
+            string_to_hash += &record.digest();
+        }
         match previous_hash {
             Some(str) => {
                 string_to_hash += str;
@@ -69,10 +145,22 @@ impl Block {
         return result;
     }
 
+    /// The hash of the block's content, which `hash` should hold.
+    pub fn computed_hash(&self) -> String {
+        Block::generate_block_hash(
+            &self.author_public_key,
+            &self.data,
+            &self.records,
+            &self.previous_hash,
+            &self.seed,
+            &self.node_id,
+        )
+    }
+
     pub fn finalize(&mut self) {
         let mut is_final: bool = false;
         loop {
-            if &self.hash[..BLOCK_NONCE.len()] == BLOCK_NONCE {
+            if self.hash.starts_with(BLOCK_NONCE) {
                 is_final = true;
             }
             if is_final {
@@ -84,6 +172,7 @@ impl Block {
             self.hash = Block::generate_block_hash(
                 &self.author_public_key,
                 &self.data,
+                &self.records,
                 &self.previous_hash,
                 &self.seed,
                 &self.node_id,
@@ -91,13 +180,29 @@ impl Block {
         }
     }
 
-    pub fn validate_block(&self) -> bool {
-        if &self.hash[..BLOCK_NONCE.len()] != BLOCK_NONCE {
+    /// Checks the hash and proof of work, size and linkage of the block, and
+    /// that its author's key was not revoked at the block's height.
+    pub fn validate_block(&self, directory: &Directory) -> bool {
+        if !self.hash.starts_with(BLOCK_NONCE) {
             return false;
         };
+        if self.computed_hash() != self.hash {
+            return false;
+        }
+        if self.print_block().len() > MAX_BLOCK_SIZE {
+            return false;
+        }
+        match Address::from_encoded_key(&self.author_public_key) {
+            Some(author) => {
+                if directory.is_revoked(&author, self.node_id) {
+                    return false;
+                }
+            }
+            None => return false,
+        }
         match &self.previous_hash {
             Some(ref hash) => {
-                if &hash[..4] != BLOCK_NONCE {
+                if !hash.starts_with(BLOCK_NONCE) {
                     return false;
                 };
             }
@@ -110,6 +215,14 @@ impl Block {
         return true;
     }
 
+    /// Whether every message pruned from the block had expired at `height`
+    /// and `now`. Messages still live must keep their bodies.
+    pub fn prunes_only_expired(&self, height: u32, now: u64) -> bool {
+        self.data
+            .iter()
+            .all(|message| !message.is_pruned() || message.is_expired(height, now))
+    }
+
     pub fn print_block(&self) -> Vec<u8> {
         bincode::serialize(self).unwrap()
     }
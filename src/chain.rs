@@ -1,49 +1,118 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Debug,
     net::SocketAddr,
-    path::Path,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    address::{Address, AddressBook, AddressError},
+    bans::Misbehavior,
+    block::{LegacyBlock, BLOCK_OVERHEAD, MAX_BLOCK_SIZE},
+    contacts::ContactBook,
+    directory::{Directory, DirectoryEntry, KeyRecord, RecordError},
+    identity::Identity,
+    inbox::Inbox,
+    keys::PublicKey,
+    mempool::{Mempool, MempoolError},
+    message::current_time,
+    payload::Payload,
+    relay::{self, InventoryItem, Object, Relay},
+    Block, Message, Network,
 };
-
-use crate::{utils::get_keys, Block, Message, Network};
 
 const CHAIN_STORAGE_LOCATION: &str = "./chain";
+const CHAIN_PART_EXTENSION: &str = ".chain.part";
+/// Starts chain parts written in a versioned format. Parts without it were
+/// written before blocks held several messages, and are read as
+/// `LegacyBlock`s.
+const CHAIN_PART_MAGIC: &[u8; 4] = b"BDYC";
+/// Format of what follows `CHAIN_PART_MAGIC`: the blocks by hash, then the
+/// body hashes of their pruned messages by block hash and message index,
+/// which blocks sent to peers leave out.
+const CHAIN_PART_VERSION: u8 = 1;
+
+type PrunedDigests = HashMap<String, Vec<(usize, String)>>;
+/// Seconds a peer's clock may run ahead of ours before messages it pruned
+/// as expired are still live here.
+const CLOCK_TOLERANCE: u64 = 120;
 
 #[derive(Debug)]
 pub enum ChainError {
     InvalidBlock,
     InvalidChain,
     SaveError,
+    LoadError,
+    MessageTooLarge,
+    InvalidRecord(RecordError),
+}
+
+/// What a node tells peers about its chain during the handshake, kept up to
+/// date as blocks are added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainStatus {
+    pub genesis_hash: Option<String>,
+    pub best_height: u32,
 }
 
-pub struct Chain<'a> {
+/// Where the chain announces new blocks and messages, and takes those
+/// fetched from peers.
+struct RelayLink {
+    relay: Arc<Mutex<Relay>>,
+    received: mpsc::Receiver<(Object, SocketAddr)>,
+}
+
+pub struct Chain {
     peer_list: Arc<Mutex<HashMap<SocketAddr, Box<Network>>>>,
-    chain_directory: &'a Path,
+    chain_directory: PathBuf,
     chain: HashMap<String, Block>,
     latest_block_hash: Option<String>,
     latest_block_id: u32,
+    mempool: Mempool,
+    prune_expired: bool,
+    address_book: AddressBook,
+    directory: Directory,
+    pending_records: Vec<KeyRecord>,
+    status: Arc<Mutex<ChainStatus>>,
+    relay: Option<RelayLink>,
 }
 
-impl<'a> Chain<'a> {
+impl Chain {
     pub fn new(peer_list: Arc<Mutex<HashMap<SocketAddr, Box<Network>>>>) -> Option<Self> {
-        let mut p = Path::new(CHAIN_STORAGE_LOCATION);
+        Chain::open(Path::new(CHAIN_STORAGE_LOCATION), peer_list)
+    }
 
-        if !p.exists() {
-            match std::fs::create_dir(CHAIN_STORAGE_LOCATION) {
-                Ok(_) => {
-                    p = Path::new(CHAIN_STORAGE_LOCATION);
-                }
-                Err(_) => return None,
-            }
+    /// A chain stored in `directory`, which is created if it does not exist.
+    pub fn open(
+        directory: &Path,
+        peer_list: Arc<Mutex<HashMap<SocketAddr, Box<Network>>>>,
+    ) -> Option<Self> {
+        if std::fs::create_dir_all(directory).is_err() {
+            return None;
         }
 
+        let genesis = Block::genesis();
+        let status = ChainStatus {
+            genesis_hash: Some(genesis.hash.to_owned()),
+            best_height: genesis.node_id,
+        };
+
         Some(Chain {
             peer_list,
-            chain_directory: p,
-            chain: HashMap::new(),
-            latest_block_hash: None,
-            latest_block_id: 0,
+            chain_directory: directory.to_path_buf(),
+            latest_block_hash: Some(genesis.hash.to_owned()),
+            latest_block_id: genesis.node_id,
+            chain: HashMap::from([(genesis.hash.to_owned(), genesis)]),
+            mempool: Mempool::new(),
+            prune_expired: false,
+            address_book: AddressBook::new(),
+            directory: Directory::new(),
+            pending_records: Vec::new(),
+            status: Arc::new(Mutex::new(status)),
+            relay: None,
         })
     }
 
@@ -54,13 +123,13 @@ impl<'a> Chain<'a> {
 
         while current_block_option.is_some() && is_valid_chain {
             let current_block = current_block_option.unwrap();
-            if !current_block.validate_block() {
+            if !current_block.validate_block(&self.directory) {
                 return false;
             }
 
             match &current_block.previous_hash {
                 None => {
-                    if current_block.node_id != 0 {
+                    if current_block.hash != Block::genesis().hash {
                         return false;
                     }
                     return is_valid_chain;
@@ -88,17 +157,39 @@ impl<'a> Chain<'a> {
     }
 
     pub fn add_block(&mut self, block: Block) -> Result<(), ChainError> {
+        if !self.prunes_only_expired(&block) {
+            return Err(ChainError::InvalidBlock);
+        }
         if !self.verify_chain(&block) {
             return Err(ChainError::InvalidChain);
         }
 
         let block_hash = String::from(&block.hash);
         let block_id = block.node_id.to_owned();
+        let is_genesis = block.previous_hash.is_none();
+        // Blocks on another branch are kept, but only a block building on
+        // the current tip moves it.
+        let extends_tip = block.previous_hash == self.latest_block_hash;
+
+        let mut directory = self.directory.clone();
+        for record in &block.records {
+            directory
+                .apply(record, block_id)
+                .map_err(ChainError::InvalidRecord)?;
+        }
+        if extends_tip {
+            self.directory = directory;
+            self.mempool.remove_included(&block.data);
+        }
 
         if self.chain.len() >= 50 {
             self.save_chain().unwrap();
         }
 
+        self.address_book.learn_block(&block);
+        if self.relay.is_some() {
+            self.publish(Object::Block(block.clone()));
+        }
         self.chain.insert(block_hash.to_owned(), block);
 
         println!(
@@ -108,42 +199,334 @@ impl<'a> Chain<'a> {
             std::mem::size_of::<HashMap<String, Block>>() * self.chain.len()
         );
 
-        self.latest_block_hash = Some(block_hash);
-        self.latest_block_id = block_id;
+        self.update_status(&block_hash, block_id, is_genesis);
+        if extends_tip {
+            self.latest_block_hash = Some(block_hash);
+            self.latest_block_id = block_id;
+        }
 
         return Ok(());
     }
 
-    pub fn init(&mut self) {
-        let (_, public) = get_keys();
-        let mut block: Option<Block>;
+    /// Adds a block received from the peer at `from`. Peers that send blocks
+    /// failing `validate_block` are reported for misbehavior. Blocks with
+    /// live messages pruned are refused without reporting the peer, as
+    /// whether a message has expired depends on its clock.
+    pub fn receive_block(&mut self, block: Block, from: SocketAddr) -> Result<(), ChainError> {
+        if !block.validate_block(&self.directory) {
+            if let Some(peer) = self.peer_list.lock().unwrap().get(&from) {
+                peer.report(Misbehavior::InvalidBlock);
+            }
+            return Err(ChainError::InvalidBlock);
+        }
+
+        self.add_block(block)
+    }
 
-        loop {
-            let mut message = Message::new(&public, &public, "testing");
-            message.encrypt(&public).unwrap();
-            match &self.latest_block_hash {
-                Some(hash) => match self.chain.get(hash) {
-                    Some(b) => {
-                        block = Some(Block::new(
-                            message,
-                            &public,
-                            Some(b.hash.to_owned()),
-                            self.latest_block_id + 1,
-                        ));
-                    }
-                    None => block = None,
-                },
-                None => {
-                    block = Some(Block::new(message, &public, None, self.latest_block_id));
+    /// Whether the messages pruned from `block` have expired at the height
+    /// the chain will have once it is added, allowing for `CLOCK_TOLERANCE`.
+    fn prunes_only_expired(&self, block: &Block) -> bool {
+        block.prunes_only_expired(
+            self.latest_block_id.max(block.node_id),
+            current_time().saturating_add(CLOCK_TOLERANCE),
+        )
+    }
+
+    /// Announces blocks and messages added from now on through `relay`,
+    /// and adds those the relay fetched from peers and sent to `received`.
+    pub fn connect_relay(
+        &mut self,
+        relay: Arc<Mutex<Relay>>,
+        received: mpsc::Receiver<(Object, SocketAddr)>,
+    ) {
+        self.relay = Some(RelayLink { relay, received });
+    }
+
+    fn publish(&self, object: Object) {
+        if let Some(link) = &self.relay {
+            let outgoing = link
+                .relay
+                .lock()
+                .expect("Unable to lock relay")
+                .publish(object);
+            relay::send_all(&self.peer_list, outgoing);
+        }
+    }
+
+    /// Asks peers for the messages with `digests`, such as those carrying the
+    /// chunks `ChunkStore::missing_messages` lists. They are added by
+    /// `receive_relayed` once they arrive.
+    pub fn request_messages(&self, digests: Vec<String>) {
+        if let Some(link) = &self.relay {
+            let items = digests.into_iter().map(InventoryItem::Message).collect();
+            let outgoing = link
+                .relay
+                .lock()
+                .expect("Unable to lock relay")
+                .request(items, Instant::now());
+            relay::send_all(&self.peer_list, outgoing);
+        }
+    }
+
+    /// Adds the blocks and messages fetched from peers since the last call.
+    pub fn receive_relayed(&mut self) {
+        let received: Vec<(Object, SocketAddr)> = match &self.relay {
+            Some(link) => link.received.try_iter().collect(),
+            None => return,
+        };
+
+        for (object, from) in received {
+            let result = match object {
+                Object::Block(block) => self
+                    .receive_block(block, from)
+                    .map_err(|e| format!("{:?}", e)),
+                Object::Message(message) => {
+                    self.submit_message(message).map_err(|e| format!("{:?}", e))
                 }
+            };
+            if let Err(e) = result {
+                println!("Dropping an object relayed by {:?}: {}", from, e);
+            }
+        }
+    }
+
+    /// Mines until `shutdown` is cancelled, then saves the blocks still held
+    /// in memory.
+    pub fn init(&mut self, identity: &Identity, shutdown: &CancellationToken) {
+        while !shutdown.is_cancelled() {
+            self.receive_relayed();
+
+            let mut message =
+                Message::new(&identity.public_key, identity, Payload::from("testing"));
+            message.encrypt(&identity.public_key).unwrap();
+
+            if let Err(e) = self.submit_message(message) {
+                println!("Dropping a test message: {:?}", e);
+            }
+            if let Err(e) = self.mine_pending(identity) {
+                println!("Unable to mine a block: {:?}", e);
+            }
+        }
+
+        match self.flush() {
+            Ok(_) => println!("Saved the blockchain"),
+            Err(e) => println!("Unable to save the blockchain: {:?}", e),
+        }
+    }
+
+    /// Writes the blocks held in memory to disk.
+    pub fn flush(&mut self) -> Result<(), ChainError> {
+        if self.chain.is_empty() {
+            return Ok(());
+        }
+
+        self.save_chain()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.latest_block_id
+    }
+
+    /// The hash of the block at the tip of the chain, if any.
+    pub fn latest_hash(&self) -> Option<&str> {
+        self.latest_block_hash.as_deref()
+    }
+
+    /// A handle on the status peers are told about, shared with the tasks
+    /// that accept connections.
+    pub fn status(&self) -> Arc<Mutex<ChainStatus>> {
+        self.status.clone()
+    }
+
+    fn update_status(&self, block_hash: &str, block_id: u32, is_genesis: bool) {
+        let mut status = self.status.lock().expect("Unable to lock chain status");
+        if is_genesis {
+            status.genesis_hash = Some(String::from(block_hash));
+        }
+        status.best_height = status.best_height.max(block_id);
+    }
+
+    /// Queues an encrypted message for the next block. Messages that have
+    /// already expired are rejected.
+    pub fn submit_message(&mut self, message: Message) -> Result<(), MempoolError> {
+        let relayed = self.relay.as_ref().map(|_| message.clone());
+        self.mempool
+            .submit(message, self.latest_block_id, current_time())?;
+        if let Some(message) = relayed {
+            self.publish(Object::Message(message));
+        }
+
+        Ok(())
+    }
+
+    /// Mines every message waiting in the mempool, returning the new block hashes.
+    /// Key records waiting in `pending_records` go into the first new block.
+    /// If a block cannot be added, what it and the blocks after it would have
+    /// held is queued again.
+    pub fn mine_pending(&mut self, author: &Identity) -> Result<Vec<String>, ChainError> {
+        if self.mempool.is_empty() && self.pending_records.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let messages = self.mempool.drain(self.latest_block_id, current_time());
+        let mut records = std::mem::take(&mut self.pending_records);
+        // Messages too large for a block are refused by the mempool, so they
+        // always pack.
+        let mut batches: VecDeque<Vec<Message>> = Chain::pack_messages(messages)?.into();
+        if batches.is_empty() {
+            batches.push_back(Vec::new());
+        }
+
+        let mut block_hashes: Vec<String> = Vec::new();
+        while let Some(batch) = batches.pop_front() {
+            match self.mine_block_with_records(batch.clone(), records.clone(), author) {
+                Ok(hash) => {
+                    block_hashes.push(hash);
+                    records.clear();
+                }
+                Err(e) => {
+                    batches.push_front(batch);
+                    self.requeue(batches.into_iter().flatten().collect(), records);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(block_hashes)
+    }
+
+    /// Puts back messages and records that could not be mined, dropping
+    /// those that are no longer valid on top of the chain.
+    fn requeue(&mut self, messages: Vec<Message>, records: Vec<KeyRecord>) {
+        for message in messages {
+            if let Err(e) = self
+                .mempool
+                .submit(message, self.latest_block_id, current_time())
+            {
+                println!("Dropping a message that can no longer be mined: {:?}", e);
+            }
+        }
+        for record in records {
+            if let Err(e) = self.submit_record(record) {
+                println!("Dropping a key record that can no longer be mined: {:?}", e);
+            }
+        }
+    }
+
+    /// Queues a key record for the next block if it is valid on top of the
+    /// current directory and the records already queued, at the height the
+    /// next block will be mined at.
+    pub fn submit_record(&mut self, record: KeyRecord) -> Result<(), RecordError> {
+        let height = self.latest_block_id + 1;
+        let mut directory = self.directory.clone();
+        for pending in &self.pending_records {
+            directory.apply(pending, height)?;
+        }
+        directory.check(&record, height)?;
+
+        self.pending_records.push(record);
+
+        Ok(())
+    }
+
+    pub fn lookup_address(&self, address: &Address) -> Option<&DirectoryEntry> {
+        self.directory.get(address)
+    }
+
+    pub fn lookup_nickname(&self, nickname: &str) -> Option<&DirectoryEntry> {
+        self.directory.find_nickname(nickname)
+    }
+
+    pub fn pending_messages(&self) -> usize {
+        self.mempool.len()
+    }
+
+    /// When enabled, expired message bodies are pruned from blocks as they are
+    /// written to disk. Block headers and message digests are always kept so
+    /// the stored chain can still be validated.
+    pub fn set_pruning(&mut self, enabled: bool) {
+        self.prune_expired = enabled;
+    }
+
+    /// Builds a block holding `messages` on top of the current tip, mines it and
+    /// adds it to the chain. Returns the new block's hash.
+    pub fn mine_block(
+        &mut self,
+        messages: Vec<Message>,
+        author: &Identity,
+    ) -> Result<String, ChainError> {
+        self.mine_block_with_records(messages, Vec::new(), author)
+    }
+
+    fn mine_block_with_records(
+        &mut self,
+        messages: Vec<Message>,
+        records: Vec<KeyRecord>,
+        author: &Identity,
+    ) -> Result<String, ChainError> {
+        let mut block = match &self.latest_block_hash {
+            Some(hash) => match self.chain.get(hash) {
+                Some(b) => Block::new(
+                    messages,
+                    &author.public_key,
+                    Some(b.hash.to_owned()),
+                    self.latest_block_id + 1,
+                ),
+                None => return Err(ChainError::InvalidChain),
+            },
+            None => Block::new(messages, &author.public_key, None, self.latest_block_id),
+        }
+        .with_records(records);
+
+        block.finalize();
+        let block_hash = block.hash.to_owned();
+        self.add_block(block)?;
+
+        Ok(block_hash)
+    }
+
+    /// Commits `messages` in order across as many blocks as needed to respect
+    /// `MAX_BLOCK_SIZE`, returning the hashes of the mined blocks.
+    pub fn commit_messages(
+        &mut self,
+        messages: Vec<Message>,
+        author: &Identity,
+    ) -> Result<Vec<String>, ChainError> {
+        let mut block_hashes: Vec<String> = Vec::new();
+
+        for batch in Chain::pack_messages(messages)? {
+            block_hashes.push(self.mine_block(batch, author)?);
+        }
+
+        Ok(block_hashes)
+    }
+
+    fn pack_messages(messages: Vec<Message>) -> Result<Vec<Vec<Message>>, ChainError> {
+        let capacity = MAX_BLOCK_SIZE - BLOCK_OVERHEAD;
+        let mut batches: Vec<Vec<Message>> = Vec::new();
+        let mut current: Vec<Message> = Vec::new();
+        let mut current_size: usize = 0;
+
+        for message in messages {
+            let size = bincode::serialized_size(&message).unwrap() as usize;
+            if size > capacity {
+                return Err(ChainError::MessageTooLarge);
             }
 
-            if block.is_some() {
-                let mut to_finalize = block.unwrap();
-                to_finalize.finalize();
-                self.add_block(to_finalize).unwrap();
+            if current_size + size > capacity {
+                batches.push(std::mem::take(&mut current));
+                current_size = 0;
             }
+
+            current_size += size;
+            current.push(message);
         }
+
+        if !current.is_empty() {
+            batches.push(current);
+        }
+
+        Ok(batches)
     }
 
     fn get_min_max_block_id(&self) -> Option<(u32, u32)> {
@@ -188,8 +571,259 @@ impl<'a> Chain<'a> {
         return Some((last_id.unwrap(), first_id.unwrap()));
     }
 
+    fn part_files(&self) -> Result<Vec<PathBuf>, ChainError> {
+        let dir = std::fs::read_dir(&self.chain_directory).map_err(|_| ChainError::LoadError)?;
+        let mut parts: Vec<PathBuf> = Vec::new();
+
+        for entry in dir {
+            let path = entry.map_err(|_| ChainError::LoadError)?.path();
+            match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if name.ends_with(CHAIN_PART_EXTENSION) => parts.push(path),
+                _ => {}
+            }
+        }
+
+        Ok(parts)
+    }
+
+    fn read_part(path: &Path) -> Result<HashMap<String, Block>, ChainError> {
+        let bytes = std::fs::read(path).map_err(|_| ChainError::LoadError)?;
+
+        match bytes.strip_prefix(CHAIN_PART_MAGIC) {
+            Some([CHAIN_PART_VERSION, part @ ..]) => {
+                let (mut blocks, pruned): (HashMap<String, Block>, PrunedDigests) =
+                    bincode::deserialize(part).map_err(|_| ChainError::LoadError)?;
+
+                for (hash, pruned) in pruned {
+                    let block = blocks.get_mut(&hash).ok_or(ChainError::LoadError)?;
+                    for (index, body_digest) in pruned {
+                        block
+                            .data
+                            .get_mut(index)
+                            .ok_or(ChainError::LoadError)?
+                            .restore_pruned(body_digest);
+                    }
+                }
+
+                Ok(blocks)
+            }
+            Some(_) => Err(ChainError::LoadError),
+            None => {
+                let legacy: HashMap<String, LegacyBlock> =
+                    bincode::deserialize(&bytes).map_err(|_| ChainError::LoadError)?;
+
+                Ok(legacy
+                    .into_iter()
+                    .map(|(hash, block)| (hash, Block::from(block)))
+                    .collect())
+            }
+        }
+    }
+
+    fn write_part(path: &Path, blocks: &HashMap<String, Block>) -> Result<(), ChainError> {
+        let pruned: PrunedDigests = blocks
+            .iter()
+            .map(|(hash, block)| {
+                let digests: Vec<(usize, String)> = block
+                    .data
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, message)| {
+                        message
+                            .pruned_digest()
+                            .map(|digest| (index, digest.to_owned()))
+                    })
+                    .collect();
+                (hash.to_owned(), digests)
+            })
+            .filter(|(_, digests)| !digests.is_empty())
+            .collect();
+
+        let mut bytes: Vec<u8> = CHAIN_PART_MAGIC.to_vec();
+        bytes.push(CHAIN_PART_VERSION);
+        bytes.extend(bincode::serialize(&(blocks, pruned)).unwrap());
+
+        std::fs::write(path, bytes).map_err(|_| ChainError::SaveError)
+    }
+
+    /// Returns the blocks saved to disk together with those still held in
+    /// memory, ordered by block id.
+    pub fn load_blocks(&self) -> Result<Vec<Block>, ChainError> {
+        let mut blocks: Vec<Block> = Vec::new();
+
+        for part in self.part_files()? {
+            blocks.extend(Chain::read_part(&part)?.into_values());
+        }
+        for block in self.chain.values() {
+            if !blocks.iter().any(|b| b.hash == block.hash) {
+                blocks.push(block.clone());
+            }
+        }
+
+        blocks.sort_by_key(|block| block.node_id);
+
+        Ok(blocks)
+    }
+
+    /// Fills the address book and rebuilds the key directory from every stored
+    /// block so senders and registrations seen before this run can be resolved.
+    pub fn learn_addresses(&mut self) -> Result<(), ChainError> {
+        let mut directory = Directory::new();
+
+        for block in self.load_blocks()? {
+            self.update_status(&block.hash, block.node_id, block.previous_hash.is_none());
+            self.address_book.learn_block(&block);
+            for record in &block.records {
+                // Stored blocks were validated when they were added.
+                let _ = directory.apply(record, block.node_id);
+            }
+        }
+        self.directory = directory;
+
+        Ok(())
+    }
+
+    /// Looks up the public key to encrypt to for a recipient given as a
+    /// registered nickname, an address or a hex encoded key. Rotated keys are
+    /// replaced by the key they were rotated to, and revoked keys are refused.
+    pub fn resolve_recipient(&self, recipient: &str) -> Result<PublicKey, AddressError> {
+        let (address, public_key) = match self.directory.find_nickname(recipient.trim()) {
+            Some(entry) => (entry.address, entry.public_key.clone()),
+            None => {
+                let public_key = self.address_book.resolve(recipient)?;
+                (Address::from_public_key(&public_key), public_key)
+            }
+        };
+
+        self.current_key(address, public_key)
+    }
+
+    /// Like `resolve_recipient`, but names in the local contact book take
+    /// precedence over nicknames registered on the chain.
+    pub fn resolve_contact(
+        &self,
+        contacts: &ContactBook,
+        recipient: &str,
+    ) -> Result<PublicKey, AddressError> {
+        match contacts.get(recipient.trim()) {
+            Some(contact) => self.current_key(contact.address, contact.public_key.clone()),
+            None => self.resolve_recipient(recipient),
+        }
+    }
+
+    /// Follows rotations of `address` to the key currently in use.
+    fn current_key(
+        &self,
+        address: Address,
+        public_key: PublicKey,
+    ) -> Result<PublicKey, AddressError> {
+        let current = self
+            .directory
+            .current_address(&address, self.latest_block_id)
+            .map_err(|e| match e {
+                RecordError::Revoked(revoked) => AddressError::Revoked(revoked),
+                _ => AddressError::Revoked(address),
+            })?;
+        if current == address {
+            return Ok(public_key);
+        }
+
+        match self.directory.get(&current) {
+            Some(entry) => Ok(entry.public_key.clone()),
+            None => Err(AddressError::Unknown(current)),
+        }
+    }
+
+    /// Adds the nickname registered on the chain by everyone `identity` has
+    /// exchanged messages with to `contacts` as a learned contact, unless the
+    /// name or key is already saved. Returns the number of contacts added.
+    pub fn learn_contacts(
+        &self,
+        identity: &Identity,
+        contacts: &mut ContactBook,
+    ) -> Result<usize, ChainError> {
+        let own_key = identity.encoded_public_key();
+        let mut correspondents: HashSet<Address> = HashSet::new();
+        for block in self.load_blocks()? {
+            for message in &block.data {
+                let other = if message.to == own_key {
+                    &message.from
+                } else if message.from == own_key {
+                    &message.to
+                } else {
+                    continue;
+                };
+                if let Some(address) = Address::from_encoded_key(other) {
+                    if address != identity.address {
+                        correspondents.insert(address);
+                    }
+                }
+            }
+        }
+
+        let mut learned = 0;
+        for address in correspondents {
+            if let Some(entry) = self.directory.get(&address) {
+                if let Some(nickname) = &entry.nickname {
+                    if contacts.learn(nickname, &entry.public_key) {
+                        learned += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(learned)
+    }
+
+    pub fn address_book(&mut self) -> &mut AddressBook {
+        &mut self.address_book
+    }
+
+    /// Indexes every block on the chain into `inbox`.
+    pub fn scan_inbox(&self, inbox: &mut Inbox) -> Result<(), ChainError> {
+        for block in self.load_blocks()? {
+            inbox.scan_block(&block);
+        }
+
+        Ok(())
+    }
+
+    fn prune_blocks<'b>(blocks: impl Iterator<Item = &'b mut Block>, height: u32) -> usize {
+        let now = current_time();
+        let mut pruned: usize = 0;
+
+        for block in blocks {
+            for message in block.data.iter_mut() {
+                if !message.is_pruned() && message.is_expired(height, now) {
+                    message.prune();
+                    pruned += 1;
+                }
+            }
+        }
+
+        pruned
+    }
+
+    /// Rewrites the chain parts already on disk without the bodies of expired
+    /// messages. Returns how many messages were pruned.
+    pub fn prune_saved_parts(&self) -> Result<usize, ChainError> {
+        let mut pruned: usize = 0;
+
+        for part in self.part_files()? {
+            let mut blocks = Chain::read_part(&part)?;
+            let count = Chain::prune_blocks(blocks.values_mut(), self.latest_block_id);
+
+            if count > 0 {
+                Chain::write_part(&part, &blocks)?;
+                pruned += count;
+            }
+        }
+
+        Ok(pruned)
+    }
+
     fn save_chain(&mut self) -> Result<(), ChainError> {
-        match std::fs::canonicalize(self.chain_directory) {
+        match std::fs::canonicalize(&self.chain_directory) {
             Ok(buff) => {
                 let min_block_id: u32;
                 let max_block_id: u32;
@@ -203,27 +837,19 @@ impl<'a> Chain<'a> {
                 }
 
                 let chain_name = buff.join(Path::new(&format!(
-                    "chain-{}-{}.chain.part",
-                    min_block_id, max_block_id
+                    "chain-{}-{}{}",
+                    min_block_id, max_block_id, CHAIN_PART_EXTENSION
                 )));
 
+                if self.prune_expired {
+                    Chain::prune_blocks(self.chain.values_mut(), self.latest_block_id);
+                }
+
                 println!("Saving blockchain to {:?}", chain_name.as_os_str());
 
-                let byte_vec: Vec<u8> = bincode::serialize(&self.chain).unwrap();
-                // for block in self.chain.values() {
-                //     byte_vec.append(&mut block.print_block());
-                //     for byte in BLOCK_PADDING {
-                //         byte_vec.push(byte);
-                //     }
-                // }
-
-                match std::fs::write(chain_name, &byte_vec[..]) {
-                    Ok(_) => {
-                        self.chain = HashMap::new();
-                        return Ok(());
-                    }
-                    Err(_) => return Err(ChainError::SaveError),
-                }
+                Chain::write_part(&chain_name, &self.chain)?;
+                self.chain = HashMap::new();
+                return Ok(());
             }
             Err(_) => return Err(ChainError::SaveError),
         }
@@ -0,0 +1,280 @@
+use crate::identity::Identity;
+use crate::keys::PublicKey;
+use crate::message::{Message, MessageError};
+use crate::payload::{Payload, PayloadError, MAX_CHUNKED_PAYLOAD_SIZE};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Number of serialized payload bytes carried by each chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum ChunkError {
+    /// The listed chunk hashes have not been received yet.
+    Missing(Vec<String>),
+    /// A chunk's contents do not match the hash it was stored under.
+    Corrupt(String),
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    Payload(PayloadError),
+}
+
+impl From<PayloadError> for ChunkError {
+    fn from(e: PayloadError) -> Self {
+        ChunkError::Payload(e)
+    }
+}
+
+/// Describes a payload that was split into chunks. The original payload is the
+/// concatenation of the chunks in `chunk_hashes` order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub total_size: u64,
+    pub chunk_hashes: Vec<String>,
+    /// Digests of the messages carrying each chunk, in the same order, which
+    /// missing chunks are requested from peers by. Empty until `seal` fills
+    /// it in.
+    pub chunk_messages: Vec<String>,
+}
+
+/// `Manifest` as sealed in payload versions 1 and 2, before it named the
+/// messages carrying the chunks.
+#[derive(Deserialize)]
+pub struct ManifestV1 {
+    total_size: u64,
+    chunk_hashes: Vec<String>,
+}
+
+impl From<ManifestV1> for Manifest {
+    fn from(manifest: ManifestV1) -> Self {
+        Manifest {
+            total_size: manifest.total_size,
+            chunk_hashes: manifest.chunk_hashes,
+            chunk_messages: Vec::new(),
+        }
+    }
+}
+
+pub fn chunk_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Splits `payload` into `Payload::Chunk`s of at most `CHUNK_SIZE` bytes and the
+/// `Manifest` needed to put them back together.
+pub fn split(payload: &Payload) -> Result<(Manifest, Vec<Payload>), PayloadError> {
+    let bytes = bincode::serialize(payload).map_err(PayloadError::Serialization)?;
+
+    if bytes.len() > MAX_CHUNKED_PAYLOAD_SIZE {
+        return Err(PayloadError::TooLarge {
+            size: bytes.len(),
+            max: MAX_CHUNKED_PAYLOAD_SIZE,
+        });
+    }
+
+    let mut chunk_hashes: Vec<String> = Vec::new();
+    let mut chunks: Vec<Payload> = Vec::new();
+
+    for piece in bytes.chunks(CHUNK_SIZE) {
+        chunk_hashes.push(chunk_hash(piece));
+        chunks.push(Payload::Chunk(piece.to_vec()));
+    }
+
+    let manifest = Manifest {
+        total_size: bytes.len() as u64,
+        chunk_hashes,
+        chunk_messages: Vec::new(),
+    };
+
+    Ok((manifest, chunks))
+}
+
+/// Splits `payload` with `split` and encrypts each chunk to `to` as a message
+/// from `from`, followed by the manifest naming those messages. The messages
+/// are returned in the order they should be committed.
+pub fn seal(
+    payload: &Payload,
+    to: &PublicKey,
+    from: &Identity,
+) -> Result<Vec<Message>, MessageError> {
+    let (mut manifest, chunks) = split(payload)?;
+    let mut messages: Vec<Message> = Vec::with_capacity(chunks.len() + 1);
+
+    for chunk in chunks {
+        let mut message = Message::new(to, from, chunk);
+        message.encrypt(to)?;
+        manifest.chunk_messages.push(message.digest());
+        messages.push(message);
+    }
+
+    let mut message = Message::new(to, from, Payload::Manifest(manifest));
+    message.encrypt(to)?;
+    messages.push(message);
+
+    Ok(messages)
+}
+
+/// Rejects a manifest whose size `split` could not have produced, before
+/// anything is allocated for it.
+fn check_size(manifest: &Manifest) -> Result<(), ChunkError> {
+    if manifest.total_size > MAX_CHUNKED_PAYLOAD_SIZE as u64 {
+        return Err(ChunkError::Payload(PayloadError::TooLarge {
+            size: usize::try_from(manifest.total_size).unwrap_or(usize::MAX),
+            max: MAX_CHUNKED_PAYLOAD_SIZE,
+        }));
+    }
+    let capacity = (manifest.chunk_hashes.len() as u64).saturating_mul(CHUNK_SIZE as u64);
+    if manifest.total_size > capacity {
+        return Err(ChunkError::SizeMismatch {
+            expected: manifest.total_size,
+            actual: capacity,
+        });
+    }
+
+    Ok(())
+}
+
+fn check_chunk(data: &[u8]) -> Result<(), ChunkError> {
+    if data.len() > CHUNK_SIZE {
+        return Err(ChunkError::Payload(PayloadError::TooLarge {
+            size: data.len(),
+            max: CHUNK_SIZE,
+        }));
+    }
+
+    Ok(())
+}
+
+/// Collects received chunks by hash until a manifest can be reassembled.
+pub struct ChunkStore {
+    chunks: HashMap<String, Vec<u8>>,
+}
+
+impl ChunkStore {
+    pub fn new() -> Self {
+        ChunkStore {
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Stores a chunk under the hash of its contents and returns that hash.
+    /// Chunks larger than `split` makes are rejected.
+    pub fn insert(&mut self, data: Vec<u8>) -> Result<String, ChunkError> {
+        check_chunk(&data)?;
+        let hash = chunk_hash(&data);
+        self.chunks.insert(hash.to_owned(), data);
+
+        Ok(hash)
+    }
+
+    /// Stores a chunk that a peer claims has the hash `expected_hash`, rejecting
+    /// it if the contents do not match.
+    pub fn insert_verified(
+        &mut self,
+        expected_hash: &str,
+        data: Vec<u8>,
+    ) -> Result<(), ChunkError> {
+        check_chunk(&data)?;
+        if chunk_hash(&data) != expected_hash {
+            return Err(ChunkError::Corrupt(String::from(expected_hash)));
+        }
+        self.chunks.insert(String::from(expected_hash), data);
+
+        Ok(())
+    }
+
+    /// Stores the chunk carried by a decrypted payload. Returns the chunk hash,
+    /// or `None` when the payload is not a chunk.
+    pub fn insert_payload(&mut self, payload: &Payload) -> Result<Option<String>, ChunkError> {
+        match payload {
+            Payload::Chunk(data) => self.insert(data.to_owned()).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.chunks.contains_key(hash)
+    }
+
+    /// Returns the hashes from `manifest` that still need to be fetched, in order
+    /// and without duplicates.
+    pub fn missing(&self, manifest: &Manifest) -> Vec<String> {
+        let mut missing: Vec<String> = Vec::new();
+
+        for hash in &manifest.chunk_hashes {
+            if !self.chunks.contains_key(hash) && !missing.contains(hash) {
+                missing.push(hash.to_owned());
+            }
+        }
+
+        missing
+    }
+
+    /// Digests of the messages carrying the chunks from `manifest` that still
+    /// need to be fetched, to request from peers. Chunks the manifest names no
+    /// message for are left out.
+    pub fn missing_messages(&self, manifest: &Manifest) -> Vec<String> {
+        let mut digests: Vec<String> = Vec::new();
+
+        for (hash, digest) in manifest.chunk_hashes.iter().zip(&manifest.chunk_messages) {
+            if !self.chunks.contains_key(hash) && !digests.contains(digest) {
+                digests.push(digest.to_owned());
+            }
+        }
+
+        digests
+    }
+
+    /// Verifies every chunk listed in `manifest` and rebuilds the original payload.
+    pub fn reassemble(&self, manifest: &Manifest) -> Result<Payload, ChunkError> {
+        check_size(manifest)?;
+        let missing = self.missing(manifest);
+        if !missing.is_empty() {
+            return Err(ChunkError::Missing(missing));
+        }
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(manifest.total_size as usize);
+
+        for hash in &manifest.chunk_hashes {
+            let data = &self.chunks[hash];
+            if &chunk_hash(data) != hash {
+                return Err(ChunkError::Corrupt(hash.to_owned()));
+            }
+            // A manifest may list the same chunk many times, so stop as soon
+            // as it adds up to more than the stated size.
+            let size = (bytes.len() + data.len()) as u64;
+            if size > manifest.total_size {
+                return Err(ChunkError::SizeMismatch {
+                    expected: manifest.total_size,
+                    actual: size,
+                });
+            }
+            bytes.extend_from_slice(data);
+        }
+
+        if bytes.len() as u64 != manifest.total_size {
+            return Err(ChunkError::SizeMismatch {
+                expected: manifest.total_size,
+                actual: bytes.len() as u64,
+            });
+        }
+
+        Ok(Payload::from_bytes(&bytes)?)
+    }
+
+    /// Drops the chunks belonging to `manifest`, typically once it has been
+    /// reassembled.
+    pub fn remove(&mut self, manifest: &Manifest) {
+        for hash in &manifest.chunk_hashes {
+            self.chunks.remove(hash);
+        }
+    }
+}
+
+impl Default for ChunkStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
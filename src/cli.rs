@@ -0,0 +1,235 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::formats::ExportFormat;
+use crate::keys::KeyAlgorithm;
+use crate::limits::{Limits, MAX_QUEUED_BYTES};
+
+pub const USAGE: &str = "usage: blockchain-messenger [--keystore <dir>] [--identity <name>]
+                            [--algorithm <rsa|ed25519>] [limits] [command]
+
+commands:
+    run                   start the node (default)
+    register [nickname]   start the node and publish the identity's key
+    send <to> <text>      start the node and send <text> to a contact,
+                          nickname, address or key
+    inbox                 list received messages
+    change-passphrase     re-encrypt the identity's private key
+    identities            list the identities in the keystore
+    new-identity <name>   generate a new identity
+    use-identity <name>   make <name> the active identity
+    new-mnemonic [name]   create an identity backed by a new recovery phrase
+    restore [name]        regenerate an identity from its recovery phrase
+    export <fmt> [file]   write the identity as pem, der, openssh or contact
+    import <name> <file>  add a PKCS#8 PEM or DER private key as <name>
+    add-contact <file>    save an OpenSSH public key or contact card
+    verify-contact <name> <fingerprint>
+                          mark a contact as verified
+    remove-contact <name> forget a contact
+    contacts              list saved contacts
+    ban <ip> [hours]      refuse connections from <ip> (default 24 hours)
+    unban <ip>            lift the ban on <ip>
+    bans                  list banned addresses
+
+limits, applied to the peers of a running node:
+    --max-peers <n>       inbound connections open at once (default 64)
+    --max-peers-per-ip <n>
+                          inbound connections from one address (default 4)
+    --max-bytes-per-second <n>
+                          data read from each peer (default 2 MiB)
+    --max-messages-per-second <n>
+                          messages read from each peer (default 200)
+    --max-queued-bytes <n>
+                          data received from a peer awaiting processing
+                          (default 8 MiB, at most 1 GiB)
+
+--algorithm picks the key type of newly created identities (default rsa).
+new-mnemonic and restore act on the active identity when no name is given.
+A recovery phrase gives one ed25519 identity for each recovery passphrase,
+whatever name it is restored under.";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    /// Run the node after queueing a registration of the active identity,
+    /// optionally claiming a nickname.
+    Register(Option<String>),
+    /// Run the node after queueing a message to a recipient.
+    Send(String, String),
+    ListInbox,
+    ChangePassphrase,
+    ListIdentities,
+    NewIdentity(String),
+    UseIdentity(String),
+    /// Generate a recovery phrase and derive an identity from it, named as
+    /// given or the active identity.
+    NewMnemonic(Option<String>),
+    /// Re-derive an identity from its recovery phrase, named as given or the
+    /// active identity.
+    Restore(Option<String>),
+    /// Export the identity, to standard output when no file is given.
+    Export(ExportFormat, Option<PathBuf>),
+    Import(String, PathBuf),
+    AddContact(PathBuf),
+    VerifyContact(String, String),
+    RemoveContact(String),
+    ListContacts,
+    /// Ban an address, for the given number of hours or the default ban
+    /// duration.
+    Ban(IpAddr, Option<u64>),
+    Unban(IpAddr),
+    ListBans,
+}
+
+/// The parsed command line. `keystore` and `identity` are `None` when the
+/// keystore's own defaults should be used.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Options {
+    pub keystore: Option<PathBuf>,
+    pub identity: Option<String>,
+    /// Key type used when an identity has to be created.
+    pub algorithm: KeyAlgorithm,
+    pub limits: Limits,
+    pub command: Command,
+}
+
+/// Parses the arguments following the program name.
+pub fn parse<I>(args: I) -> Result<Options, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+    let mut keystore: Option<PathBuf> = None;
+    let mut identity: Option<String> = None;
+    let mut algorithm = KeyAlgorithm::Rsa;
+    let mut limits = Limits::default();
+    let mut positional: Vec<String> = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--keystore" => match args.next() {
+                Some(dir) => keystore = Some(PathBuf::from(dir)),
+                None => return Err(String::from("--keystore needs a directory")),
+            },
+            "--identity" => match args.next() {
+                Some(name) => identity = Some(name),
+                None => return Err(String::from("--identity needs a name")),
+            },
+            "--algorithm" => match args.next() {
+                Some(name) => algorithm = name.parse()?,
+                None => return Err(String::from("--algorithm needs rsa or ed25519")),
+            },
+            "--max-peers" => limits.max_inbound = parse_limit(&arg, args.next())?,
+            "--max-peers-per-ip" => limits.max_per_ip = parse_limit(&arg, args.next())?,
+            "--max-bytes-per-second" => limits.bytes_per_second = parse_limit(&arg, args.next())?,
+            "--max-messages-per-second" => {
+                limits.messages_per_second = parse_limit(&arg, args.next())?
+            }
+            "--max-queued-bytes" => {
+                limits.max_queued_bytes = parse_limit(&arg, args.next())?;
+                if limits.max_queued_bytes > MAX_QUEUED_BYTES {
+                    return Err(format!("{} can be at most {}", arg, MAX_QUEUED_BYTES));
+                }
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match positional.next().as_deref() {
+        None | Some("run") => Command::Run,
+        Some("register") => Command::Register(positional.next()),
+        Some("send") => match (positional.next(), positional.next()) {
+            (Some(recipient), Some(text)) => Command::Send(recipient, text),
+            _ => return Err(String::from("send needs a recipient and a message")),
+        },
+        Some("inbox") => Command::ListInbox,
+        Some("change-passphrase") => Command::ChangePassphrase,
+        Some("identities") => Command::ListIdentities,
+        Some("new-identity") => match positional.next() {
+            Some(name) => Command::NewIdentity(name),
+            None => return Err(String::from("new-identity needs a name")),
+        },
+        Some("use-identity") => match positional.next() {
+            Some(name) => Command::UseIdentity(name),
+            None => return Err(String::from("use-identity needs a name")),
+        },
+        Some("new-mnemonic") => Command::NewMnemonic(positional.next()),
+        Some("restore") => Command::Restore(positional.next()),
+        Some("export") => match positional.next() {
+            Some(format) => Command::Export(format.parse()?, positional.next().map(PathBuf::from)),
+            None => return Err(String::from("export needs a format")),
+        },
+        Some("import") => match (positional.next(), positional.next()) {
+            (Some(name), Some(file)) => Command::Import(name, PathBuf::from(file)),
+            _ => return Err(String::from("import needs a name and a file")),
+        },
+        Some("add-contact") => match positional.next() {
+            Some(file) => Command::AddContact(PathBuf::from(file)),
+            None => return Err(String::from("add-contact needs a file")),
+        },
+        Some("verify-contact") => match (positional.next(), positional.next()) {
+            (Some(name), Some(fingerprint)) => Command::VerifyContact(name, fingerprint),
+            _ => {
+                return Err(String::from(
+                    "verify-contact needs a name and a fingerprint",
+                ))
+            }
+        },
+        Some("remove-contact") => match positional.next() {
+            Some(name) => Command::RemoveContact(name),
+            None => return Err(String::from("remove-contact needs a name")),
+        },
+        Some("contacts") => Command::ListContacts,
+        Some("ban") => match positional.next() {
+            Some(ip) => {
+                let hours = match positional.next() {
+                    Some(hours) => Some(
+                        hours
+                            .parse()
+                            .map_err(|_| format!("{:?} is not a number of hours", hours))?,
+                    ),
+                    None => None,
+                };
+                Command::Ban(parse_ip(&ip)?, hours)
+            }
+            None => return Err(String::from("ban needs an IP address")),
+        },
+        Some("unban") => match positional.next() {
+            Some(ip) => Command::Unban(parse_ip(&ip)?),
+            None => return Err(String::from("unban needs an IP address")),
+        },
+        Some("bans") => Command::ListBans,
+        Some(other) => return Err(format!("Unknown command {:?}", other)),
+    };
+
+    if let Some(extra) = positional.next() {
+        return Err(format!("Unexpected argument {:?}", extra));
+    }
+
+    Ok(Options {
+        keystore,
+        identity,
+        algorithm,
+        limits,
+        command,
+    })
+}
+
+fn parse_ip(text: &str) -> Result<IpAddr, String> {
+    text.parse()
+        .map_err(|_| format!("{:?} is not an IP address", text))
+}
+
+/// Parses the positive number following `flag`.
+fn parse_limit<T>(flag: &str, value: Option<String>) -> Result<T, String>
+where
+    T: FromStr + Default + PartialEq,
+{
+    let value = value.ok_or_else(|| format!("{} needs a number", flag))?;
+    match value.parse::<T>() {
+        Ok(limit) if limit != T::default() => Ok(limit),
+        _ => Err(format!("{} needs a positive number, not {:?}", flag, value)),
+    }
+}
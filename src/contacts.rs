@@ -0,0 +1,337 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::address::Address;
+use crate::formats::{self, ContactCard, FormatError};
+use crate::identity::fingerprint;
+use crate::keys::PublicKey;
+
+/// Fewest fingerprint characters accepted when verifying a contact, matching
+/// `Identity::short_fingerprint`.
+const MIN_FINGERPRINT_LENGTH: usize = 16;
+/// Longest display name accepted for a contact.
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Trust {
+    /// Learned from the chain or an imported card; the fingerprint has not
+    /// been checked with the contact.
+    Learned,
+    /// The fingerprint was confirmed with the contact.
+    Verified,
+}
+
+impl fmt::Display for Trust {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trust::Learned => write!(f, "learned"),
+            Trust::Verified => write!(f, "verified"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contact {
+    pub name: String,
+    pub address: Address,
+    pub public_key: PublicKey,
+    pub trust: Trust,
+}
+
+/// The on disk form of a `Contact`; the address is derived from the key.
+#[derive(Serialize, Deserialize)]
+struct StoredContact {
+    name: String,
+    public_key: String,
+    trust: Trust,
+}
+
+#[derive(Debug)]
+pub enum ContactError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// The contact book file could not be parsed.
+    Malformed(PathBuf),
+    InvalidName(String),
+    /// Another contact already uses the name.
+    NameTaken(String),
+    /// The key is already saved under the given name.
+    AlreadyKnown(String),
+    NotFound(String),
+    /// The fingerprint given to `verify` is not the contact's.
+    FingerprintMismatch(String),
+    Format(FormatError),
+}
+
+impl From<FormatError> for ContactError {
+    fn from(e: FormatError) -> Self {
+        ContactError::Format(e)
+    }
+}
+
+impl fmt::Display for ContactError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContactError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ContactError::Malformed(path) => {
+                write!(f, "{} is not a valid contact book", path.display())
+            }
+            ContactError::InvalidName(name) => write!(
+                f,
+                "{:?} is not a valid contact name, use 1 to {} printable characters",
+                name, MAX_NAME_LENGTH
+            ),
+            ContactError::NameTaken(name) => {
+                write!(f, "A different key is already saved as {:?}", name)
+            }
+            ContactError::AlreadyKnown(name) => {
+                write!(f, "This key is already saved as {:?}", name)
+            }
+            ContactError::NotFound(name) => write!(f, "There is no contact named {:?}", name),
+            ContactError::FingerprintMismatch(name) => {
+                write!(f, "The fingerprint does not match the key of {:?}", name)
+            }
+            ContactError::Format(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ContactError {}
+
+fn validate_name(name: &str) -> Result<(), ContactError> {
+    let length = name.chars().count();
+    if length == 0
+        || length > MAX_NAME_LENGTH
+        || name.trim() != name
+        || name.chars().any(char::is_control)
+    {
+        return Err(ContactError::InvalidName(String::from(name)));
+    }
+
+    Ok(())
+}
+
+/// Lowercase hex digits of a fingerprint typed by a user, ignoring the
+/// spaces and colons it may be grouped with.
+fn normalize_fingerprint(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Display names for public keys, saved as JSON so they survive restarts.
+/// Names are local to this user and take precedence over nicknames
+/// registered on the chain.
+pub struct ContactBook {
+    path: PathBuf,
+    contacts: Vec<Contact>,
+}
+
+impl ContactBook {
+    /// Opens the contact book stored at `path`, which is created on the
+    /// first `save`.
+    pub fn open(path: &Path) -> Result<Self, ContactError> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(ContactBook {
+                    path: path.to_path_buf(),
+                    contacts: Vec::new(),
+                })
+            }
+            Err(error) => {
+                return Err(ContactError::Io {
+                    path: path.to_path_buf(),
+                    error,
+                })
+            }
+        };
+
+        let stored: Vec<StoredContact> =
+            serde_json::from_str(&json).map_err(|_| ContactError::Malformed(path.to_path_buf()))?;
+        let mut contacts: Vec<Contact> = Vec::with_capacity(stored.len());
+        for contact in stored {
+            let public_key = PublicKey::decode(&contact.public_key)
+                .ok_or_else(|| ContactError::Malformed(path.to_path_buf()))?;
+            contacts.push(Contact {
+                name: contact.name,
+                address: Address::from_public_key(&public_key),
+                public_key,
+                trust: contact.trust,
+            });
+        }
+
+        Ok(ContactBook {
+            path: path.to_path_buf(),
+            contacts,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), ContactError> {
+        let stored: Vec<StoredContact> = self
+            .contacts
+            .iter()
+            .map(|contact| StoredContact {
+                name: contact.name.clone(),
+                public_key: contact.public_key.encode(),
+                trust: contact.trust,
+            })
+            .collect();
+        let json = serde_json::to_string_pretty(&stored).expect("Unable to serialize contacts");
+
+        let temp_path = self.path.with_extension("tmp");
+        std::fs::write(&temp_path, json).map_err(|error| ContactError::Io {
+            path: temp_path.clone(),
+            error,
+        })?;
+        std::fs::rename(&temp_path, &self.path).map_err(|error| ContactError::Io {
+            path: self.path.clone(),
+            error,
+        })
+    }
+
+    /// Every contact, sorted by name.
+    pub fn contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|contact| contact.name == name)
+    }
+
+    pub fn find_address(&self, address: &Address) -> Option<&Contact> {
+        self.contacts
+            .iter()
+            .find(|contact| &contact.address == address)
+    }
+
+    /// Saves `public_key` as `name`. Adding a key that is already saved under
+    /// the same name only raises its trust.
+    pub fn add(
+        &mut self,
+        name: &str,
+        public_key: &PublicKey,
+        trust: Trust,
+    ) -> Result<&Contact, ContactError> {
+        validate_name(name)?;
+        let address = Address::from_public_key(public_key);
+
+        if let Some(existing) = self.find_address(&address) {
+            if existing.name != name {
+                return Err(ContactError::AlreadyKnown(existing.name.clone()));
+            }
+        } else if self.get(name).is_some() {
+            return Err(ContactError::NameTaken(String::from(name)));
+        }
+
+        let index = match self.contacts.iter().position(|c| c.address == address) {
+            Some(index) => {
+                let contact = &mut self.contacts[index];
+                contact.trust = contact.trust.max(trust);
+                index
+            }
+            None => {
+                let index = self
+                    .contacts
+                    .partition_point(|contact| contact.name.as_str() < name);
+                self.contacts.insert(
+                    index,
+                    Contact {
+                        name: String::from(name),
+                        address,
+                        public_key: public_key.clone(),
+                        trust,
+                    },
+                );
+                index
+            }
+        };
+
+        Ok(&self.contacts[index])
+    }
+
+    /// Adds a contact from a contact card or an OpenSSH public key line,
+    /// whose comment becomes the name.
+    pub fn import(&mut self, text: &str) -> Result<&Contact, ContactError> {
+        let (name, public_key) = if text.trim_start().starts_with('{') {
+            let card = ContactCard::from_json(text)?;
+            let public_key = card.verify()?;
+            (card.name, public_key)
+        } else {
+            let (public_key, comment) = formats::from_openssh(text.trim())?;
+            let name = if comment.is_empty() {
+                Address::from_public_key(&public_key).to_string()
+            } else {
+                comment
+            };
+            (name, public_key)
+        };
+
+        self.add(&name, &public_key, Trust::Learned)
+    }
+
+    /// Adds a contact learned from the chain unless its name or key is
+    /// already saved. Returns whether it was added.
+    pub fn learn(&mut self, name: &str, public_key: &PublicKey) -> bool {
+        let address = Address::from_public_key(public_key);
+        if self.get(name).is_some() || self.find_address(&address).is_some() {
+            return false;
+        }
+
+        self.add(name, public_key, Trust::Learned).is_ok()
+    }
+
+    /// Marks `name` as verified if `fingerprint_text` is its fingerprint, or
+    /// at least the first 16 characters of it.
+    pub fn verify(&mut self, name: &str, fingerprint_text: &str) -> Result<(), ContactError> {
+        let contact = self
+            .contacts
+            .iter_mut()
+            .find(|contact| contact.name == name)
+            .ok_or_else(|| ContactError::NotFound(String::from(name)))?;
+
+        let given = normalize_fingerprint(fingerprint_text);
+        if given.len() < MIN_FINGERPRINT_LENGTH
+            || !fingerprint(&contact.public_key).starts_with(&given)
+        {
+            return Err(ContactError::FingerprintMismatch(String::from(name)));
+        }
+
+        contact.trust = Trust::Verified;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Contact, ContactError> {
+        match self
+            .contacts
+            .iter()
+            .position(|contact| contact.name == name)
+        {
+            Some(index) => Ok(self.contacts.remove(index)),
+            None => Err(ContactError::NotFound(String::from(name))),
+        }
+    }
+
+    /// How to show `address`: the contact name, marked with `?` until it is
+    /// verified, or the address itself for strangers.
+    pub fn label(&self, address: &Address) -> String {
+        match self.find_address(address) {
+            Some(contact) if contact.trust == Trust::Verified => contact.name.clone(),
+            Some(contact) => format!("{}?", contact.name),
+            None => address.to_string(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.contacts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+}
@@ -0,0 +1,475 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::address::Address;
+use crate::identity::Identity;
+use crate::keys::PublicKey;
+use crate::message::decode_public_key;
+
+const REGISTRATION_DOMAIN: &str = "biddy-registration-v1";
+const ROTATION_DOMAIN: &str = "biddy-rotation-v1";
+const REVOCATION_DOMAIN: &str = "biddy-revocation-v1";
+const MIN_NICKNAME_LENGTH: usize = 3;
+const MAX_NICKNAME_LENGTH: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecordError {
+    InvalidKey,
+    InvalidSignature,
+    InvalidNickname(String),
+    /// The nickname is already registered to another address.
+    NicknameTaken(String),
+    /// An update must carry a higher sequence number than the record it
+    /// replaces.
+    StaleSequence {
+        current: u64,
+        received: u64,
+    },
+    /// The key was revoked at or before the height the record applies to.
+    Revoked(Address),
+    /// The key has already been rotated to another key.
+    AlreadyRotated(Address),
+    /// The key is already revoked from the same height or earlier.
+    AlreadyRevoked(Address),
+    /// The new key of a rotation is already in use.
+    KeyInUse(Address),
+    /// The revocation is signed by neither the key nor a key it was rotated to.
+    UnauthorizedRevocation,
+    /// Revocations can't take effect before the block they are mined in.
+    RetroactiveRevocation {
+        height: u32,
+        effective_height: u32,
+    },
+}
+
+/// Publishes a public key, and optionally a nickname for it, on the chain.
+/// Signed by the key being registered, so only its owner can create or update
+/// the entry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    /// Encoded as in `Message::from`.
+    pub public_key: String,
+    pub nickname: Option<String>,
+    /// Increases with every update so an old registration can't be replayed.
+    pub sequence: u64,
+    pub signature: Vec<u8>,
+}
+
+impl Registration {
+    pub fn new(identity: &Identity, nickname: Option<&str>, sequence: u64) -> Self {
+        let mut registration = Registration {
+            public_key: identity.encoded_public_key(),
+            nickname: nickname.map(String::from),
+            sequence,
+            signature: Vec::new(),
+        };
+        registration.signature = identity.private_key.sign(&registration.signed_bytes());
+
+        registration
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&(
+            REGISTRATION_DOMAIN,
+            &self.public_key,
+            &self.nickname,
+            self.sequence,
+        ))
+        .unwrap()
+    }
+
+    /// Checks the nickname format and that the record is signed by the key
+    /// it registers, returning that key.
+    pub fn verify(&self) -> Result<PublicKey, RecordError> {
+        let public_key = decode_public_key(&self.public_key).ok_or(RecordError::InvalidKey)?;
+
+        if let Some(nickname) = &self.nickname {
+            validate_nickname(nickname)?;
+        }
+        if !public_key.verify(&self.signed_bytes(), &self.signature) {
+            return Err(RecordError::InvalidSignature);
+        }
+
+        Ok(public_key)
+    }
+}
+
+fn validate_nickname(nickname: &str) -> Result<(), RecordError> {
+    let valid = (MIN_NICKNAME_LENGTH..=MAX_NICKNAME_LENGTH).contains(&nickname.len())
+        && nickname
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+
+    if !valid {
+        return Err(RecordError::InvalidNickname(String::from(nickname)));
+    }
+
+    Ok(())
+}
+
+/// Replaces `old_key` with `new_key`. Signed by the old key to authorize the
+/// change and by the new key to prove it is held by the same owner.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Rotation {
+    pub old_key: String,
+    pub new_key: String,
+    pub old_signature: Vec<u8>,
+    pub new_signature: Vec<u8>,
+}
+
+impl Rotation {
+    pub fn new(old: &Identity, new: &Identity) -> Self {
+        let mut rotation = Rotation {
+            old_key: old.encoded_public_key(),
+            new_key: new.encoded_public_key(),
+            old_signature: Vec::new(),
+            new_signature: Vec::new(),
+        };
+        let bytes = rotation.signed_bytes();
+        rotation.old_signature = old.private_key.sign(&bytes);
+        rotation.new_signature = new.private_key.sign(&bytes);
+
+        rotation
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&(ROTATION_DOMAIN, &self.old_key, &self.new_key)).unwrap()
+    }
+
+    /// Checks both signatures, returning the old and new keys.
+    pub fn verify(&self) -> Result<(PublicKey, PublicKey), RecordError> {
+        let old_key = decode_public_key(&self.old_key).ok_or(RecordError::InvalidKey)?;
+        let new_key = decode_public_key(&self.new_key).ok_or(RecordError::InvalidKey)?;
+
+        let bytes = self.signed_bytes();
+        if !old_key.verify(&bytes, &self.old_signature)
+            || !new_key.verify(&bytes, &self.new_signature)
+        {
+            return Err(RecordError::InvalidSignature);
+        }
+
+        Ok((old_key, new_key))
+    }
+}
+
+/// Marks `public_key` invalid from `effective_height` on. Signed either by the
+/// key itself or by a key it was rotated to, so a lost key can still be
+/// revoked after a rotation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Revocation {
+    pub public_key: String,
+    pub signer: String,
+    pub effective_height: u32,
+    pub signature: Vec<u8>,
+}
+
+impl Revocation {
+    pub fn new(public_key: &PublicKey, signer: &Identity, effective_height: u32) -> Self {
+        let mut revocation = Revocation {
+            public_key: public_key.encode(),
+            signer: signer.encoded_public_key(),
+            effective_height,
+            signature: Vec::new(),
+        };
+        revocation.signature = signer.private_key.sign(&revocation.signed_bytes());
+
+        revocation
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&(
+            REVOCATION_DOMAIN,
+            &self.public_key,
+            &self.signer,
+            self.effective_height,
+        ))
+        .unwrap()
+    }
+
+    /// Checks the signature, returning the addresses of the revoked key and
+    /// of the signer.
+    pub fn verify(&self) -> Result<(Address, Address), RecordError> {
+        let revoked = decode_public_key(&self.public_key).ok_or(RecordError::InvalidKey)?;
+        let signer = decode_public_key(&self.signer).ok_or(RecordError::InvalidKey)?;
+
+        if !signer.verify(&self.signed_bytes(), &self.signature) {
+            return Err(RecordError::InvalidSignature);
+        }
+
+        Ok((
+            Address::from_public_key(&revoked),
+            Address::from_public_key(&signer),
+        ))
+    }
+}
+
+/// A public, unencrypted record stored in a block alongside its messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum KeyRecord {
+    Registration(Registration),
+    Rotation(Rotation),
+    Revocation(Revocation),
+}
+
+impl KeyRecord {
+    /// Encoded keys published by this record.
+    pub fn public_keys(&self) -> Vec<&String> {
+        match self {
+            KeyRecord::Registration(registration) => vec![&registration.public_key],
+            KeyRecord::Rotation(rotation) => vec![&rotation.old_key, &rotation.new_key],
+            KeyRecord::Revocation(revocation) => vec![&revocation.public_key, &revocation.signer],
+        }
+    }
+
+    /// The hash a block commits to for this record.
+    pub fn digest(&self) -> String {
+        hex::encode(Sha256::digest(&bincode::serialize(self).unwrap()))
+    }
+}
+
+/// The current registration for one address.
+#[derive(Debug, Clone)]
+pub struct DirectoryEntry {
+    pub address: Address,
+    pub public_key: PublicKey,
+    pub nickname: Option<String>,
+    pub sequence: u64,
+    /// Block in which the address was first registered.
+    pub registered_at: u32,
+}
+
+/// Key registrations found on the chain, indexed by address and nickname,
+/// together with the rotations and revocations that apply to them.
+/// Nicknames are first come, first served: once claimed, only the owning key
+/// (or the key it was rotated to) can change or release them.
+#[derive(Clone)]
+pub struct Directory {
+    entries: HashMap<Address, DirectoryEntry>,
+    nicknames: HashMap<String, Address>,
+    rotated_to: HashMap<Address, Address>,
+    /// The height from which each revoked key is invalid.
+    revoked: HashMap<Address, u32>,
+}
+
+impl Directory {
+    pub fn new() -> Self {
+        Directory {
+            entries: HashMap::new(),
+            nicknames: HashMap::new(),
+            rotated_to: HashMap::new(),
+            revoked: HashMap::new(),
+        }
+    }
+
+    /// Checks that `record` could be applied at `height` without changing the
+    /// directory.
+    pub fn check(&self, record: &KeyRecord, height: u32) -> Result<(), RecordError> {
+        self.clone().apply(record, height)
+    }
+
+    /// Applies a record mined at `height`.
+    pub fn apply(&mut self, record: &KeyRecord, height: u32) -> Result<(), RecordError> {
+        match record {
+            KeyRecord::Registration(registration) => self.apply_registration(registration, height),
+            KeyRecord::Rotation(rotation) => self.apply_rotation(rotation, height),
+            KeyRecord::Revocation(revocation) => self.apply_revocation(revocation, height),
+        }
+    }
+
+    fn apply_registration(
+        &mut self,
+        registration: &Registration,
+        height: u32,
+    ) -> Result<(), RecordError> {
+        let public_key = registration.verify()?;
+        let address = Address::from_public_key(&public_key);
+
+        if self.is_revoked(&address, height) {
+            return Err(RecordError::Revoked(address));
+        }
+        if self.rotated_to.contains_key(&address) {
+            return Err(RecordError::AlreadyRotated(address));
+        }
+        if let Some(entry) = self.entries.get(&address) {
+            if registration.sequence <= entry.sequence {
+                return Err(RecordError::StaleSequence {
+                    current: entry.sequence,
+                    received: registration.sequence,
+                });
+            }
+        }
+        if let Some(nickname) = &registration.nickname {
+            match self.nicknames.get(nickname) {
+                Some(owner) if owner != &address => {
+                    return Err(RecordError::NicknameTaken(nickname.to_owned()))
+                }
+                _ => {}
+            }
+        }
+
+        let registered_at = match self.entries.get(&address) {
+            Some(previous) => {
+                if let Some(nickname) = &previous.nickname {
+                    self.nicknames.remove(nickname);
+                }
+                previous.registered_at
+            }
+            None => height,
+        };
+        if let Some(nickname) = &registration.nickname {
+            self.nicknames.insert(nickname.to_owned(), address);
+        }
+
+        self.entries.insert(
+            address,
+            DirectoryEntry {
+                address,
+                public_key,
+                nickname: registration.nickname.to_owned(),
+                sequence: registration.sequence,
+                registered_at,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Moves the old key's entry, including its nickname, to the new key.
+    fn apply_rotation(&mut self, rotation: &Rotation, height: u32) -> Result<(), RecordError> {
+        let (old_key, new_key) = rotation.verify()?;
+        let old = Address::from_public_key(&old_key);
+        let new = Address::from_public_key(&new_key);
+
+        if self.is_revoked(&old, height) {
+            return Err(RecordError::Revoked(old));
+        }
+        if self.rotated_to.contains_key(&old) {
+            return Err(RecordError::AlreadyRotated(old));
+        }
+        if old == new
+            || self.entries.contains_key(&new)
+            || self.rotated_to.contains_key(&new)
+            || self.revoked.contains_key(&new)
+        {
+            return Err(RecordError::KeyInUse(new));
+        }
+
+        let entry = match self.entries.remove(&old) {
+            Some(entry) => DirectoryEntry {
+                address: new,
+                public_key: new_key,
+                ..entry
+            },
+            None => DirectoryEntry {
+                address: new,
+                public_key: new_key,
+                nickname: None,
+                sequence: 0,
+                registered_at: height,
+            },
+        };
+        if let Some(nickname) = &entry.nickname {
+            self.nicknames.insert(nickname.to_owned(), new);
+        }
+
+        self.entries.insert(new, entry);
+        self.rotated_to.insert(old, new);
+
+        Ok(())
+    }
+
+    fn apply_revocation(
+        &mut self,
+        revocation: &Revocation,
+        height: u32,
+    ) -> Result<(), RecordError> {
+        let (revoked, signer) = revocation.verify()?;
+
+        if revocation.effective_height < height {
+            return Err(RecordError::RetroactiveRevocation {
+                height,
+                effective_height: revocation.effective_height,
+            });
+        }
+        // A later revocation may only bring the effective height forward, so
+        // a thief revoking a stolen key far in the future can't shut the
+        // owner out.
+        if let Some(&effective_height) = self.revoked.get(&revoked) {
+            if revocation.effective_height >= effective_height {
+                return Err(RecordError::AlreadyRevoked(revoked));
+            }
+        }
+        if signer != revoked && !self.successors(&revoked).contains(&signer) {
+            return Err(RecordError::UnauthorizedRevocation);
+        }
+
+        self.revoked.insert(revoked, revocation.effective_height);
+
+        Ok(())
+    }
+
+    /// Every key `address` was rotated to, oldest first.
+    fn successors(&self, address: &Address) -> Vec<Address> {
+        let mut successors: Vec<Address> = Vec::new();
+        let mut current = address;
+
+        while let Some(next) = self.rotated_to.get(current) {
+            // Rotation targets must be unused keys, so this can't loop, but
+            // guard against it anyway.
+            if successors.contains(next) {
+                break;
+            }
+            successors.push(*next);
+            current = next;
+        }
+
+        successors
+    }
+
+    pub fn is_revoked(&self, address: &Address, height: u32) -> bool {
+        match self.revoked.get(address) {
+            Some(from) => height >= *from,
+            None => false,
+        }
+    }
+
+    /// The address a sender should encrypt to at `height` instead of
+    /// `address`, following rotations. Fails if that key has been revoked.
+    pub fn current_address(&self, address: &Address, height: u32) -> Result<Address, RecordError> {
+        let current = match self.successors(address).last() {
+            Some(latest) => *latest,
+            None => *address,
+        };
+
+        if self.is_revoked(&current, height) {
+            return Err(RecordError::Revoked(current));
+        }
+
+        Ok(current)
+    }
+
+    pub fn get(&self, address: &Address) -> Option<&DirectoryEntry> {
+        self.entries.get(address)
+    }
+
+    pub fn find_nickname(&self, nickname: &str) -> Option<&DirectoryEntry> {
+        self.nicknames
+            .get(nickname)
+            .and_then(|address| self.entries.get(address))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Default for Directory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
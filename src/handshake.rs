@@ -0,0 +1,292 @@
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::BitOr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::address::Address;
+use crate::chain::ChainStatus;
+use crate::identity::Identity;
+use crate::keys::PublicKey;
+use crate::protocol::{read_frame, write_frame, FrameError};
+use crate::transport::{self, NoiseKey, NoiseStream, TransportError};
+
+/// Version of the peer protocol spoken by this node.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest peer protocol version this node still understands.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// How long a peer has to complete the handshake before it is dropped.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest handshake frame accepted, well above an RSA key and signature.
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+const NONCE_SIZE: usize = 32;
+/// Prefixed to everything signed during the handshake so the signature
+/// cannot be replayed as a message or key record signature.
+const SIGNATURE_CONTEXT: &[u8] = b"biddy handshake v1";
+
+/// Services a node offers its peers, as a set of flags.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Forwards blocks and messages to its other peers.
+    pub const RELAY: Capabilities = Capabilities(1);
+    /// Mines submitted messages into blocks.
+    pub const MINING: Capabilities = Capabilities(1 << 1);
+    /// Keeps every block in full, including expired message bodies.
+    pub const ARCHIVE: Capabilities = Capabilities(1 << 2);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+/// The first message each side of a connection sends.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    /// `None` for a node that has no blocks yet.
+    pub genesis_hash: Option<String>,
+    pub best_height: u32,
+    /// The port the node accepts connections on, which may differ from the
+    /// port its connection comes from.
+    pub listen_port: u16,
+    /// The encoded public key of the node's identity.
+    pub node_key: String,
+    pub capabilities: Capabilities,
+    /// Random bytes the peer signs to prove it holds `node_key`.
+    nonce: Vec<u8>,
+}
+
+impl Hello {
+    pub fn new(
+        identity: &Identity,
+        status: &ChainStatus,
+        listen_port: u16,
+        capabilities: Capabilities,
+    ) -> Self {
+        let mut nonce = vec![0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+
+        Hello {
+            version: PROTOCOL_VERSION,
+            genesis_hash: status.genesis_hash.clone(),
+            best_height: status.best_height,
+            listen_port,
+            node_key: identity.encoded_public_key(),
+            capabilities,
+            nonce,
+        }
+    }
+
+    /// Checks that a node which sent `self` can talk to the node that sent
+    /// `local`, returning the peer's public key.
+    pub fn check(&self, local: &Hello) -> Result<PublicKey, HandshakeError> {
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::IncompatibleVersion(self.version));
+        }
+        if let (Some(ours), Some(theirs)) = (&local.genesis_hash, &self.genesis_hash) {
+            if ours != theirs {
+                return Err(HandshakeError::GenesisMismatch(theirs.clone()));
+            }
+        }
+        if self.node_key == local.node_key {
+            return Err(HandshakeError::SelfConnection);
+        }
+
+        PublicKey::decode(&self.node_key).ok_or(HandshakeError::InvalidKey)
+    }
+
+    /// The bytes signed by the node that sent `self` to answer `challenge`
+    /// on the Noise session identified by `handshake_hash`.
+    fn signed_bytes(&self, handshake_hash: &[u8], challenge: &[u8]) -> Vec<u8> {
+        let mut bytes = SIGNATURE_CONTEXT.to_vec();
+        bytes.extend_from_slice(handshake_hash);
+        bytes.extend_from_slice(challenge);
+        bytes.extend(bincode::serialize(self).expect("Unable to serialize hello"));
+        bytes
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum HandshakeMessage {
+    Hello(Hello),
+    /// Signs the Noise session and the receiver's nonce together with the
+    /// sender's own `Hello`.
+    Ack {
+        signature: Vec<u8>,
+    },
+    /// Sent instead of `Ack` when the peer cannot be talked to.
+    Reject(String),
+}
+
+/// A peer that completed the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The lower of the two nodes' protocol versions, used from now on.
+    pub version: u32,
+    pub public_key: PublicKey,
+    pub address: Address,
+    pub hello: Hello,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(std::io::Error),
+    Transport(TransportError),
+    Malformed,
+    /// The peer sent a handshake message out of order.
+    Unexpected,
+    IncompatibleVersion(u32),
+    /// The peer's chain starts from a different genesis block.
+    GenesisMismatch(String),
+    InvalidKey,
+    InvalidSignature,
+    /// The connection leads back to this node.
+    SelfConnection,
+    /// The peer refused the handshake, giving a reason.
+    Rejected(String),
+    Timeout,
+}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(e: std::io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+impl From<FrameError> for HandshakeError {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Io(e) => HandshakeError::Io(e),
+            FrameError::TooLarge(_) | FrameError::Malformed => HandshakeError::Malformed,
+        }
+    }
+}
+
+impl From<TransportError> for HandshakeError {
+    fn from(e: TransportError) -> Self {
+        HandshakeError::Transport(e)
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "{}", e),
+            HandshakeError::Transport(e) => write!(f, "{}", e),
+            HandshakeError::Malformed => write!(f, "The handshake could not be parsed"),
+            HandshakeError::Unexpected => write!(f, "The handshake arrived out of order"),
+            HandshakeError::IncompatibleVersion(version) => write!(
+                f,
+                "Protocol version {} is not supported, {} to {} are",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            HandshakeError::GenesisMismatch(hash) => {
+                write!(
+                    f,
+                    "The chain starts from a different genesis block {}",
+                    hash
+                )
+            }
+            HandshakeError::InvalidKey => write!(f, "The node key could not be decoded"),
+            HandshakeError::InvalidSignature => {
+                write!(f, "The handshake was not signed by the node key")
+            }
+            HandshakeError::SelfConnection => write!(f, "The connection leads back to this node"),
+            HandshakeError::Rejected(reason) => write!(f, "Rejected by the peer: {}", reason),
+            HandshakeError::Timeout => write!(f, "The handshake was not completed in time"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Encrypts a fresh connection with the Noise handshake, then exchanges
+/// `Hello` messages over it and has each side sign the session with its node
+/// key. Peers that speak an unsupported protocol version, follow a different
+/// chain or take longer than `timeout` are refused; the connection is
+/// dropped with the error.
+pub async fn handshake<S>(
+    stream: S,
+    initiator: bool,
+    noise_key: &NoiseKey,
+    local: &Hello,
+    identity: &Identity,
+    timeout: Duration,
+) -> Result<(NoiseStream<S>, PeerInfo), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let secure = async {
+        let mut stream = transport::establish(stream, noise_key, initiator).await?;
+        let peer = exchange(&mut stream, local, identity).await?;
+        Ok((stream, peer))
+    };
+
+    tokio::time::timeout(timeout, secure)
+        .await
+        .map_err(|_| HandshakeError::Timeout)?
+}
+
+async fn exchange<S>(
+    stream: &mut NoiseStream<S>,
+    local: &Hello,
+    identity: &Identity,
+) -> Result<PeerInfo, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_frame(stream, &HandshakeMessage::Hello(local.clone())).await?;
+    let remote = match read_frame(stream, MAX_FRAME_SIZE).await? {
+        HandshakeMessage::Hello(hello) => hello,
+        HandshakeMessage::Reject(reason) => return Err(HandshakeError::Rejected(reason)),
+        HandshakeMessage::Ack { .. } => return Err(HandshakeError::Unexpected),
+    };
+
+    let public_key = match remote.check(local) {
+        Ok(public_key) => public_key,
+        Err(e) => {
+            // The peer is being dropped anyway, so failing to tell it why
+            // changes nothing.
+            let _ = write_frame(stream, &HandshakeMessage::Reject(e.to_string())).await;
+            return Err(e);
+        }
+    };
+
+    let signature = identity
+        .private_key
+        .sign(&local.signed_bytes(stream.handshake_hash(), &remote.nonce));
+    write_frame(stream, &HandshakeMessage::Ack { signature }).await?;
+    let signature = match read_frame(stream, MAX_FRAME_SIZE).await? {
+        HandshakeMessage::Ack { signature } => signature,
+        HandshakeMessage::Reject(reason) => return Err(HandshakeError::Rejected(reason)),
+        HandshakeMessage::Hello(_) => return Err(HandshakeError::Unexpected),
+    };
+    let signed = remote.signed_bytes(stream.handshake_hash(), &local.nonce);
+    if !public_key.verify(&signed, &signature) {
+        return Err(HandshakeError::InvalidSignature);
+    }
+
+    Ok(PeerInfo {
+        version: remote.version.min(PROTOCOL_VERSION),
+        address: Address::from_public_key(&public_key),
+        public_key,
+        hello: remote,
+    })
+}
@@ -0,0 +1,44 @@
+use sha2::{Digest, Sha256};
+
+use crate::address::Address;
+use crate::keys::{PrivateKey, PublicKey};
+
+/// SHA-256 of the public key's DER encoded SubjectPublicKeyInfo (or raw
+/// Ed25519/X25519 keys), hex encoded.
+pub fn fingerprint(public_key: &PublicKey) -> String {
+    hex::encode(Sha256::digest(&public_key.hash_bytes()))
+}
+
+/// A named key pair from the keystore that messages can be sent and read as.
+#[derive(Clone)]
+pub struct Identity {
+    pub name: String,
+    pub private_key: PrivateKey,
+    pub public_key: PublicKey,
+    pub fingerprint: String,
+    pub address: Address,
+}
+
+impl Identity {
+    pub fn new(name: &str, private_key: PrivateKey) -> Self {
+        let public_key = private_key.public_key();
+
+        Identity {
+            name: String::from(name),
+            fingerprint: fingerprint(&public_key),
+            address: Address::from_public_key(&public_key),
+            private_key,
+            public_key,
+        }
+    }
+
+    /// The encoded public key used in `Message::to` and `Message::from`.
+    pub fn encoded_public_key(&self) -> String {
+        self.public_key.encode()
+    }
+
+    /// The first 16 characters of the fingerprint, for display.
+    pub fn short_fingerprint(&self) -> &str {
+        &self.fingerprint[..16]
+    }
+}
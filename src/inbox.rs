@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use crate::address::Address;
+use crate::identity::Identity;
+use crate::message::Expiry;
+use crate::payload::MessageRef;
+use crate::{Block, Payload};
+
+/// A decrypted message sent to or by the inbox owner, and where it was mined.
+pub struct InboxEntry {
+    pub block_hash: String,
+    pub block_id: u32,
+    pub index: usize,
+    pub to: String,
+    pub from: String,
+    pub to_address: Address,
+    pub from_address: Address,
+    /// Whether the message was signed by the key it claims to be from.
+    pub verified: bool,
+    pub payload: Payload,
+    pub in_reply_to: Option<MessageRef>,
+    pub expires: Option<Expiry>,
+}
+
+/// A message and every reply to it, directly or indirectly, in chronological order.
+pub struct Thread<'a> {
+    pub root: MessageRef,
+    pub entries: Vec<&'a InboxEntry>,
+}
+
+impl InboxEntry {
+    pub fn reference(&self) -> MessageRef {
+        MessageRef {
+            block_hash: self.block_hash.to_owned(),
+            index: self.index as u32,
+        }
+    }
+
+    /// The message this entry answers, from its header or, for replies,
+    /// reactions and receipts, from the payload itself.
+    pub fn parent(&self) -> Option<&MessageRef> {
+        self.in_reply_to.as_ref().or_else(|| self.payload.target())
+    }
+
+    pub fn is_expired(&self, height: u32, now: u64) -> bool {
+        match &self.expires {
+            Some(expiry) => expiry.is_expired(height, now),
+            None => false,
+        }
+    }
+}
+
+/// Indexes the messages on the chain that one identity is able to read.
+pub struct Inbox {
+    identity: Identity,
+    encoded_key: String,
+    entries: Vec<InboxEntry>,
+}
+
+impl Inbox {
+    pub fn new(identity: &Identity) -> Self {
+        Inbox {
+            identity: identity.clone(),
+            encoded_key: identity.encoded_public_key(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Decrypts and records every message in `block` sent to or by this inbox's
+    /// identity. Pruned messages and messages that fail to decrypt are skipped.
+    pub fn scan_block(&mut self, block: &Block) {
+        for (index, message) in block.data.iter().enumerate() {
+            if (message.to != self.encoded_key && message.from != self.encoded_key)
+                || message.is_pruned()
+            {
+                continue;
+            }
+            if self
+                .entries
+                .iter()
+                .any(|entry| entry.block_hash == block.hash && entry.index == index)
+            {
+                continue;
+            }
+
+            let mut decrypted = message.clone();
+            if decrypted.decrypt(&self.identity).is_err() {
+                continue;
+            }
+            let (to_address, from_address) = match (
+                Address::from_encoded_key(&decrypted.to),
+                Address::from_encoded_key(&decrypted.from),
+            ) {
+                (Some(to), Some(from)) => (to, from),
+                _ => continue,
+            };
+
+            let verified = decrypted.verify_sender();
+            if let Some(payload) = decrypted.payload {
+                self.entries.push(InboxEntry {
+                    block_hash: block.hash.to_owned(),
+                    block_id: block.node_id,
+                    index,
+                    to: decrypted.to,
+                    from: decrypted.from,
+                    to_address,
+                    from_address,
+                    verified,
+                    payload,
+                    in_reply_to: decrypted.in_reply_to,
+                    expires: decrypted.expires,
+                });
+            }
+        }
+    }
+
+    /// Messages that have not expired at the given chain height and time, in
+    /// the order they were mined.
+    pub fn messages(&self, height: u32, now: u64) -> Vec<&InboxEntry> {
+        let mut messages: Vec<&InboxEntry> = self
+            .entries
+            .iter()
+            .filter(|entry| !entry.is_expired(height, now))
+            .collect();
+        messages.sort_by_key(|entry| (entry.block_id, entry.index));
+
+        messages
+    }
+
+    /// Unexpired messages exchanged with `other`, oldest first.
+    pub fn conversation(&self, other: &Address, height: u32, now: u64) -> Vec<&InboxEntry> {
+        let own = &self.identity.address;
+
+        self.messages(height, now)
+            .into_iter()
+            .filter(|entry| {
+                (&entry.from_address == other && &entry.to_address == own)
+                    || (&entry.from_address == own && &entry.to_address == other)
+            })
+            .collect()
+    }
+
+    /// Groups the conversation with `other` into threads by following each
+    /// message's reply reference back to the earliest message still known.
+    /// Threads are ordered by their first message.
+    pub fn threads(&self, other: &Address, height: u32, now: u64) -> Vec<Thread<'_>> {
+        let conversation = self.conversation(other, height, now);
+        let by_reference: HashMap<MessageRef, &InboxEntry> = conversation
+            .iter()
+            .map(|entry| (entry.reference(), *entry))
+            .collect();
+
+        let mut threads: Vec<Thread<'_>> = Vec::new();
+        let mut thread_index: HashMap<MessageRef, usize> = HashMap::new();
+
+        for entry in conversation {
+            let mut root = entry;
+            let mut steps: usize = 0;
+            while let Some(parent) = root.parent().and_then(|p| by_reference.get(p)) {
+                // A reference can only point backwards, but guard against loops
+                // in malformed data anyway.
+                steps += 1;
+                if steps > by_reference.len() {
+                    break;
+                }
+                root = parent;
+            }
+
+            let root_ref = root.reference();
+            match thread_index.get(&root_ref) {
+                Some(index) => threads[*index].entries.push(entry),
+                None => {
+                    thread_index.insert(root_ref.to_owned(), threads.len());
+                    threads.push(Thread {
+                        root: root_ref,
+                        entries: vec![entry],
+                    });
+                }
+            }
+        }
+
+        threads
+    }
+
+    /// Every indexed message, including expired ones.
+    pub fn all_messages(&self) -> &[InboxEntry] {
+        &self.entries
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,382 @@
+use bip39::Mnemonic;
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use ed25519_dalek::{
+    ExpandedSecretKey, PublicKey as Ed25519PublicKey, SecretKey as Ed25519SecretKey, Signature,
+    Verifier,
+};
+use rand::{rngs::OsRng, RngCore};
+use rsa::{
+    pkcs1::FromRsaPublicKey, pkcs8::ToPublicKey, Hash, PaddingScheme, PublicKey as _,
+    RsaPrivateKey, RsaPublicKey,
+};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+
+use crate::message::RsaPublicHelpers;
+
+/// Size of newly generated RSA keys.
+pub const RSA_KEY_BITS: usize = 4096;
+/// Prefix of encoded Ed25519/X25519 public keys. Encoded keys without a prefix
+/// are hex encoded PKCS#1 PEM RSA keys, as written by older versions.
+pub const ED25519_KEY_PREFIX: &str = "ed25519:";
+const RSA_KEY_PREFIX: &str = "rsa:";
+
+const X25519_DERIVATION: &[u8] = b"biddy-x25519-from-ed25519-seed";
+const X25519_WRAP: &[u8] = b"biddy-x25519-key-wrap";
+const MNEMONIC_DERIVATION: &[u8] = b"biddy-ed25519-from-mnemonic";
+const CURVE_KEY_SIZE: usize = 32;
+const WRAP_TAG_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    Rsa(rsa::errors::Error),
+    /// A wrapped message key has the wrong length for the key type or fails
+    /// authentication.
+    InvalidWrappedKey,
+}
+
+impl From<rsa::errors::Error> for CryptoError {
+    fn from(e: rsa::errors::Error) -> Self {
+        CryptoError::Rsa(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    Rsa,
+    /// Ed25519 for signing with an X25519 key for encryption, both derived
+    /// from one 32 byte seed.
+    Ed25519,
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyAlgorithm::Rsa => write!(f, "rsa"),
+            KeyAlgorithm::Ed25519 => write!(f, "ed25519"),
+        }
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rsa" => Ok(KeyAlgorithm::Rsa),
+            "ed25519" => Ok(KeyAlgorithm::Ed25519),
+            _ => Err(format!("Unknown key algorithm {:?}", s)),
+        }
+    }
+}
+
+/// A public key of any supported algorithm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    Rsa(RsaPublicKey),
+    Ed25519 {
+        signing: Ed25519PublicKey,
+        encryption: X25519PublicKey,
+    },
+}
+
+impl PublicKey {
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            PublicKey::Rsa(_) => KeyAlgorithm::Rsa,
+            PublicKey::Ed25519 { .. } => KeyAlgorithm::Ed25519,
+        }
+    }
+
+    /// The string form used in `Message::to`, `Message::from` and
+    /// `Block::author_public_key`. RSA keys keep the untagged hex PEM form so
+    /// existing blocks hash and validate as before.
+    pub fn encode(&self) -> String {
+        match self {
+            PublicKey::Rsa(key) => hex::encode(key.print_key()),
+            PublicKey::Ed25519 {
+                signing,
+                encryption,
+            } => format!(
+                "{}{}{}",
+                ED25519_KEY_PREFIX,
+                hex::encode(signing.as_bytes()),
+                hex::encode(encryption.as_bytes())
+            ),
+        }
+    }
+
+    pub fn decode(encoded: &str) -> Option<Self> {
+        if let Some(curve) = encoded.strip_prefix(ED25519_KEY_PREFIX) {
+            let bytes = hex::decode(curve).ok()?;
+            if bytes.len() != 2 * CURVE_KEY_SIZE {
+                return None;
+            }
+
+            let mut signing = [0u8; CURVE_KEY_SIZE];
+            let mut encryption = [0u8; CURVE_KEY_SIZE];
+            signing.copy_from_slice(&bytes[..CURVE_KEY_SIZE]);
+            encryption.copy_from_slice(&bytes[CURVE_KEY_SIZE..]);
+
+            return Some(PublicKey::Ed25519 {
+                signing: Ed25519PublicKey::from_bytes(&signing).ok()?,
+                encryption: X25519PublicKey::from(encryption),
+            });
+        }
+
+        let rsa = encoded.strip_prefix(RSA_KEY_PREFIX).unwrap_or(encoded);
+        let pem = String::from_utf8(hex::decode(rsa).ok()?).ok()?;
+
+        RsaPublicKey::from_pkcs1_pem(&pem).ok().map(PublicKey::Rsa)
+    }
+
+    /// The bytes addresses and fingerprints are hashed from: the DER
+    /// SubjectPublicKeyInfo for RSA, and the tagged raw keys for Ed25519.
+    pub fn hash_bytes(&self) -> Vec<u8> {
+        match self {
+            PublicKey::Rsa(key) => key
+                .to_public_key_der()
+                .expect("Unable to serialize RsaPublicKey")
+                .as_ref()
+                .to_vec(),
+            PublicKey::Ed25519 {
+                signing,
+                encryption,
+            } => {
+                let mut bytes = ED25519_KEY_PREFIX.as_bytes().to_vec();
+                bytes.extend_from_slice(signing.as_bytes());
+                bytes.extend_from_slice(encryption.as_bytes());
+                bytes
+            }
+        }
+    }
+
+    /// Encrypts a message key to this key: RSA PKCS#1 v1.5, or for Ed25519
+    /// keys an ephemeral X25519 exchange whose shared secret encrypts the key
+    /// with ChaCha20-Poly1305. The ephemeral public key is prepended to the
+    /// result and authenticated along with the key.
+    pub fn wrap_key(&self, key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            PublicKey::Rsa(public_key) => {
+                let padding = PaddingScheme::PKCS1v15Encrypt;
+                Ok(public_key.encrypt(&mut OsRng, padding, key)?)
+            }
+            PublicKey::Ed25519 { encryption, .. } => {
+                if key.len() != CURVE_KEY_SIZE {
+                    return Err(CryptoError::InvalidWrappedKey);
+                }
+
+                let ephemeral = StaticSecret::from(random_seed());
+                let ephemeral_public = X25519PublicKey::from(&ephemeral);
+                let shared = ephemeral.diffie_hellman(encryption);
+                let cipher = wrapping_cipher(shared.as_bytes(), &ephemeral_public, encryption);
+
+                // Every wrapping key is used once, so a fixed nonce is safe.
+                let sealed = cipher
+                    .encrypt(
+                        Nonce::from_slice(&[0u8; 12]),
+                        Payload {
+                            msg: key,
+                            aad: ephemeral_public.as_bytes(),
+                        },
+                    )
+                    .map_err(|_| CryptoError::InvalidWrappedKey)?;
+
+                let mut wrapped = ephemeral_public.as_bytes().to_vec();
+                wrapped.extend_from_slice(&sealed);
+                Ok(wrapped)
+            }
+        }
+    }
+
+    /// Checks a signature made by `PrivateKey::sign`.
+    pub fn verify(&self, bytes: &[u8], signature: &[u8]) -> bool {
+        match self {
+            PublicKey::Rsa(public_key) => {
+                let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+                public_key
+                    .verify(padding, &Sha256::digest(bytes), signature)
+                    .is_ok()
+            }
+            PublicKey::Ed25519 { signing, .. } => match Signature::try_from(signature) {
+                Ok(signature) => signing.verify(bytes, &signature).is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+fn wrapping_cipher(
+    shared: &[u8],
+    ephemeral: &X25519PublicKey,
+    recipient: &X25519PublicKey,
+) -> ChaCha20Poly1305 {
+    let mut hasher = Sha256::new();
+    hasher.update(X25519_WRAP);
+    hasher.update(shared);
+    hasher.update(ephemeral.as_bytes());
+    hasher.update(recipient.as_bytes());
+
+    ChaCha20Poly1305::new(Key::from_slice(&hasher.finalize()))
+}
+
+fn random_seed() -> [u8; CURVE_KEY_SIZE] {
+    let mut seed = [0u8; CURVE_KEY_SIZE];
+    OsRng.fill_bytes(&mut seed);
+
+    seed
+}
+
+/// Generates a new 24 word BIP-39 recovery phrase.
+pub fn generate_mnemonic() -> Mnemonic {
+    Mnemonic::from_entropy(&random_seed()).expect("32 bytes is a valid BIP-39 entropy size")
+}
+
+/// A private key of any supported algorithm.
+pub enum PrivateKey {
+    Rsa(Box<RsaPrivateKey>),
+    Ed25519 {
+        signing: Ed25519SecretKey,
+        encryption: StaticSecret,
+    },
+}
+
+impl From<RsaPrivateKey> for PrivateKey {
+    fn from(private_key: RsaPrivateKey) -> Self {
+        PrivateKey::Rsa(Box::new(private_key))
+    }
+}
+
+impl Clone for PrivateKey {
+    fn clone(&self) -> Self {
+        match self {
+            PrivateKey::Rsa(private_key) => PrivateKey::Rsa(private_key.clone()),
+            PrivateKey::Ed25519 { signing, .. } => {
+                PrivateKey::from_ed25519_seed(signing.to_bytes())
+            }
+        }
+    }
+}
+
+impl PrivateKey {
+    pub fn generate(algorithm: KeyAlgorithm) -> Result<Self, rsa::errors::Error> {
+        match algorithm {
+            KeyAlgorithm::Rsa => Ok(RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS)?.into()),
+            KeyAlgorithm::Ed25519 => Ok(PrivateKey::from_ed25519_seed(random_seed())),
+        }
+    }
+
+    /// Builds the Ed25519 signing key from `seed` and derives the X25519
+    /// encryption key from it.
+    pub fn from_ed25519_seed(seed: [u8; CURVE_KEY_SIZE]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(X25519_DERIVATION);
+        hasher.update(seed);
+        let mut encryption = [0u8; CURVE_KEY_SIZE];
+        encryption.copy_from_slice(&hasher.finalize());
+
+        PrivateKey::Ed25519 {
+            signing: Ed25519SecretKey::from_bytes(&seed).expect("Ed25519 seeds are 32 bytes"),
+            encryption: StaticSecret::from(encryption),
+        }
+    }
+
+    /// Derives an Ed25519 key from a recovery phrase and its optional BIP-39
+    /// passphrase, so the same phrase and passphrase always give back the
+    /// same key, whatever the identity is called.
+    pub fn from_mnemonic(mnemonic: &Mnemonic, passphrase: &str) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(MNEMONIC_DERIVATION);
+        hasher.update(mnemonic.to_seed(passphrase));
+        let mut seed = [0u8; CURVE_KEY_SIZE];
+        seed.copy_from_slice(&hasher.finalize());
+
+        PrivateKey::from_ed25519_seed(seed)
+    }
+
+    /// The seed an Ed25519 key was built from, which is all that needs storing.
+    pub fn ed25519_seed(&self) -> Option<[u8; CURVE_KEY_SIZE]> {
+        match self {
+            PrivateKey::Rsa(_) => None,
+            PrivateKey::Ed25519 { signing, .. } => Some(signing.to_bytes()),
+        }
+    }
+
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            PrivateKey::Rsa(_) => KeyAlgorithm::Rsa,
+            PrivateKey::Ed25519 { .. } => KeyAlgorithm::Ed25519,
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        match self {
+            PrivateKey::Rsa(private_key) => {
+                PublicKey::Rsa(RsaPublicKey::from(private_key.as_ref()))
+            }
+            PrivateKey::Ed25519 {
+                signing,
+                encryption,
+            } => PublicKey::Ed25519 {
+                signing: Ed25519PublicKey::from(signing),
+                encryption: X25519PublicKey::from(encryption),
+            },
+        }
+    }
+
+    /// Signs `bytes`: PKCS#1 v1.5 over SHA-256 for RSA, plain Ed25519 otherwise.
+    pub fn sign(&self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            PrivateKey::Rsa(private_key) => {
+                let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+                private_key
+                    .sign(padding, &Sha256::digest(bytes))
+                    .expect("Unable to sign")
+            }
+            PrivateKey::Ed25519 { signing, .. } => ExpandedSecretKey::from(signing)
+                .sign(bytes, &Ed25519PublicKey::from(signing))
+                .to_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// Recovers a message key wrapped by `PublicKey::wrap_key`.
+    pub fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            PrivateKey::Rsa(private_key) => {
+                let padding = PaddingScheme::PKCS1v15Encrypt;
+                Ok(private_key.decrypt(padding, wrapped)?)
+            }
+            PrivateKey::Ed25519 { encryption, .. } => {
+                if wrapped.len() != 2 * CURVE_KEY_SIZE + WRAP_TAG_SIZE {
+                    return Err(CryptoError::InvalidWrappedKey);
+                }
+
+                let mut ephemeral = [0u8; CURVE_KEY_SIZE];
+                ephemeral.copy_from_slice(&wrapped[..CURVE_KEY_SIZE]);
+                let ephemeral = X25519PublicKey::from(ephemeral);
+                let shared = encryption.diffie_hellman(&ephemeral);
+                let cipher = wrapping_cipher(
+                    shared.as_bytes(),
+                    &ephemeral,
+                    &X25519PublicKey::from(encryption),
+                );
+
+                cipher
+                    .decrypt(
+                        Nonce::from_slice(&[0u8; 12]),
+                        Payload {
+                            msg: &wrapped[CURVE_KEY_SIZE..],
+                            aad: ephemeral.as_bytes(),
+                        },
+                    )
+                    .map_err(|_| CryptoError::InvalidWrappedKey)
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::protocol::MAX_FRAME_SIZE;
+
+/// Largest `Limits::max_queued_bytes` used, which keeps the queued byte count
+/// within what a semaphore can hold.
+pub const MAX_QUEUED_BYTES: usize = 1 << 30;
+
+/// How much a single peer, and peers as a whole, may ask of the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Data read from a peer each second, on average; faster peers are
+    /// throttled by reading from them less often.
+    pub bytes_per_second: u64,
+    /// Messages read from a peer each second, on average.
+    pub messages_per_second: u64,
+    /// Bytes received from a peer and not yet taken with `next_data`. The
+    /// socket is not read while the queue is at this limit. Values above
+    /// `MAX_QUEUED_BYTES` are treated as `MAX_QUEUED_BYTES`.
+    pub max_queued_bytes: usize,
+    /// Inbound connections accepted at once, including those still
+    /// handshaking.
+    pub max_inbound: usize,
+    /// Inbound connections accepted at once from a single IP address.
+    pub max_per_ip: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            bytes_per_second: MAX_FRAME_SIZE as u64,
+            messages_per_second: 200,
+            max_queued_bytes: 4 * MAX_FRAME_SIZE,
+            max_inbound: 64,
+            max_per_ip: 4,
+        }
+    }
+}
+
+/// A token bucket allowing bursts of up to one second's worth of `rate`.
+pub struct RateLimiter {
+    rate: u64,
+    /// May go below zero after a take larger than the bucket, which is then
+    /// paid back before anything else is allowed.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64, now: Instant) -> Self {
+        RateLimiter {
+            rate,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    /// Takes `amount` tokens, returning how long to wait before the rate is
+    /// respected again.
+    pub fn take(&mut self, amount: u64, now: Instant) -> Duration {
+        let rate = self.rate.max(1) as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - amount as f64;
+        self.updated = now;
+
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / rate)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LimitError {
+    /// `max_inbound` connections are already open.
+    TooManyConnections,
+    /// `max_per_ip` connections from the address are already open.
+    TooManyFromAddress(IpAddr),
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::TooManyConnections => write!(f, "Too many peers are connected"),
+            LimitError::TooManyFromAddress(ip) => {
+                write!(f, "Too many peers are connected from {}", ip)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+/// Counts inbound connections against `max_inbound` and `max_per_ip`.
+pub struct ConnectionSlots {
+    max_inbound: usize,
+    max_per_ip: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionSlots {
+    pub fn new(limits: &Limits) -> Self {
+        ConnectionSlots {
+            max_inbound: limits.max_inbound,
+            max_per_ip: limits.max_per_ip,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Reserves a slot for a connection from `ip`, held until the returned
+    /// `ConnectionSlot` is dropped.
+    pub fn acquire(&self, ip: IpAddr) -> Result<ConnectionSlot, LimitError> {
+        let mut open = self.open.lock().expect("Unable to lock connection slots");
+        if open.values().sum::<usize>() >= self.max_inbound {
+            return Err(LimitError::TooManyConnections);
+        }
+        let count = open.get(&ip).copied().unwrap_or(0);
+        if count >= self.max_per_ip {
+            return Err(LimitError::TooManyFromAddress(ip));
+        }
+        open.insert(ip, count + 1);
+
+        Ok(ConnectionSlot {
+            ip,
+            open: self.open.clone(),
+        })
+    }
+}
+
+pub struct ConnectionSlot {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().expect("Unable to lock connection slots");
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -1,74 +1,2691 @@
+mod address;
+mod bans;
 mod block;
 mod chain;
+pub mod chunk;
+mod cli;
+mod contacts;
+mod directory;
+mod formats;
+mod handshake;
+mod identity;
+mod inbox;
+mod keys;
+mod limits;
+mod mempool;
 mod message;
 mod network;
+mod payload;
+mod protocol;
+mod relay;
+#[cfg(test)]
+mod simulation;
+mod transport;
 pub mod utils;
+pub use crate::address::{Address, AddressBook};
+pub use crate::bans::{Ban, BanList, Misbehavior};
 pub use crate::chain::Chain;
-pub use crate::{block::Block, message::Message, network::Network};
+pub use crate::contacts::{Contact, ContactBook, Trust};
+pub use crate::directory::{Directory, KeyRecord, Registration, Revocation, Rotation};
+pub use crate::formats::{ContactCard, ExportFormat};
+pub use crate::handshake::{Capabilities, Hello, PeerInfo};
+pub use crate::identity::Identity;
+pub use crate::inbox::{Inbox, InboxEntry};
+pub use crate::keys::{KeyAlgorithm, PrivateKey, PublicKey};
+pub use crate::limits::Limits;
+pub use crate::protocol::{DisconnectReason, PeerMessage};
+pub use crate::relay::{InventoryItem, Object, Relay, RelayMessage};
+pub use crate::transport::{MemoryTransport, NoiseKey, NoiseStream, Transport};
+pub use crate::{block::Block, message::Message, network::Network, payload::Payload};
+use bans::{BanError, BAN_THRESHOLD, DEFAULT_BAN_DURATION};
+use cli::Command;
+use handshake::{handshake, HANDSHAKE_TIMEOUT};
+use limits::ConnectionSlots;
+use network::DEFAULT_PORT;
+use relay::REQUEST_TIMEOUT;
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use utils::Keystore;
 
 #[tokio::main]
 async fn main() {
+    let options = match cli::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}\n\n{}", e, cli::USAGE);
+            return;
+        }
+    };
+
+    let keystore = match &options.keystore {
+        Some(dir) => Keystore::open(dir),
+        None => Keystore::open_default(),
+    };
+    let keystore = match keystore {
+        Ok(keystore) => keystore,
+        Err(e) => {
+            eprintln!("Unable to open the keystore: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let name = match options.identity {
+        Some(name) => name,
+        None => match keystore.active_identity() {
+            Ok(name) => name,
+            Err(e) => {
+                eprintln!("Unable to read the active identity: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let mut nickname: Option<String> = None;
+    let mut register = false;
+    let mut outgoing: Option<(String, String)> = None;
+    let mut list_inbox = false;
+
+    match options.command {
+        Command::Run => {}
+        Command::Register(name) => {
+            nickname = name;
+            register = true;
+        }
+        Command::Send(recipient, text) => outgoing = Some((recipient, text)),
+        Command::ListInbox => list_inbox = true,
+        Command::ChangePassphrase => {
+            let result = utils::read_passphrase().and_then(|current| {
+                let new_passphrase = utils::read_new_passphrase()?;
+                keystore.change_passphrase(&name, &current, &new_passphrase)
+            });
+            match result {
+                Ok(_) => println!("The passphrase of {:?} has been changed", name),
+                Err(e) => println!("Unable to change the key passphrase: {}", e),
+            }
+            return;
+        }
+        Command::ListIdentities => {
+            match keystore.identities() {
+                Ok(identities) => {
+                    for identity in identities {
+                        let marker = if identity == name { "*" } else { " " };
+                        match keystore.public_key(&identity) {
+                            Ok(public_key) => println!(
+                                "{} {} {}",
+                                marker,
+                                identity,
+                                Address::from_public_key(&public_key)
+                            ),
+                            Err(e) => println!("{} {} ({})", marker, identity, e),
+                        }
+                    }
+                }
+                Err(e) => println!("Unable to list identities: {}", e),
+            }
+            return;
+        }
+        Command::NewIdentity(new_name) => {
+            let result = utils::read_new_passphrase().and_then(|passphrase| {
+                keystore.create_identity(&new_name, &passphrase, options.algorithm)
+            });
+            match result {
+                Ok(identity) => println!(
+                    "Created identity {:?} with address {} and fingerprint {}",
+                    identity.name, identity.address, identity.fingerprint
+                ),
+                Err(e) => println!("Unable to create identity {:?}: {}", new_name, e),
+            }
+            return;
+        }
+        Command::NewMnemonic(new_name) => {
+            let new_name = new_name.unwrap_or(name);
+            // The name is checked and the phrase shown before anything is
+            // stored, so no identity is created without its backup.
+            let passphrases = keystore
+                .check_available(&new_name)
+                .and_then(|_| utils::read_recovery_passphrase(true))
+                .and_then(|recovery| Ok((recovery, utils::read_new_passphrase()?)));
+            let (recovery_passphrase, passphrase) = match passphrases {
+                Ok(passphrases) => passphrases,
+                Err(e) => {
+                    println!("Unable to create identity {:?}: {}", new_name, e);
+                    return;
+                }
+            };
+            let mnemonic = keys::generate_mnemonic();
+            println!(
+                "Recovery phrase, write it down and keep it safe:\n\n{}\n",
+                mnemonic
+            );
+            match keystore.restore_identity(&new_name, &mnemonic, &recovery_passphrase, &passphrase)
+            {
+                Ok(identity) => println!(
+                    "Created identity {:?} with address {}",
+                    identity.name, identity.address
+                ),
+                Err(e) => println!("Unable to create identity {:?}: {}", new_name, e),
+            }
+            return;
+        }
+        Command::Restore(new_name) => {
+            let new_name = new_name.unwrap_or(name);
+            let result = utils::read_mnemonic().and_then(|mnemonic| {
+                let recovery_passphrase = utils::read_recovery_passphrase(false)?;
+                let passphrase = utils::read_new_passphrase()?;
+                keystore.restore_identity(&new_name, &mnemonic, &recovery_passphrase, &passphrase)
+            });
+            // Inboxes are read from the chain each time, so there is nothing
+            // else to rebuild.
+            match result {
+                Ok(identity) => println!(
+                    "Restored identity {:?} with address {}",
+                    identity.name, identity.address
+                ),
+                Err(e) => println!("Unable to restore identity {:?}: {}", new_name, e),
+            }
+            return;
+        }
+        Command::Export(format, file) => {
+            let result = if format.is_private() {
+                utils::read_passphrase()
+                    .and_then(|passphrase| keystore.export_private_key(&name, &passphrase, format))
+            } else {
+                keystore.export_public_key(&name, format)
+            };
+            let written = result
+                .map_err(|e| e.to_string())
+                .and_then(|data| match &file {
+                    Some(path) => std::fs::write(path, data).map_err(|e| e.to_string()),
+                    None => std::io::stdout()
+                        .write_all(&data)
+                        .map_err(|e| e.to_string()),
+                });
+            if let Err(e) = written {
+                println!("Unable to export identity {:?}: {}", name, e);
+            }
+            return;
+        }
+        Command::Import(new_name, file) => {
+            let result = std::fs::read(&file)
+                .map_err(|e| e.to_string())
+                .and_then(|data| {
+                    utils::read_passphrase()
+                        .and_then(|passphrase| {
+                            keystore.import_identity(&new_name, &data, &passphrase)
+                        })
+                        .map_err(|e| e.to_string())
+                });
+            match result {
+                Ok(identity) => println!(
+                    "Imported identity {:?} with address {}",
+                    identity.name, identity.address
+                ),
+                Err(e) => println!("Unable to import {}: {}", file.display(), e),
+            }
+            return;
+        }
+        Command::AddContact(file) => {
+            let mut contacts = open_contacts(&keystore);
+            let result = std::fs::read_to_string(&file)
+                .map_err(|e| e.to_string())
+                .and_then(|text| {
+                    let contact = contacts.import(&text).map_err(|e| e.to_string())?;
+                    let added = format!("{:?} with address {}", contact.name, contact.address);
+                    contacts.save().map_err(|e| e.to_string())?;
+                    Ok(added)
+                });
+            match result {
+                Ok(added) => println!("Added contact {}", added),
+                Err(e) => println!("Unable to add contact from {}: {}", file.display(), e),
+            }
+            return;
+        }
+        Command::VerifyContact(contact, fingerprint) => {
+            let mut contacts = open_contacts(&keystore);
+            match contacts
+                .verify(&contact, &fingerprint)
+                .and_then(|_| contacts.save())
+            {
+                Ok(_) => println!("{:?} is now verified", contact),
+                Err(e) => println!("Unable to verify {:?}: {}", contact, e),
+            }
+            return;
+        }
+        Command::RemoveContact(contact) => {
+            let mut contacts = open_contacts(&keystore);
+            match contacts.remove(&contact).and_then(|_| contacts.save()) {
+                Ok(_) => println!("Removed contact {:?}", contact),
+                Err(e) => println!("Unable to remove {:?}: {}", contact, e),
+            }
+            return;
+        }
+        Command::ListContacts => {
+            for contact in open_contacts(&keystore).contacts() {
+                println!("{} {:8} {}", contact.address, contact.trust, contact.name);
+            }
+            return;
+        }
+        Command::Ban(ip, hours) => {
+            let duration = hours
+                .map(|hours| Duration::from_secs(hours.saturating_mul(60 * 60)))
+                .unwrap_or(DEFAULT_BAN_DURATION);
+            match ban_address(&keystore.ban_list_path(), ip, duration, "banned by hand") {
+                Ok(ban) => println!(
+                    "{} is banned for {} more seconds",
+                    ban.ip,
+                    ban.until.saturating_sub(message::current_time())
+                ),
+                Err(e) => println!("Unable to ban {}: {}", ip, e),
+            }
+            return;
+        }
+        Command::Unban(ip) => {
+            let now = message::current_time();
+            let result = BanList::open(&keystore.ban_list_path()).and_then(|mut bans| {
+                bans.unban(ip)?;
+                bans.save(now)
+            });
+            match result {
+                Ok(_) => println!("{} is no longer banned", ip),
+                Err(e) => println!("Unable to unban {}: {}", ip, e),
+            }
+            return;
+        }
+        Command::ListBans => {
+            let now = message::current_time();
+            match BanList::open(&keystore.ban_list_path()) {
+                Ok(bans) => {
+                    for ban in bans.bans(now) {
+                        println!("{:<39} {:>8}s {}", ban.ip, ban.until - now, ban.reason);
+                    }
+                }
+                Err(e) => println!("Unable to read the ban list: {}", e),
+            }
+            return;
+        }
+        Command::UseIdentity(new_name) => {
+            match keystore.set_active_identity(&new_name) {
+                Ok(_) => println!("{:?} is now the active identity", new_name),
+                Err(e) => println!("Unable to select identity {:?}: {}", new_name, e),
+            }
+            return;
+        }
+    }
+
+    let identity = match utils::read_passphrase().and_then(|passphrase| {
+        keystore.load_or_create_identity(&name, &passphrase, options.algorithm)
+    }) {
+        Ok(identity) => identity,
+        Err(e) => {
+            eprintln!("Unable to load identity {:?}: {}", name, e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut contacts = open_contacts(&keystore);
+    if list_inbox {
+        print_inbox(&identity, &contacts);
+        return;
+    }
+
     print!("\x1B[2J\x1B[1;1H");
-    let listener = TcpListener::bind("0.0.0.0:8675").await.unwrap();
+    let listener = TcpListener::bind(("0.0.0.0", DEFAULT_PORT)).await.unwrap();
     let network_list: Arc<Mutex<HashMap<SocketAddr, Box<Network>>>> =
         Arc::new(Mutex::new(HashMap::new()));
 
     let peer_list = network_list.clone();
+    let mut chain = match Chain::new(peer_list) {
+        Some(chain) => chain,
+        None => {
+            eprintln!("Unable to initialize the blockchain");
+            std::process::exit(1);
+        }
+    };
+    let chain_status = chain.status();
+    let relay = Arc::new(Mutex::new(Relay::new(REQUEST_TIMEOUT)));
+    let (relayed, received) = std::sync::mpsc::channel();
+    chain.connect_relay(relay.clone(), received);
+    let node_identity = identity.clone();
+    let noise_key = NoiseKey::generate();
+    let ban_path = keystore.ban_list_path();
+    let limits = options.limits;
+    let slots = ConnectionSlots::new(&limits);
 
+    let shutdown = CancellationToken::new();
+    let signalled = shutdown.clone();
     tokio::spawn(async move {
-        match Chain::new(peer_list) {
-            Some(mut chain) => chain.init(),
-            None => {
-                println!("Unable to initialize the blockchain");
-            }
-        };
+        shutdown_signal().await;
+        println!("Shutting down");
+        signalled.cancel();
     });
 
-    loop {
-        let (socket, address) = listener.accept().await.unwrap();
+    // Requests peers did not answer in time go to other peers.
+    let expiring = relay.clone();
+    let peers = network_list.clone();
+    let stop = shutdown.clone();
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {},
+                _ = stop.cancelled() => break,
+            }
+            let outgoing = expiring.lock().unwrap().expire(Instant::now());
+            relay::send_all(&peers, outgoing);
+        }
+    });
 
-        let connection = Network::new(Some(socket), address.clone());
-        let mut connection_clone = connection.clone();
+    let chain_shutdown = shutdown.clone();
+    let chain_task = tokio::task::spawn_blocking(move || {
+        chain.address_book().insert(&identity.public_key);
+        for contact in contacts.contacts() {
+            chain.address_book().insert(&contact.public_key);
+        }
+        if let Err(e) = chain.learn_addresses() {
+            println!("Unable to read addresses from the chain: {:?}", e);
+        }
+        match chain.learn_contacts(&identity, &mut contacts) {
+            Ok(0) => {}
+            Ok(_) => {
+                if let Err(e) = contacts.save() {
+                    println!("Unable to save contacts: {}", e);
+                }
+            }
+            Err(e) => println!("Unable to learn contacts from the chain: {:?}", e),
+        }
+        if register {
+            let sequence = match chain.lookup_address(&identity.address) {
+                Some(entry) => entry.sequence + 1,
+                None => 0,
+            };
+            let registration = Registration::new(&identity, nickname.as_deref(), sequence);
+            match chain.submit_record(KeyRecord::Registration(registration)) {
+                Ok(_) => println!("Registering {} on the chain", identity.address),
+                Err(e) => println!("Unable to register {}: {:?}", identity.address, e),
+            }
+        }
+        if let Some((recipient, text)) = outgoing {
+            let result = chain
+                .resolve_contact(&contacts, &recipient)
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|public_key| {
+                    let mut message =
+                        Message::new(&public_key, &identity, Payload::from(text.as_str()));
+                    message
+                        .encrypt(&public_key)
+                        .map_err(|e| format!("{:?}", e))?;
+                    chain
+                        .submit_message(message)
+                        .map_err(|e| format!("{:?}", e))
+                });
+            match result {
+                Ok(_) => println!("Sending message to {}", recipient),
+                Err(e) => println!("Unable to send to {:?}: {}", recipient, e),
+            }
+        }
+        chain.init(&identity, &chain_shutdown)
+    });
 
+    // Every connection task holds a sender, so `recv` on `drained` returns
+    // `None` once they have all finished.
+    let (drain, mut drained) = tokio::sync::mpsc::channel::<()>(1);
+    loop {
+        let (socket, address) = tokio::select! {
+            accepted = listener.accept() => accepted.unwrap(),
+            _ = shutdown.cancelled() => break,
+        };
+        // Read for every connection so bans made with the `ban` command
+        // apply to a running node.
+        match BanList::open(&ban_path) {
+            Ok(bans) if bans.is_banned(address.ip(), message::current_time()) => {
+                println!("Refusing {:?}, the address is banned", address);
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => println!("Unable to read the ban list: {}", e),
+        }
+        let slot = match slots.acquire(address.ip()) {
+            Ok(slot) => slot,
+            Err(e) => {
+                println!("Refusing {:?}: {}", address, e);
+                continue;
+            }
+        };
+        let local = Hello::new(
+            &node_identity,
+            &chain_status.lock().unwrap(),
+            DEFAULT_PORT,
+            Capabilities::MINING | Capabilities::ARCHIVE,
+        );
+        let node_identity = node_identity.clone();
+        let noise_key = noise_key.clone();
         let copied_network_list = network_list.clone();
-        (*copied_network_list.lock().unwrap()).insert(address.clone(), Box::new(connection));
-
-        println!("{:?} has just connected", address);
+        let shutdown = shutdown.clone();
+        let drain = drain.clone();
+        let ban_path = ban_path.clone();
+        let relay = relay.clone();
+        let relayed = relayed.clone();
 
         tokio::spawn(async move {
-            connection_clone.run().await;
+            let socket: Box<dyn Transport> = Box::new(socket);
+            let secured = handshake(
+                socket,
+                false,
+                &noise_key,
+                &local,
+                &node_identity,
+                HANDSHAKE_TIMEOUT,
+            );
+            let (stream, peer) = tokio::select! {
+                secured = secured => match secured {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        println!("Disconnecting {:?}: {}", address, e);
+                        return;
+                    }
+                },
+                _ = shutdown.cancelled() => return,
+            };
+            println!(
+                "{:?} has just connected as {} speaking protocol version {}",
+                address, peer.address, peer.version
+            );
 
-            println!("Connection to {:?} has terminated.", address);
+            let connection = Network::new(Some(stream), address)
+                .with_peer(peer)
+                .with_shutdown(&shutdown)
+                .with_limits(limits);
+            let connection_clone = connection.clone();
+            relay::run_connection(connection, relay, copied_network_list, relayed).await;
+
+            let reason = connection_clone.close_reason();
+            match &reason {
+                Some(reason) => println!("Connection to {:?} has terminated, {}.", address, reason),
+                None => println!("Connection to {:?} has terminated.", address),
+            }
+            let stats = connection_clone.stats();
+            println!(
+                "{:?} was connected for {:?}, receiving {} bytes and sending {}",
+                address, stats.connected_for, stats.bytes_received, stats.bytes_sent
+            );
+            if connection_clone.misbehavior_score() >= BAN_THRESHOLD {
+                let reason = reason.map(|reason| reason.to_string()).unwrap_or_default();
+                match ban_address(&ban_path, address.ip(), DEFAULT_BAN_DURATION, &reason) {
+                    Ok(_) => println!("Banned {} for misbehaving", address.ip()),
+                    Err(e) => println!("Unable to ban {}: {}", address.ip(), e),
+                }
+            }
+            drop(slot);
+            drop(drain);
         });
     }
+
+    drop(drain);
+    drained.recv().await;
+    chain_task.await.expect("Unable to join the chain task");
+}
+
+/// Resolves once the node is asked to stop with SIGINT, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Bans `ip` in the ban list at `path` and saves it.
+fn ban_address(path: &Path, ip: IpAddr, duration: Duration, reason: &str) -> Result<Ban, BanError> {
+    let now = message::current_time();
+    let mut bans = BanList::open(path)?;
+    let ban = bans.ban(ip, duration, reason, now).clone();
+    bans.save(now)?;
+
+    Ok(ban)
+}
+
+/// Opens the contact book of the keystore, exiting if it cannot be read.
+fn open_contacts(keystore: &Keystore) -> ContactBook {
+    match keystore.contact_book() {
+        Ok(contacts) => contacts,
+        Err(e) => {
+            eprintln!("Unable to read contacts: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints every message to or from `identity` on the stored chain, naming
+/// senders from the contact book.
+fn print_inbox(identity: &Identity, contacts: &ContactBook) {
+    let mut chain = match Chain::new(Arc::new(Mutex::new(HashMap::new()))) {
+        Some(chain) => chain,
+        None => {
+            println!("Unable to open the blockchain");
+            return;
+        }
+    };
+    if let Err(e) = chain.learn_addresses() {
+        println!("Unable to read addresses from the chain: {:?}", e);
+    }
+
+    let mut inbox = Inbox::new(identity);
+    if let Err(e) = chain.scan_inbox(&mut inbox) {
+        println!("Unable to scan the chain: {:?}", e);
+        return;
+    }
+    for entry in inbox.all_messages() {
+        // Unsigned messages could claim to be from anyone, so they are not
+        // shown under a contact's name.
+        let sender = if entry.verified {
+            contacts.label(&entry.from_address)
+        } else {
+            format!("{} (unverified)", entry.from_address)
+        };
+        println!(
+            "{:>6} {}: {}",
+            entry.block_id,
+            sender,
+            entry.payload.summary()
+        );
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{utils, Message};
+    use crate::address::AddressError;
+    use crate::bans::{BanError, BAN_THRESHOLD};
+    use crate::chain::{ChainError, ChainStatus};
+    use crate::chunk::{self, ChunkError, ChunkStore, CHUNK_SIZE};
+    use crate::cli;
+    use crate::contacts::ContactError;
+    use crate::directory::RecordError;
+    use crate::formats::{self, FormatError};
+    use crate::handshake::{
+        handshake, HandshakeError, HANDSHAKE_TIMEOUT, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    };
+    use crate::keys::generate_mnemonic;
+    use crate::limits::{ConnectionSlots, LimitError, RateLimiter, MAX_QUEUED_BYTES};
+    use crate::mempool::{Mempool, MempoolError};
+    use crate::message::{current_time, Expiry};
+    use crate::network::{CloseReason, Keepalive, NetworkError, PeerStream, QUEUE_CAPACITY};
+    use crate::payload::{Attachment, Compression, MessageRef, PayloadError, PAYLOAD_VERSION};
+    use crate::protocol::{self, MAX_FRAME_SIZE};
+    use crate::relay::{Handled, REQUEST_TIMEOUT};
+    use crate::simulation::{Simulation, Topology};
+    use crate::transport;
+    use crate::utils::{KeyError, Keystore};
+    use crate::{
+        Address, AddressBook, BanList, Block, Capabilities, Chain, ContactBook, ContactCard,
+        Directory, DisconnectReason, ExportFormat, Hello, Identity, Inbox, InventoryItem,
+        KeyAlgorithm, KeyRecord, Limits, MemoryTransport, Message, Misbehavior, Network, NoiseKey,
+        Object, Payload, PeerMessage, PrivateKey, PublicKey, Registration, Relay, RelayMessage,
+        Revocation, Rotation, Transport, Trust,
+    };
+    use bip39::Mnemonic;
+    use rand::rngs::OsRng;
+    use rsa::{pkcs8::ToPrivateKey, RsaPrivateKey};
+    use std::collections::HashMap;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::sync::CancellationToken;
+
+    const TEST_PASSPHRASE: &str = "test passphrase";
+
+    fn test_identity(name: &str) -> Identity {
+        let private_key = RsaPrivateKey::new(&mut OsRng, 1024).expect("Unable to generate key");
+
+        Identity::new(name, PrivateKey::from(private_key))
+    }
 
     #[test]
     fn message_decryption_works() {
-        let (private_key, public_key) = utils::get_keys();
+        let identity = test_identity("test");
+        let public_key = identity.public_key.clone();
 
-        let test_text = "Testing123";
+        let test_text = Payload::from("Testing123");
 
-        let mut message: Message = Message::new(&public_key, &public_key, &test_text);
+        let mut message: Message = Message::new(&public_key, &identity, test_text.clone());
 
-        assert_eq!(&test_text, &message.text);
+        assert_eq!(Some(&test_text), message.payload.as_ref());
 
         message
             .encrypt(&public_key)
             .expect("Unable to encrypt message");
 
-        assert_ne!(&message.text, &test_text);
+        assert!(message.payload.is_none());
+        assert!(!message.ciphertext.is_empty());
+
+        message
+            .decrypt(&identity)
+            .expect("Unable to decrypt message");
+
+        assert_eq!(Some(&test_text), message.payload.as_ref());
+    }
+
+    #[test]
+    fn binary_attachment_round_trips() {
+        let identity = test_identity("test");
+        let public_key = identity.public_key.clone();
+
+        let data: Vec<u8> = vec![0xff, 0x00, 0xfe, 0x10, 0x00, 0x00];
+        let attachment = Attachment::new("application/octet-stream", "blob.bin", data)
+            .expect("Unable to build attachment");
+        let payload = Payload::Attachment(attachment);
+
+        let mut message: Message = Message::new(&public_key, &identity, payload.clone());
+        message
+            .encrypt(&public_key)
+            .expect("Unable to encrypt message");
+        message
+            .decrypt(&identity)
+            .expect("Unable to decrypt message");
+
+        assert_eq!(Some(&payload), message.payload.as_ref());
+    }
+
+    #[test]
+    fn chunked_payload_reassembles() {
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 100)).map(|i| i as u8).collect();
+        let attachment = Attachment::new("application/octet-stream", "large.bin", data)
+            .expect("Unable to build attachment");
+        let payload = Payload::Attachment(attachment);
+
+        let (manifest, chunks) = chunk::split(&payload).expect("Unable to split payload");
+        assert_eq!(3, chunks.len());
+
+        let mut store = ChunkStore::new();
+        store
+            .insert_payload(&chunks[0])
+            .expect("Unable to store chunk");
+        store
+            .insert_payload(&chunks[2])
+            .expect("Unable to store chunk");
+
+        match store.reassemble(&manifest) {
+            Err(ChunkError::Missing(missing)) => {
+                assert_eq!(vec![manifest.chunk_hashes[1].to_owned()], missing)
+            }
+            _ => panic!("Expected the middle chunk to be reported missing"),
+        }
+
+        store
+            .insert_payload(&chunks[1])
+            .expect("Unable to store chunk");
+        assert_eq!(
+            payload,
+            store.reassemble(&manifest).expect("Unable to reassemble")
+        );
+
+        // Sizes from an untrusted manifest are checked before allocating.
+        let mut huge = manifest.clone();
+        huge.total_size = u64::MAX;
+        assert!(matches!(
+            store.reassemble(&huge),
+            Err(ChunkError::Payload(PayloadError::TooLarge { .. }))
+        ));
+        let mut overstated = manifest.clone();
+        overstated.total_size = (CHUNK_SIZE * 3 + 1) as u64;
+        assert!(matches!(
+            store.reassemble(&overstated),
+            Err(ChunkError::SizeMismatch { .. })
+        ));
+
+        // Chunks are no larger than `split` makes them, and a manifest
+        // repeating one stops being read once it passes its stated size.
+        assert!(matches!(
+            store.insert(vec![0; CHUNK_SIZE + 1]),
+            Err(ChunkError::Payload(PayloadError::TooLarge { .. }))
+        ));
+        let repeated = chunk::Manifest {
+            total_size: CHUNK_SIZE as u64,
+            chunk_hashes: vec![manifest.chunk_hashes[0].to_owned(); 1000],
+            chunk_messages: Vec::new(),
+        };
+        assert!(matches!(
+            store.reassemble(&repeated),
+            Err(ChunkError::SizeMismatch { actual, .. }) if actual == 2 * CHUNK_SIZE as u64
+        ));
+
+        // Sealed manifests name the messages to request missing chunks by.
+        let identity = test_identity("test");
+        let mut messages =
+            chunk::seal(&payload, &identity.public_key, &identity).expect("Unable to seal");
+        assert_eq!(4, messages.len());
+        let digests: Vec<String> = messages.iter().map(|message| message.digest()).collect();
+        let mut store = ChunkStore::new();
+        for message in messages.iter_mut() {
+            message.decrypt(&identity).expect("Unable to decrypt");
+        }
+        let manifest = match &messages[3].payload {
+            Some(Payload::Manifest(manifest)) => manifest.clone(),
+            _ => panic!("Expected the manifest last"),
+        };
+        assert_eq!(digests[..3].to_vec(), manifest.chunk_messages);
+        for message in [&messages[0], &messages[2]] {
+            store
+                .insert_payload(message.payload.as_ref().expect("Missing payload"))
+                .expect("Unable to store chunk");
+        }
+        assert_eq!(
+            vec![digests[1].to_owned()],
+            store.missing_messages(&manifest)
+        );
+    }
+
+    /// Compressible text ends up smaller with deflate than raw. The sizes for
+    /// a range of payloads are printed by the `compression` example.
+    #[test]
+    fn deflate_shrinks_compressible_messages() {
+        let identity = test_identity("test");
+        let public_key = identity.public_key.clone();
+
+        let text = "Are we still meeting at the usual place tomorrow? Let me know. ".repeat(64);
+        let payload = Payload::from(text.as_str());
+
+        let mut uncompressed: Message = Message::new(&public_key, &identity, payload.clone());
+        uncompressed
+            .encrypt_with(&public_key, Compression::None)
+            .expect("Unable to encrypt message");
+        let mut compressed: Message = Message::new(&public_key, &identity, payload.clone());
+        compressed
+            .encrypt(&public_key)
+            .expect("Unable to encrypt message");
+
+        assert!(compressed.ciphertext.len() < uncompressed.ciphertext.len());
+        compressed
+            .decrypt(&identity)
+            .expect("Unable to decrypt message");
+        assert_eq!(Some(payload), compressed.payload);
+    }
+
+    #[test]
+    fn expired_messages_are_rejected_hidden_and_pruned() {
+        let identity = test_identity("test");
+        let public_key = identity.public_key.clone();
+
+        let mut expired: Message = Message::new(&public_key, &identity, Payload::from("old"))
+            .with_expiry(Expiry::Height(1));
+        expired
+            .encrypt(&public_key)
+            .expect("Unable to encrypt message");
+        let mut live: Message = Message::new(&public_key, &identity, Payload::from("new"))
+            .with_expiry(Expiry::Height(10));
+        live.encrypt(&public_key)
+            .expect("Unable to encrypt message");
+
+        let mut mempool = Mempool::new();
+        assert!(matches!(
+            mempool.submit(expired.clone(), 2, current_time()),
+            Err(MempoolError::Expired)
+        ));
+        assert!(mempool.submit(live.clone(), 2, current_time()).is_ok());
+        // Messages land in the next block, so one expiring at the current
+        // height is already too late.
+        let mut last_call = Message::new(&public_key, &identity, Payload::from("now"))
+            .with_expiry(Expiry::Height(2));
+        last_call
+            .encrypt(&public_key)
+            .expect("Unable to encrypt message");
+        assert!(matches!(
+            mempool.submit(last_call.clone(), 2, current_time()),
+            Err(MempoolError::Expired)
+        ));
+        assert!(mempool.submit(last_call, 1, current_time()).is_ok());
+        assert_eq!(2, mempool.drain(1, current_time()).len());
+        assert!(mempool.submit(live.clone(), 2, current_time()).is_ok());
+
+        let mut block = Block::new(vec![expired, live], &public_key, None, 0);
+        let mut inbox = Inbox::new(&identity);
+        inbox.scan_block(&block);
+
+        assert_eq!(2, inbox.all_messages().len());
+        let visible = inbox.messages(2, current_time());
+        assert_eq!(1, visible.len());
+        assert_eq!(Payload::from("new"), visible[0].payload);
+
+        let digest = block.data[0].digest();
+        block.data[0].prune();
+        assert!(block.data[0].is_pruned());
+        assert!(block.data[0].ciphertext.is_empty());
+        assert_eq!(digest, block.data[0].digest());
+
+        // The header is still committed to once the body is gone.
+        let mut rewritten = block.data[0].clone();
+        rewritten.expires = Some(Expiry::Height(100));
+        assert_ne!(digest, rewritten.digest());
+
+        // Bodies of messages that are still live cannot be pruned away.
+        let directory = std::env::temp_dir().join(format!("biddy-pruned-{}", std::process::id()));
+        let mut chain =
+            Chain::open(&directory, Arc::new(Mutex::new(HashMap::new()))).expect("Unable to open");
+        let mut stripped = Block::new(vec![block.data[1].clone()], &public_key, None, 0);
+        stripped.data[0].prune();
+        stripped.finalize();
+        assert!(matches!(
+            chain.add_block(stripped),
+            Err(ChainError::InvalidBlock)
+        ));
+
+        // Peers can't claim a body was pruned, so a forged digest doesn't
+        // survive being sent.
+        let mut forged = Block::new(
+            vec![block.data[1].clone()],
+            &public_key,
+            Some(Block::genesis().hash),
+            1,
+        );
+        forged.data[0].restore_pruned(String::from("forged"));
+        forged.finalize();
+        assert!(forged.validate_block(&Directory::new()));
+        let sent: Block =
+            bincode::deserialize(&bincode::serialize(&forged).expect("Unable to serialize"))
+                .expect("Unable to deserialize");
+        assert!(!sent.data[0].is_pruned());
+        assert!(!sent.validate_block(&Directory::new()));
+
+        // Pruned bodies are kept track of on disk.
+        let mut gone = Message::new(&public_key, &identity, Payload::from("gone"))
+            .with_expiry(Expiry::Time(1));
+        gone.encrypt(&public_key)
+            .expect("Unable to encrypt message");
+        let mut kept = Block::new(vec![gone], &public_key, Some(Block::genesis().hash), 1);
+        kept.finalize();
+        let hash = kept.hash.clone();
+        chain.add_block(kept).expect("Unable to add block");
+        chain.set_pruning(true);
+        chain.flush().expect("Unable to save the chain");
+        let saved = chain
+            .load_blocks()
+            .expect("Unable to load blocks")
+            .into_iter()
+            .find(|block| block.hash == hash)
+            .expect("Missing saved block");
+        assert!(saved.data[0].is_pruned());
+        assert_eq!(hash, saved.computed_hash());
+        std::fs::remove_dir_all(&directory).expect("Unable to remove chain");
+    }
+
+    #[test]
+    fn conversation_threads_follow_replies() {
+        let identity = test_identity("test");
+        let public_key = identity.public_key.clone();
+        let other_identity = test_identity("other");
+        let other_public = other_identity.public_key.clone();
+
+        let mut question = Message::new(&other_public, &identity, Payload::from("lunch?"));
+        question.encrypt(&other_public).expect("Unable to encrypt");
+        let mut unrelated = Message::new(&other_public, &identity, Payload::from("unrelated"));
+        unrelated.encrypt(&other_public).expect("Unable to encrypt");
+        let first = Block::new(vec![question, unrelated], &public_key, None, 0);
+
+        let mut answer = Message::new(&public_key, &other_identity, Payload::from("sure"))
+            .reply_to(MessageRef {
+                block_hash: first.hash.to_owned(),
+                index: 0,
+            });
+        answer.encrypt(&public_key).expect("Unable to encrypt");
+        let second = Block::new(vec![answer], &other_public, Some(first.hash.to_owned()), 1);
+
+        let mut inbox = Inbox::new(&identity);
+        inbox.scan_block(&second);
+        inbox.scan_block(&first);
+
+        let other = other_identity.address;
+        let conversation = inbox.conversation(&other, 1, current_time());
+        assert_eq!(3, conversation.len());
+        assert_eq!(Payload::from("lunch?"), conversation[0].payload);
+
+        let threads = inbox.threads(&other, 1, current_time());
+        assert_eq!(2, threads.len());
+        assert_eq!(2, threads[0].entries.len());
+        assert_eq!(Payload::from("sure"), threads[0].entries[1].payload);
+        assert_eq!(Payload::from("unrelated"), threads[1].entries[0].payload);
+
+        // Payloads sealed by earlier versions still open, with replies
+        // threaded through the header.
+        #[derive(serde::Serialize)]
+        enum EarlierPayload<R> {
+            Text(String),
+            // Only holds the place of attachments so replies keep their tag.
+            #[allow(dead_code)]
+            Attachment(()),
+            Reply {
+                message: R,
+                text: String,
+            },
+        }
+        let earlier = |version: u8, header: Vec<u8>, payload: Vec<u8>| {
+            let mut sealed = vec![version, 0];
+            sealed.extend(header);
+            sealed.extend(bincode::serialize(&payload).expect("Unable to serialize"));
+            Payload::open(&sealed)
+        };
+        let reference = MessageRef {
+            block_hash: first.hash.to_owned(),
+            index: 1,
+        };
+
+        let v1: EarlierPayload<String> = EarlierPayload::Text(String::from("hi"));
+        let (header, payload) = earlier(1, Vec::new(), bincode::serialize(&v1).unwrap())
+            .expect("Unable to open a version 1 payload");
+        assert_eq!((1, None), (header.version, header.in_reply_to));
+        assert_eq!(Payload::from("hi"), payload);
+        let v1 = EarlierPayload::Reply {
+            message: first.hash.to_owned(),
+            text: String::from("re"),
+        };
+        let (header, payload) = earlier(1, Vec::new(), bincode::serialize(&v1).unwrap())
+            .expect("Unable to open a version 1 payload");
+        assert_eq!(
+            Some(first.hash.as_str()),
+            header.in_reply_to.as_ref().map(|r| r.block_hash.as_str())
+        );
+        assert_eq!(Payload::from("re"), payload);
+
+        let v2 = EarlierPayload::Reply {
+            message: reference.clone(),
+            text: String::from("re"),
+        };
+        let no_reply = bincode::serialize(&None::<MessageRef>).unwrap();
+        let (header, payload) = earlier(2, no_reply, bincode::serialize(&v2).unwrap())
+            .expect("Unable to open a version 2 payload");
+        assert_eq!(Some(reference), header.in_reply_to);
+        assert_eq!(Payload::from("re"), payload);
+
+        assert!(matches!(
+            earlier(PAYLOAD_VERSION + 1, Vec::new(), Vec::new()),
+            Err(PayloadError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn keystore_holds_named_identities() {
+        let directory = std::env::temp_dir().join(format!("biddy-keystore-{}", std::process::id()));
+        let keystore = Keystore::open(&directory).expect("Unable to open keystore");
+
+        let alice = test_identity("alice");
+        let bob = test_identity("bob");
+        keystore
+            .store_identity(&alice, TEST_PASSPHRASE)
+            .expect("Unable to store identity");
+        std::fs::write(directory.join("bob.tmp"), b"left over").expect("Unable to write file");
+        keystore
+            .store_identity(&bob, TEST_PASSPHRASE)
+            .expect("Unable to store identity");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = std::fs::metadata(directory.join("bob.key")).expect("Missing key");
+            assert_eq!(0o600, metadata.permissions().mode() & 0o777);
+        }
+        assert_eq!(
+            vec!["alice", "bob"],
+            keystore.identities().expect("Unable to list identities")
+        );
+
+        let loaded = keystore
+            .load_identity("bob", TEST_PASSPHRASE)
+            .expect("Unable to load identity");
+        assert_eq!(bob.fingerprint, loaded.fingerprint);
+        assert!(matches!(
+            keystore.load_identity("bob", "wrong passphrase"),
+            Err(KeyError::WrongPassphrase(_))
+        ));
+        assert!(keystore
+            .store_identity(&test_identity("../escape"), TEST_PASSPHRASE)
+            .is_err());
+
+        keystore
+            .set_active_identity("bob")
+            .expect("Unable to select identity");
+        assert_eq!(
+            "bob",
+            keystore.active_identity().expect("Unable to read identity")
+        );
+        assert!(matches!(
+            keystore.set_active_identity("carol"),
+            Err(KeyError::NotFound(_))
+        ));
+
+        std::fs::copy(directory.join("alice.pub"), directory.join("bob.pub"))
+            .expect("Unable to copy public key");
+        assert!(matches!(
+            keystore.load_identity("bob", TEST_PASSPHRASE),
+            Err(KeyError::KeyMismatch(_))
+        ));
+        std::fs::write(directory.join("alice.key"), "not a key").expect("Unable to write key");
+        assert!(matches!(
+            keystore.load_identity("alice", TEST_PASSPHRASE),
+            Err(KeyError::Malformed(_))
+        ));
+
+        std::fs::remove_dir_all(&directory).expect("Unable to remove keystore");
+    }
+
+    #[test]
+    fn addresses_round_trip_and_resolve() {
+        let identity = test_identity("test");
+        let address = identity.address;
+
+        let text = address.to_string();
+        assert!(text.len() < 40);
+        assert_eq!(Ok(address), text.parse::<Address>());
+
+        let mut typo: Vec<char> = text.chars().collect();
+        typo[5] = if typo[5] == '2' { '3' } else { '2' };
+        let typo: String = typo.into_iter().collect();
+        assert_eq!(Err(AddressError::InvalidChecksum), typo.parse::<Address>());
+
+        let mut book = AddressBook::new();
+        assert_eq!(
+            Err(AddressError::Unknown(address)),
+            book.resolve(&text).map(|_| ())
+        );
+
+        let message = Message::new(&identity.public_key, &identity, Payload::from("hi"));
+        book.learn_block(&Block::new(vec![message], &identity.public_key, None, 0));
+        assert_eq!(Ok(identity.public_key.clone()), book.resolve(&text));
+        assert_eq!(
+            Ok(identity.public_key.clone()),
+            book.resolve(&identity.encoded_public_key())
+        );
+    }
+
+    #[test]
+    fn registrations_claim_nicknames_first_come() {
+        let alice = test_identity("alice");
+        let mallory = test_identity("mallory");
+        let mut directory = Directory::new();
+
+        let claim = KeyRecord::Registration(Registration::new(&alice, Some("alice"), 1));
+        directory.apply(&claim, 0).expect("Unable to register");
+        assert_eq!(
+            alice.address,
+            directory
+                .find_nickname("alice")
+                .expect("Missing entry")
+                .address
+        );
+
+        let stolen = KeyRecord::Registration(Registration::new(&mallory, Some("alice"), 1));
+        assert_eq!(
+            Err(RecordError::NicknameTaken(String::from("alice"))),
+            directory.apply(&stolen, 1)
+        );
+
+        let mut forged = Registration::new(&mallory, Some("mallory"), 1);
+        forged.public_key = alice.encoded_public_key();
+        assert_eq!(
+            Err(RecordError::InvalidSignature),
+            directory.check(&KeyRecord::Registration(forged), 1)
+        );
+
+        assert!(matches!(
+            directory.check(&claim, 1),
+            Err(RecordError::StaleSequence { .. })
+        ));
+        let rename = KeyRecord::Registration(Registration::new(&alice, Some("alice2"), 2));
+        directory.apply(&rename, 2).expect("Unable to update");
+        assert!(directory.find_nickname("alice").is_none());
+        assert_eq!(
+            0,
+            directory
+                .get(&alice.address)
+                .expect("Missing entry")
+                .registered_at
+        );
+
+        let block = Block::new(Vec::new(), &alice.public_key, None, 0).with_records(vec![claim]);
+        assert_ne!(
+            Block::new(Vec::new(), &alice.public_key, None, 0).hash,
+            block.hash
+        );
+
+        // A record that fails to be mined does not take queued messages
+        // with it.
+        let directory =
+            std::env::temp_dir().join(format!("biddy-registrations-{}", std::process::id()));
+        let mut chain =
+            Chain::open(&directory, Arc::new(Mutex::new(HashMap::new()))).expect("Unable to open");
+        let claim = KeyRecord::Registration(Registration::new(&alice, Some("alice"), 0));
+        chain
+            .submit_record(claim.clone())
+            .expect("Unable to submit");
+        let mut message = Message::new(&alice.public_key, &mallory, Payload::from("hi"));
+        message
+            .encrypt(&alice.public_key)
+            .expect("Unable to encrypt message");
+        chain.submit_message(message).expect("Unable to submit");
+        let tip = chain.latest_hash().map(String::from);
+        let mut claimed =
+            Block::new(Vec::new(), &alice.public_key, tip, 1).with_records(vec![claim]);
+        claimed.finalize();
+        chain.add_block(claimed).expect("Unable to add block");
+        assert!(matches!(
+            chain.mine_pending(&mallory),
+            Err(ChainError::InvalidRecord(_))
+        ));
+        assert_eq!(1, chain.pending_messages());
+        assert_eq!(
+            1,
+            chain.mine_pending(&mallory).expect("Unable to mine").len()
+        );
+        assert_eq!(0, chain.pending_messages());
+
+        // Records are checked at the height they will be mined at.
+        let current = chain.height();
+        let revocation = Revocation::new(&alice.public_key, &alice, current);
+        assert!(matches!(
+            chain.submit_record(KeyRecord::Revocation(revocation)),
+            Err(RecordError::RetroactiveRevocation { .. })
+        ));
+        let revocation = Revocation::new(&alice.public_key, &alice, current + 1);
+        chain
+            .submit_record(KeyRecord::Revocation(revocation))
+            .expect("Unable to submit");
+        std::fs::remove_dir_all(&directory).expect("Unable to remove chain");
+    }
+
+    #[test]
+    fn rotated_keys_move_and_revoked_keys_stop_authoring() {
+        let old = test_identity("old");
+        let new = test_identity("new");
+        let mut directory = Directory::new();
+
+        let claim = KeyRecord::Registration(Registration::new(&old, Some("carol"), 0));
+        directory.apply(&claim, 0).expect("Unable to register");
+        let rotation = KeyRecord::Rotation(Rotation::new(&old, &new));
+        directory.apply(&rotation, 1).expect("Unable to rotate");
+
+        assert_eq!(
+            new.address,
+            directory
+                .find_nickname("carol")
+                .expect("Missing entry")
+                .address
+        );
+        assert_eq!(Ok(new.address), directory.current_address(&old.address, 1));
+        assert!(matches!(
+            directory.check(&rotation, 2),
+            Err(RecordError::AlreadyRotated(_))
+        ));
+
+        let outsider = test_identity("outsider");
+        let forged = KeyRecord::Revocation(Revocation::new(&old.public_key, &outsider, 5));
+        assert_eq!(
+            Err(RecordError::UnauthorizedRevocation),
+            directory.check(&forged, 2)
+        );
+        let late = KeyRecord::Revocation(Revocation::new(&old.public_key, &new, 1));
+        assert!(matches!(
+            directory.check(&late, 2),
+            Err(RecordError::RetroactiveRevocation { .. })
+        ));
+
+        let stolen = KeyRecord::Revocation(Revocation::new(&old.public_key, &old, u32::MAX));
+        directory.apply(&stolen, 2).expect("Unable to revoke");
+        assert!(!directory.is_revoked(&old.address, 3));
+        let revocation = KeyRecord::Revocation(Revocation::new(&old.public_key, &new, 3));
+        directory.apply(&revocation, 2).expect("Unable to revoke");
+        assert!(matches!(
+            directory.check(&stolen, 2),
+            Err(RecordError::AlreadyRevoked(_))
+        ));
+        assert!(!directory.is_revoked(&old.address, 2));
+        assert!(directory.is_revoked(&old.address, 3));
+
+        let mut before = Block::new(Vec::new(), &old.public_key, None, 0);
+        before.finalize();
+        assert!(before.validate_block(&directory));
+
+        let mut after = Block::new(Vec::new(), &old.public_key, Some(before.hash.to_owned()), 3);
+        after.finalize();
+        assert!(!after.validate_block(&directory));
+
+        let own = KeyRecord::Revocation(Revocation::new(&new.public_key, &new, 4));
+        directory.apply(&own, 4).expect("Unable to revoke");
+        assert_eq!(
+            Err(RecordError::Revoked(new.address)),
+            directory.current_address(&old.address, 4)
+        );
+    }
+
+    #[test]
+    fn ed25519_identities_sign_and_encrypt() {
+        let rsa = test_identity("rsa");
+        let curve = Identity::new(
+            "curve",
+            PrivateKey::generate(KeyAlgorithm::Ed25519).expect("Unable to generate key"),
+        );
+        let encoded = curve.encoded_public_key();
+        assert!(encoded.starts_with("ed25519:"));
+        assert_eq!(Some(curve.public_key.clone()), PublicKey::decode(&encoded));
+        assert_eq!(
+            Some(rsa.public_key.clone()),
+            PublicKey::decode(&rsa.encoded_public_key())
+        );
+
+        let text = Payload::from("Testing123");
+        let mut message = Message::new(&rsa.public_key, &curve, text.clone());
+        message.encrypt(&rsa.public_key).expect("Unable to encrypt");
+        let mut reply = Message::new(&curve.public_key, &rsa, text.clone());
+        reply.encrypt(&curve.public_key).expect("Unable to encrypt");
+
+        let mut sent = message.clone();
+        sent.decrypt(&curve).expect("Unable to decrypt own message");
+        message.decrypt(&rsa).expect("Unable to decrypt");
+        reply.decrypt(&curve).expect("Unable to decrypt");
+        assert_eq!(Some(&text), message.payload.as_ref());
+        assert_eq!(Some(&text), sent.payload.as_ref());
+        assert_eq!(Some(&text), reply.payload.as_ref());
+
+        // Wrapped keys are authenticated, ephemeral key included.
+        let wrapped = curve
+            .public_key
+            .wrap_key(&[7u8; 32])
+            .expect("Unable to wrap key");
+        assert_eq!(
+            vec![7u8; 32],
+            curve
+                .private_key
+                .unwrap_key(&wrapped)
+                .expect("Unable to unwrap key")
+        );
+        for index in [0, 40, wrapped.len() - 1] {
+            let mut tampered = wrapped.clone();
+            tampered[index] ^= 1;
+            assert!(curve.private_key.unwrap_key(&tampered).is_err());
+        }
+
+        let mut directory = Directory::new();
+        let claim = KeyRecord::Registration(Registration::new(&curve, Some("curve"), 0));
+        directory.apply(&claim, 0).expect("Unable to register");
+        assert_eq!(
+            Some(curve.address),
+            directory.find_nickname("curve").map(|e| e.address)
+        );
+        let mut block =
+            Block::new(Vec::new(), &curve.public_key, None, 0).with_records(vec![claim]);
+        block.finalize();
+        assert!(block.validate_block(&directory));
+
+        let directory = std::env::temp_dir().join(format!("biddy-ed25519-{}", std::process::id()));
+        let keystore = Keystore::open(&directory).expect("Unable to open keystore");
+        let created = keystore
+            .create_identity("curve", TEST_PASSPHRASE, KeyAlgorithm::Ed25519)
+            .expect("Unable to create identity");
+        let loaded = keystore
+            .load_identity("curve", TEST_PASSPHRASE)
+            .expect("Unable to load identity");
+        assert_eq!(created.address, loaded.address);
+        assert_eq!(
+            created.public_key,
+            keystore.public_key("curve").expect("Unable to read key")
+        );
+        assert!(matches!(
+            keystore.load_identity("curve", "wrong passphrase"),
+            Err(KeyError::WrongPassphrase(_))
+        ));
+
+        std::fs::remove_dir_all(&directory).expect("Unable to remove keystore");
+    }
+
+    #[test]
+    fn chain_parts_saved_before_key_algorithms_still_load() {
+        // The block and message layout chain parts were written with before
+        // key algorithms were tagged.
+        #[derive(serde::Serialize)]
+        struct BaselineMessage {
+            to: String,
+            from: String,
+            text: String,
+            signing_key: Option<String>,
+        }
+        #[derive(serde::Serialize)]
+        struct BaselineBlock {
+            node_id: u32,
+            previous_hash: Option<String>,
+            hash: String,
+            data: BaselineMessage,
+            author_public_key: String,
+            seed: u32,
+        }
+
+        let directory =
+            std::env::temp_dir().join(format!("biddy-baseline-parts-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).expect("Unable to create directory");
+        let baseline = BaselineBlock {
+            node_id: 3,
+            previous_hash: Some(String::from("4249EF01")),
+            hash: String::from("4249ABCD"),
+            data: BaselineMessage {
+                to: String::from("746f"),
+                from: String::from("66726f6d"),
+                text: String::from("00112233445566778899aabbccddeeff"),
+                signing_key: Some(String::from("0102")),
+            },
+            author_public_key: String::from("617574686f72"),
+            seed: 7,
+        };
+        let part = HashMap::from([(baseline.hash.clone(), baseline)]);
+        std::fs::write(
+            directory.join("chain-3-3.chain.part"),
+            bincode::serialize(&part).expect("Unable to serialize"),
+        )
+        .expect("Unable to write part");
+
+        let mut chain =
+            Chain::open(&directory, Arc::new(Mutex::new(HashMap::new()))).expect("Unable to open");
+        chain.flush().expect("Unable to save the chain");
+        let blocks = chain.load_blocks().expect("Unable to load blocks");
+        assert_eq!(2, blocks.len());
+        assert!(blocks
+            .iter()
+            .any(|block| block.hash == Block::genesis().hash));
+
+        let old = blocks
+            .iter()
+            .find(|block| block.hash == "4249ABCD")
+            .expect("Missing baseline block");
+        assert_eq!("617574686f72", old.author_public_key);
+        assert_eq!(1, old.data.len());
+        assert_eq!("66726f6d", old.data[0].from);
+        assert_eq!(16, old.data[0].ciphertext.len());
+        assert_eq!(Some(vec![1, 2]), old.data[0].signing_key);
+
+        std::fs::remove_dir_all(&directory).expect("Unable to remove chain");
+    }
+
+    #[test]
+    fn mnemonics_restore_the_same_identities() {
+        let mnemonic = generate_mnemonic();
+        assert_eq!(24, mnemonic.word_count());
+        let parsed = Mnemonic::parse(mnemonic.to_string()).expect("Unable to parse mnemonic");
+        assert_eq!(
+            PrivateKey::from_mnemonic(&mnemonic, "").public_key(),
+            PrivateKey::from_mnemonic(&parsed, "").public_key()
+        );
+        assert_ne!(
+            PrivateKey::from_mnemonic(&mnemonic, "").public_key(),
+            PrivateKey::from_mnemonic(&mnemonic, "extra").public_key()
+        );
+
+        let directory = std::env::temp_dir().join(format!("biddy-mnemonic-{}", std::process::id()));
+        let keystore = Keystore::open(&directory).expect("Unable to open keystore");
+        let alice = keystore
+            .restore_identity("alice", &mnemonic, "", TEST_PASSPHRASE)
+            .expect("Unable to derive identity");
+        let mut message = Message::new(&alice.public_key, &alice, Payload::from("kept"));
+        message
+            .encrypt(&alice.public_key)
+            .expect("Unable to encrypt");
+        let block = Block::new(vec![message], &alice.public_key, None, 0);
+
+        std::fs::remove_file(directory.join("alice.key")).expect("Unable to remove key");
+        let restored = keystore
+            .restore_identity("alice", &parsed, "", "new passphrase")
+            .expect("Unable to restore identity");
+        assert_eq!(alice.address, restored.address);
+        assert_eq!(
+            alice.address,
+            keystore
+                .load_identity("alice", "new passphrase")
+                .expect("Unable to load identity")
+                .address
+        );
+        keystore
+            .restore_identity("alice", &parsed, "", TEST_PASSPHRASE)
+            .expect("Restoring twice should succeed");
+        let carol = keystore
+            .restore_identity("carol", &parsed, "", TEST_PASSPHRASE)
+            .expect("Unable to restore under another name");
+        assert_eq!(alice.address, carol.address);
+
+        let mut inbox = Inbox::new(&restored);
+        inbox.scan_block(&block);
+        assert_eq!(1, inbox.all_messages().len());
+
+        keystore
+            .store_identity(&test_identity("bob"), TEST_PASSPHRASE)
+            .expect("Unable to store identity");
+        assert!(matches!(
+            keystore.restore_identity("bob", &mnemonic, "", TEST_PASSPHRASE),
+            Err(KeyError::AlreadyExists(_))
+        ));
+        assert!(keystore.check_available("dave").is_ok());
+        assert!(matches!(
+            keystore.check_available("bob"),
+            Err(KeyError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            keystore.check_available("no spaces"),
+            Err(KeyError::InvalidName(_))
+        ));
+
+        std::fs::remove_dir_all(&directory).expect("Unable to remove keystore");
+    }
+
+    #[test]
+    fn keys_import_and_export_in_standard_formats() {
+        let rsa = test_identity("rsa");
+        let curve = Identity::new(
+            "curve",
+            PrivateKey::generate(KeyAlgorithm::Ed25519).expect("Unable to generate key"),
+        );
+
+        for identity in [&rsa, &curve] {
+            let document = formats::encrypt_private_key(&identity.private_key, TEST_PASSPHRASE)
+                .expect("Unable to encrypt key");
+            for data in [document.to_pem().as_bytes(), document.as_ref()] {
+                let decoded = formats::decode_private_key(data, TEST_PASSPHRASE)
+                    .expect("Unable to decode key");
+                assert_eq!(identity.public_key, decoded.public_key());
+                assert!(matches!(
+                    formats::decode_private_key(data, "wrong passphrase"),
+                    Err(FormatError::WrongPassphrase)
+                ));
+            }
+        }
+        let PrivateKey::Rsa(rsa_key) = &rsa.private_key else {
+            panic!("test identities use RSA");
+        };
+        let plain = rsa_key.to_pkcs8_der().expect("Unable to encode key");
+        assert_eq!(
+            rsa.public_key,
+            formats::decode_private_key(plain.as_ref(), "")
+                .expect("Unable to decode key")
+                .public_key()
+        );
+        assert!(formats::decode_private_key(b"not a key", TEST_PASSPHRASE).is_err());
+
+        let line = formats::to_openssh(&rsa.public_key, "rsa");
+        assert!(line.starts_with("ssh-rsa "));
+        assert_eq!(
+            Ok((rsa.public_key.clone(), String::from("rsa"))),
+            formats::from_openssh(&line)
+        );
+        let line = formats::to_openssh(&curve.public_key, "curve");
+        assert!(line.starts_with("ssh-ed25519 "));
+        assert_eq!(
+            Err(FormatError::MissingEncryptionKey),
+            formats::from_openssh(&line)
+        );
+        assert!(formats::from_openssh("ssh-rsa AAAA rsa").is_err());
+
+        let card = ContactCard::new("Curve", &curve.public_key);
+        assert_eq!(Ok(card.clone()), ContactCard::from_json(&card.to_json()));
+        let mut forged = card.clone();
+        forged.address = rsa.address.to_string();
+        assert!(matches!(
+            ContactCard::from_json(&forged.to_json()),
+            Err(FormatError::AddressMismatch { .. })
+        ));
+        let mut unnamed = card.clone();
+        unnamed.name = String::new();
+        assert!(matches!(
+            unnamed.verify(),
+            Err(FormatError::InvalidContact(_))
+        ));
+
+        let directory = std::env::temp_dir().join(format!("biddy-formats-{}", std::process::id()));
+        let keystore = Keystore::open(&directory).expect("Unable to open keystore");
+        let imported = keystore
+            .import_identity("curve", document_pem(&curve).as_bytes(), TEST_PASSPHRASE)
+            .expect("Unable to import identity");
+        assert_eq!(curve.address, imported.address);
+        assert!(matches!(
+            keystore.import_identity("curve", document_pem(&curve).as_bytes(), TEST_PASSPHRASE),
+            Err(KeyError::AlreadyExists(_))
+        ));
+        let exported = keystore
+            .export_public_key("curve", ExportFormat::Contact)
+            .expect("Unable to export contact");
+        let mut contacts = keystore.contact_book().expect("Unable to open contacts");
+        let added = contacts
+            .import(&String::from_utf8(exported).expect("Contact cards are text"))
+            .expect("Unable to add contact");
+        assert_eq!(curve.address, added.address);
+        contacts
+            .import(&formats::to_openssh(&rsa.public_key, "rsa"))
+            .expect("Unable to add contact");
+        assert_eq!(
+            vec!["curve", "rsa"],
+            contacts
+                .contacts()
+                .iter()
+                .map(|contact| contact.name.as_str())
+                .collect::<Vec<&str>>()
+        );
+
+        std::fs::remove_dir_all(&directory).expect("Unable to remove keystore");
+    }
+
+    #[test]
+    fn contacts_are_named_verified_and_saved() {
+        let alice = test_identity("alice");
+        let bob = test_identity("bob");
+        let carol = test_identity("carol");
+
+        let directory = std::env::temp_dir().join(format!("biddy-contacts-{}", std::process::id()));
+        let keystore = Keystore::open(&directory).expect("Unable to open keystore");
+        let mut contacts = keystore.contact_book().expect("Unable to open contacts");
+        assert!(contacts.is_empty());
+
+        contacts
+            .add("Alice", &alice.public_key, Trust::Learned)
+            .expect("Unable to add contact");
+        contacts
+            .import(&ContactCard::new("Bob", &bob.public_key).to_json())
+            .expect("Unable to import contact");
+        assert!(matches!(
+            contacts.add("Alice", &carol.public_key, Trust::Learned),
+            Err(ContactError::NameTaken(_))
+        ));
+        assert!(matches!(
+            contacts.add("Robert", &bob.public_key, Trust::Learned),
+            Err(ContactError::AlreadyKnown(_))
+        ));
+        assert!(matches!(
+            contacts.add("", &carol.public_key, Trust::Learned),
+            Err(ContactError::InvalidName(_))
+        ));
+        assert!(!contacts.learn("Bob", &carol.public_key));
+        assert!(contacts.learn("Carol", &carol.public_key));
+
+        assert_eq!("Alice?", contacts.label(&alice.address));
+        assert!(matches!(
+            contacts.verify("Alice", &bob.fingerprint),
+            Err(ContactError::FingerprintMismatch(_))
+        ));
+        assert!(matches!(
+            contacts.verify("Alice", &alice.fingerprint[..8]),
+            Err(ContactError::FingerprintMismatch(_))
+        ));
+        let grouped = alice
+            .short_fingerprint()
+            .as_bytes()
+            .chunks(4)
+            .map(|group| std::str::from_utf8(group).unwrap().to_uppercase())
+            .collect::<Vec<String>>()
+            .join(" ");
+        contacts
+            .verify("Alice", &grouped)
+            .expect("Unable to verify contact");
+        assert_eq!("Alice", contacts.label(&alice.address));
+        let stranger_identity = test_identity("dave");
+        let stranger = stranger_identity.address;
+        assert_eq!(stranger.to_string(), contacts.label(&stranger));
+
+        contacts.remove("Carol").expect("Unable to remove contact");
+        assert!(matches!(
+            contacts.remove("Carol"),
+            Err(ContactError::NotFound(_))
+        ));
+        contacts.save().expect("Unable to save contacts");
+
+        let reopened = keystore.contact_book().expect("Unable to open contacts");
+        assert_eq!(contacts.contacts(), reopened.contacts());
+        assert_eq!(Trust::Verified, reopened.get("Alice").unwrap().trust);
+        assert_eq!(Trust::Learned, reopened.get("Bob").unwrap().trust);
+
+        // Cards saved one per file by older versions are moved in once.
+        let legacy = directory.join("contacts");
+        std::fs::create_dir_all(&legacy).expect("Unable to create directory");
+        for (name, identity) in [("Carol", &carol), ("Alice", &stranger_identity)] {
+            let card = ContactCard::new(name, &identity.public_key);
+            std::fs::write(
+                legacy.join(format!("{}.json", card.address)),
+                card.to_json(),
+            )
+            .expect("Unable to write card");
+        }
+        let migrated = keystore.contact_book().expect("Unable to open contacts");
+        assert_eq!(4, migrated.len());
+        assert_eq!(Trust::Learned, migrated.get("Carol").unwrap().trust);
+        let renamed = stranger_identity.address.to_string();
+        assert_eq!(
+            Some(&renamed),
+            migrated
+                .find_address(&stranger_identity.address)
+                .map(|c| &c.name)
+        );
+        assert!(!legacy.exists());
+        assert_eq!(
+            4,
+            keystore
+                .contact_book()
+                .expect("Unable to open contacts")
+                .len()
+        );
+
+        // Only nicknames of people messages were exchanged with are learned.
+        let mut chain = Chain::open(
+            &directory.join("chain"),
+            Arc::new(Mutex::new(HashMap::new())),
+        )
+        .expect("Unable to open the chain");
+        let (erin, frank) = (test_identity("erin"), test_identity("frank"));
+        for identity in [&erin, &frank] {
+            let registration = Registration::new(identity, Some(&identity.name), 0);
+            chain
+                .submit_record(KeyRecord::Registration(registration))
+                .expect("Unable to register");
+        }
+        let mut message = Message::new(&alice.public_key, &erin, Payload::from("hi"));
+        message
+            .encrypt(&alice.public_key)
+            .expect("Unable to encrypt message");
+        chain.submit_message(message).expect("Unable to submit");
+        chain.mine_pending(&erin).expect("Unable to mine");
+        let mut learning =
+            ContactBook::open(&directory.join("learning.json")).expect("Unable to open contacts");
+        assert_eq!(
+            1,
+            chain
+                .learn_contacts(&alice, &mut learning)
+                .expect("Unable to learn contacts")
+        );
+        assert!(learning.get("erin").is_some());
+        assert!(learning.get("frank").is_none());
+
+        // Only messages signed by the key they claim to be from are shown
+        // as from that sender.
+        let mut spoofed = Message::new(&alice.public_key, &frank, Payload::from("it's erin"));
+        spoofed.from = erin.encoded_public_key();
+        spoofed
+            .encrypt(&alice.public_key)
+            .expect("Unable to encrypt message");
+        chain.submit_message(spoofed).expect("Unable to submit");
+        chain.mine_pending(&frank).expect("Unable to mine");
+        let mut inbox = Inbox::new(&alice);
+        chain.scan_inbox(&mut inbox).expect("Unable to scan");
+        let verified: Vec<(String, bool)> = inbox
+            .all_messages()
+            .iter()
+            .map(|entry| (entry.payload.summary(), entry.verified))
+            .collect();
+        assert_eq!(
+            vec![
+                (String::from("hi"), true),
+                (String::from("it's erin"), false)
+            ],
+            verified
+        );
+
+        std::fs::remove_dir_all(&directory).expect("Unable to remove keystore");
+    }
+
+    #[tokio::test]
+    async fn handshakes_check_version_genesis_and_node_key() {
+        let alice = test_identity("alice");
+        let bob = test_identity("bob");
+        let status = ChainStatus {
+            genesis_hash: Some(String::from("genesis")),
+            best_height: 7,
+        };
+        let capabilities = Capabilities::MINING | Capabilities::ARCHIVE;
+        let (alice_key, bob_key) = (NoiseKey::generate(), NoiseKey::generate());
+        let connect = |local: Hello, remote: Hello| {
+            let (alice, bob) = (alice.clone(), bob.clone());
+            let (alice_key, bob_key) = (alice_key.clone(), bob_key.clone());
+            async move {
+                let (near, far) = tokio::io::duplex(1024);
+                let (near, far) = tokio::join!(
+                    handshake(near, true, &alice_key, &local, &alice, HANDSHAKE_TIMEOUT),
+                    handshake(far, false, &bob_key, &remote, &bob, HANDSHAKE_TIMEOUT)
+                );
+                (near.map(|(_, peer)| peer), far.map(|(_, peer)| peer))
+            }
+        };
+
+        // Chains started on their own agree on the genesis they advertise.
+        let directory = std::env::temp_dir().join(format!("biddy-genesis-{}", std::process::id()));
+        let open = |name: &str| {
+            Chain::open(&directory.join(name), Arc::new(Mutex::new(HashMap::new())))
+                .expect("Unable to open the chain")
+        };
+        let (first, second) = (open("first"), open("second"));
+        let genesis = first.status().lock().unwrap().clone();
+        assert_eq!(Some(Block::genesis().hash), genesis.genesis_hash);
+        assert_eq!(genesis, *second.status().lock().unwrap());
+        std::fs::remove_dir_all(&directory).expect("Unable to remove chains");
+
+        let local = Hello::new(&alice, &status, 8675, capabilities);
+        let remote = Hello::new(&bob, &ChainStatus::default(), 9000, Capabilities::RELAY);
+        let (near, far) = connect(local.clone(), remote.clone()).await;
+        let (near, far) = (
+            near.expect("Handshake failed"),
+            far.expect("Handshake failed"),
+        );
+        assert_eq!(bob.public_key, near.public_key);
+        assert_eq!(bob.address, near.address);
+        assert_eq!(remote, near.hello);
+        assert_eq!(PROTOCOL_VERSION, near.version);
+        assert_eq!(alice.address, far.address);
+        assert_eq!(7, far.hello.best_height);
+        assert_eq!(8675, far.hello.listen_port);
+        assert!(far.hello.capabilities.contains(Capabilities::MINING));
+        assert!(!far.hello.capabilities.contains(Capabilities::RELAY));
+
+        let forked = ChainStatus {
+            genesis_hash: Some(String::from("another genesis")),
+            best_height: 3,
+        };
+        let (near, far) =
+            connect(local.clone(), Hello::new(&bob, &forked, 9000, capabilities)).await;
+        assert!(matches!(near, Err(HandshakeError::GenesisMismatch(_))));
+        assert!(matches!(far, Err(HandshakeError::GenesisMismatch(_))));
+
+        // A connection back to this node is spotted by its node key, even
+        // though each hello has its own nonce.
+        let (near, far) = connect(
+            local.clone(),
+            Hello::new(&alice, &status, 9000, capabilities),
+        )
+        .await;
+        assert!(matches!(near, Err(HandshakeError::SelfConnection)));
+        assert!(matches!(far, Err(HandshakeError::SelfConnection)));
+
+        let mut outdated = Hello::new(&bob, &status, 9000, capabilities);
+        outdated.version = MIN_PROTOCOL_VERSION - 1;
+        let (near, far) = connect(local.clone(), outdated).await;
+        assert!(matches!(near, Err(HandshakeError::IncompatibleVersion(0))));
+        assert!(matches!(far, Err(HandshakeError::Rejected(_))));
+
+        let mut impostor = Hello::new(&bob, &status, 9000, capabilities);
+        impostor.node_key = test_identity("carol").encoded_public_key();
+        let (near, _) = connect(local.clone(), impostor).await;
+        assert!(matches!(near, Err(HandshakeError::InvalidSignature)));
+
+        let (near, _silent) = tokio::io::duplex(1024);
+        let timeout = Duration::from_millis(50);
+        assert!(matches!(
+            handshake(near, true, &alice_key, &local, &alice, timeout).await,
+            Err(HandshakeError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn peer_traffic_is_encrypted() {
+        let (alice_key, bob_key) = (NoiseKey::generate(), NoiseKey::generate());
+        let (near, relay_near) = tokio::io::duplex(1024);
+        let (relay_far, far) = tokio::io::duplex(1024);
+
+        // Forwards bytes from `near` to `far` unchanged, keeping a copy of
+        // everything seen on the wire.
+        let relay = tokio::spawn(async move {
+            let (mut from_near, mut to_near) = tokio::io::split(relay_near);
+            let (mut from_far, mut to_far) = tokio::io::split(relay_far);
+            let back = tokio::spawn(async move {
+                tokio::io::copy(&mut from_far, &mut to_near).await.ok();
+            });
+            let mut wire = Vec::new();
+            let mut buffer = [0u8; 4096];
+            loop {
+                let read = from_near.read(&mut buffer).await.unwrap_or(0);
+                if read == 0 {
+                    break;
+                }
+                wire.extend_from_slice(&buffer[..read]);
+                to_far
+                    .write_all(&buffer[..read])
+                    .await
+                    .expect("Relay failed");
+            }
+            to_far.shutdown().await.ok();
+            back.abort();
+            wire
+        });
+
+        let (near, far) = tokio::join!(
+            transport::establish(near, &alice_key, true),
+            transport::establish(far, &bob_key, false)
+        );
+        let (mut near, mut far) = (
+            near.expect("Handshake failed"),
+            far.expect("Handshake failed"),
+        );
+        assert_eq!(near.handshake_hash(), far.handshake_hash());
+        assert_eq!(Some(bob_key.public_key()), near.remote_static());
+        assert_eq!(Some(alice_key.public_key()), far.remote_static());
+
+        let secret = b"meet me at the usual place ".repeat(5000);
+        let sent = secret.clone();
+        let writer = tokio::spawn(async move {
+            near.write_all(&sent).await.expect("Unable to write");
+            near.shutdown().await.expect("Unable to close");
+        });
+        let mut received = Vec::new();
+        far.read_to_end(&mut received)
+            .await
+            .expect("Unable to read");
+        writer.await.expect("Writer failed");
+        assert_eq!(secret, received);
+
+        let wire = relay.await.expect("Relay failed");
+        assert!(wire.len() > secret.len());
+        assert!(!wire.windows(12).any(|window| window == b"meet me at t"));
+    }
+
+    #[tokio::test]
+    async fn connections_queue_data_with_backpressure() {
+        let (near, far) = connected_networks().await;
+
+        for i in 0..QUEUE_CAPACITY {
+            near.try_send_data(vec![i as u8])
+                .expect("Unable to queue data");
+        }
+        assert_eq!(Err(NetworkError::Full), near.try_send_data(vec![0]));
+        assert_eq!(None, far.get_next_data());
+
+        let (mut near_runner, mut far_runner) = (near.clone(), far.clone());
+        let near_task = tokio::spawn(async move { near_runner.run().await });
+        let far_task = tokio::spawn(async move { far_runner.run().await });
+
+        let mut received = Vec::new();
+        while received.len() < QUEUE_CAPACITY + 1 {
+            if received.len() == 1 {
+                near.send_data(vec![0xff; 3]).await.expect("Unable to send");
+            }
+            received.extend(far.next_data().await.expect("The connection closed"));
+        }
+        let mut expected: Vec<u8> = (0..QUEUE_CAPACITY as u8).collect();
+        expected.extend([0xff; 3]);
+        assert_eq!(expected, received[..expected.len()]);
+
+        // Dropping every handle on the send queue closes the connection.
+        near_task.abort();
+        drop(near);
+        assert_eq!(None, far.next_data().await);
+        far_task.await.expect("Connection task failed");
+        assert_eq!(Err(NetworkError::Closed), far.send_data(vec![1]).await);
+    }
+
+    #[tokio::test]
+    async fn connections_close_with_a_reason() {
+        let shutdown = CancellationToken::new();
+        let run = |network: &Network| {
+            let mut runner = network.clone();
+            tokio::spawn(async move { runner.run().await })
+        };
+
+        let (near, far) = connected_networks().await;
+        let (near_task, far_task) = (run(&near), run(&far));
+        near.send_data(b"last words".to_vec())
+            .await
+            .expect("Unable to send");
+        let reason = DisconnectReason::Other(String::from("moving house"));
+        near.disconnect(reason.clone());
+        assert_eq!(Some(b"last words".to_vec()), far.next_data().await);
+        assert_eq!(None, far.next_data().await);
+        near_task.await.expect("Connection task failed");
+        far_task.await.expect("Connection task failed");
+        assert_eq!(
+            Some(CloseReason::Local(reason.clone())),
+            near.close_reason()
+        );
+        assert_eq!(Some(CloseReason::Remote(reason)), far.close_reason());
+        assert_eq!(
+            Err(NetworkError::Closed),
+            near.send_data(b"too late".to_vec()).await
+        );
+
+        let (near, far) = connected_networks().await;
+        let near = near.with_shutdown(&shutdown);
+        let (near_task, far_task) = (run(&near), run(&far));
+        assert_eq!(None, near.close_reason());
+        shutdown.cancel();
+        assert_eq!(None, far.next_data().await);
+        near_task.await.expect("Connection task failed");
+        far_task.await.expect("Connection task failed");
+        assert_eq!(
+            Some(CloseReason::Remote(DisconnectReason::Shutdown)),
+            far.close_reason()
+        );
+    }
+
+    #[tokio::test]
+    async fn misbehaving_peers_are_scored_and_disconnected() {
+        let run = |network: &Network| {
+            let mut runner = network.clone();
+            tokio::spawn(async move { runner.run().await })
+        };
+
+        // A frame that is not a `PeerMessage` is skipped, but counts.
+        let (near, mut far, address) = connected_streams().await;
+        let near = Network::new(Some(near), address);
+        let near_task = run(&near);
+        far.write_u32(3).await.expect("Unable to write");
+        far.write_all(&[0xff; 3]).await.expect("Unable to write");
+        protocol::write_frame(&mut far, &PeerMessage::Data(b"sorry".to_vec()))
+            .await
+            .expect("Unable to write");
+        assert_eq!(Some(b"sorry".to_vec()), near.next_data().await);
+        assert_eq!(
+            Misbehavior::MalformedMessage.score(),
+            near.misbehavior_score()
+        );
+        assert_eq!(None, near.close_reason());
+
+        // Reports add up until the peer is disconnected.
+        let mut reports = 1;
+        while !near.report(Misbehavior::MalformedMessage) {
+            reports += 1;
+        }
+        // The malformed frame counted as one of them.
+        assert_eq!(
+            BAN_THRESHOLD / Misbehavior::MalformedMessage.score(),
+            reports + 1
+        );
+        let goodbye: PeerMessage = protocol::read_frame(&mut far, MAX_FRAME_SIZE)
+            .await
+            .expect("No disconnect was sent");
+        let reason = DisconnectReason::ProtocolError(Misbehavior::MalformedMessage.to_string());
+        assert_eq!(PeerMessage::Disconnect(reason.clone()), goodbye);
+        near_task.await.expect("Connection task failed");
+        assert_eq!(Some(CloseReason::Local(reason)), near.close_reason());
+        assert!(near.misbehavior_score() >= BAN_THRESHOLD);
+
+        // An oversized frame cannot be skipped, so the peer is dropped.
+        let (near, mut far, address) = connected_streams().await;
+        let near = Network::new(Some(near), address);
+        let near_task = run(&near);
+        far.write_u32(MAX_FRAME_SIZE as u32 + 1)
+            .await
+            .expect("Unable to write");
+        near_task.await.expect("Connection task failed");
+        assert_eq!(
+            Misbehavior::OversizedFrame.score(),
+            near.misbehavior_score()
+        );
+        assert!(matches!(
+            near.close_reason(),
+            Some(CloseReason::Local(DisconnectReason::ProtocolError(_)))
+        ));
+    }
+
+    #[test]
+    fn relayed_blocks_with_bad_hashes_are_reported() {
+        let directory =
+            std::env::temp_dir().join(format!("biddy-bad-blocks-{}", std::process::id()));
+        let peer: SocketAddr = "192.0.2.1:8675".parse().unwrap();
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let network = Network::new(None, peer);
+        peers
+            .lock()
+            .unwrap()
+            .insert(peer, Box::new(network.clone()));
+        let mut chain = Chain::open(&directory, peers).expect("Unable to open the chain");
+        let (relayed, received) = mpsc::channel();
+        let relay = Arc::new(Mutex::new(Relay::new(REQUEST_TIMEOUT)));
+        chain.connect_relay(relay, received);
+
+        let identity = test_identity("miner");
+        let genesis = Block::genesis();
+        let mut mined = Block::new(
+            Vec::new(),
+            &identity.public_key,
+            Some(genesis.hash.clone()),
+            1,
+        );
+        mined.finalize();
+        let mut short = mined.clone();
+        short.hash = String::from("42");
+        let mut forged = mined.clone();
+        forged.hash = format!("{}{}", &mined.hash[..4], "0".repeat(60));
+        let mut unlinked = mined.clone();
+        unlinked.previous_hash = Some(String::from("4"));
+
+        // Each is refused and reported instead of panicking the chain.
+        let mut score = 0;
+        for block in [short, forged, unlinked] {
+            relayed
+                .send((Object::Block(block), peer))
+                .expect("Unable to relay");
+            chain.receive_relayed();
+            score += Misbehavior::InvalidBlock.score();
+            assert_eq!(score, network.misbehavior_score());
+        }
+        assert_eq!(Some(genesis.hash.as_str()), chain.latest_hash());
+
+        // Blocks pruning messages that only expired on the peer's clock are
+        // refused without blaming the peer.
+        let mut early = Message::new(&identity.public_key, &identity, Payload::from("soon"))
+            .with_expiry(Expiry::Time(current_time() + 600));
+        early
+            .encrypt(&identity.public_key)
+            .expect("Unable to encrypt message");
+        early.prune();
+        let mut skewed = Block::new(
+            vec![early],
+            &identity.public_key,
+            Some(genesis.hash.clone()),
+            1,
+        );
+        skewed.finalize();
+        relayed
+            .send((Object::Block(skewed), peer))
+            .expect("Unable to relay");
+        chain.receive_relayed();
+        assert_eq!(Some(genesis.hash.as_str()), chain.latest_hash());
+        assert_eq!(score, network.misbehavior_score());
+
+        relayed
+            .send((Object::Block(mined.clone()), peer))
+            .expect("Unable to relay");
+        chain.receive_relayed();
+        assert_eq!(Some(mined.hash.as_str()), chain.latest_hash());
+        assert_eq!(score, network.misbehavior_score());
+
+        // A block mined elsewhere on the tip moves it and takes what it
+        // holds out of the mempool.
+        let mut pending = Message::new(&identity.public_key, &identity, Payload::from("mined"));
+        pending
+            .encrypt(&identity.public_key)
+            .expect("Unable to encrypt message");
+        chain
+            .submit_message(pending.clone())
+            .expect("Unable to submit message");
+        assert_eq!(1, chain.pending_messages());
+        let mut next = Block::new(
+            vec![pending],
+            &identity.public_key,
+            Some(mined.hash.clone()),
+            2,
+        );
+        next.finalize();
+        relayed
+            .send((Object::Block(next.clone()), peer))
+            .expect("Unable to relay");
+        chain.receive_relayed();
+        assert_eq!(Some(next.hash.as_str()), chain.latest_hash());
+        assert_eq!(0, chain.pending_messages());
+
+        // A valid block on another branch is kept without becoming the tip.
+        let mut sibling = Block::new(
+            Vec::new(),
+            &test_identity("other").public_key,
+            Some(genesis.hash.clone()),
+            1,
+        );
+        sibling.finalize();
+        relayed
+            .send((Object::Block(sibling), peer))
+            .expect("Unable to relay");
+        chain.receive_relayed();
+        assert_eq!(Some(next.hash.as_str()), chain.latest_hash());
+        assert_eq!(score, network.misbehavior_score());
+
+        std::fs::remove_dir_all(&directory).expect("Unable to remove chain");
+    }
+
+    #[test]
+    fn bans_expire_and_persist() {
+        let directory = std::env::temp_dir().join(format!("biddy-bans-{}", std::process::id()));
+        let keystore = Keystore::open(&directory).expect("Unable to open keystore");
+        let path = keystore.ban_list_path();
+        let mut bans = BanList::open(&path).expect("Unable to open bans");
+        let (spammer, lurker): (IpAddr, IpAddr) = (
+            "203.0.113.7".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        );
+        let now = 1_000_000;
+
+        bans.ban(spammer, Duration::from_secs(60), "spam", now);
+        bans.ban(lurker, Duration::from_secs(3600), "lurking", now);
+        assert!(bans.is_banned(spammer, now + 59));
+        assert!(!bans.is_banned(spammer, now + 60));
+        assert!(!bans.is_banned("203.0.113.8".parse().unwrap(), now));
+
+        // A shorter ban does not cut a longer one short.
+        bans.ban(lurker, Duration::from_secs(10), "again", now);
+        assert!(bans.is_banned(lurker, now + 3599));
+        assert_eq!("lurking", bans.bans(now).last().unwrap().reason);
+
+        bans.save(now + 60).expect("Unable to save bans");
+        let mut reopened = BanList::open(&path).expect("Unable to open bans");
+        assert_eq!(
+            vec![lurker],
+            reopened.bans(now).map(|ban| ban.ip).collect::<Vec<_>>()
+        );
+        reopened.unban(lurker).expect("Unable to unban");
+        assert!(matches!(
+            reopened.unban(lurker),
+            Err(BanError::NotBanned(_))
+        ));
+        assert!(!reopened.is_banned(lurker, now));
+
+        std::fs::write(&path, "not json").expect("Unable to write bans");
+        assert!(matches!(BanList::open(&path), Err(BanError::Malformed(_))));
+
+        std::fs::remove_dir_all(&directory).expect("Unable to remove keystore");
+    }
+
+    #[tokio::test]
+    async fn peers_are_throttled_and_connections_capped() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(100, start);
+        assert_eq!(Duration::ZERO, limiter.take(100, start));
+        assert_eq!(Duration::from_millis(500), limiter.take(50, start));
+        assert_eq!(
+            Duration::ZERO,
+            limiter.take(0, start + Duration::from_secs(1))
+        );
+        assert_eq!(
+            Duration::ZERO,
+            limiter.take(100, start + Duration::from_secs(10))
+        );
+
+        let slots = ConnectionSlots::new(&Limits {
+            max_inbound: 3,
+            max_per_ip: 2,
+            ..Limits::default()
+        });
+        let (home, away): (IpAddr, IpAddr) =
+            ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let first = slots.acquire(home).expect("No slot for the first peer");
+        let second = slots.acquire(home).expect("No slot for the second peer");
+        assert_eq!(
+            Err(LimitError::TooManyFromAddress(home)),
+            slots.acquire(home).map(|_| ())
+        );
+        let third = slots.acquire(away).expect("No slot for another address");
+        assert_eq!(
+            Err(LimitError::TooManyConnections),
+            slots.acquire(away).map(|_| ())
+        );
+        drop(first);
+        assert!(slots.acquire(home).is_ok());
+        drop((second, third));
+
+        let run = |network: &Network| {
+            let mut runner = network.clone();
+            tokio::spawn(async move { runner.run().await })
+        };
+
+        // Messages over the rate are read late rather than dropped.
+        let (near, far) = connected_networks().await;
+        let far = far.with_limits(Limits {
+            messages_per_second: 10,
+            ..Limits::default()
+        });
+        let (near_task, far_task) = (run(&near), run(&far));
+        let start = Instant::now();
+        for i in 0..15u8 {
+            near.send_data(vec![i]).await.expect("Unable to send");
+        }
+        for i in 0..15u8 {
+            assert_eq!(Some(vec![i]), far.next_data().await);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        drop((near, near_task, far, far_task));
+
+        // Pings count against the same rate.
+        let (near, mut far, address) = connected_streams().await;
+        let near = Network::new(Some(near), address).with_limits(Limits {
+            messages_per_second: 10,
+            ..Limits::default()
+        });
+        let near_task = run(&near);
+        let start = Instant::now();
+        for nonce in 0..15 {
+            protocol::write_frame(&mut far, &PeerMessage::Ping(nonce))
+                .await
+                .expect("Unable to write");
+        }
+        protocol::write_frame(&mut far, &PeerMessage::Data(b"late".to_vec()))
+            .await
+            .expect("Unable to write");
+        assert_eq!(Some(b"late".to_vec()), near.next_data().await);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        drop((near, near_task, far));
+
+        // The socket is not read while the queued bytes are at the limit.
+        let (near, far) = connected_networks().await;
+        let far = far.with_limits(Limits {
+            max_queued_bytes: 100,
+            ..Limits::default()
+        });
+        let (_near_task, _far_task) = (run(&near), run(&far));
+        for i in 0..3u8 {
+            near.send_data(vec![i; 60]).await.expect("Unable to send");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(Some(vec![0; 60]), far.get_next_data());
+        assert_eq!(None, far.get_next_data());
+        assert_eq!(Some(vec![1; 60]), far.next_data().await);
+        assert_eq!(Some(vec![2; 60]), far.next_data().await);
 
-        message.decrypt(&private_key);
+        // Queue limits too large to count are refused or capped.
+        let args =
+            |value: &str| vec![String::from("--max-queued-bytes"), String::from(value)].into_iter();
+        assert!(cli::parse(args(&MAX_QUEUED_BYTES.to_string())).is_ok());
+        assert!(cli::parse(args(&usize::MAX.to_string())).is_err());
+        let (near, far) = connected_networks().await;
+        let far = far.with_limits(Limits {
+            max_queued_bytes: usize::MAX,
+            ..Limits::default()
+        });
+        let (_near_task, _far_task) = (run(&near), run(&far));
+        near.send_data(vec![7; 60]).await.expect("Unable to send");
+        assert_eq!(Some(vec![7; 60]), far.next_data().await);
+    }
+
+    #[tokio::test]
+    async fn keepalives_track_latency_and_drop_silent_peers() {
+        let keepalive = Keepalive {
+            interval: Duration::from_millis(50),
+            max_missed: 2,
+        };
+        let run = |network: &Network| {
+            let mut runner = network.clone();
+            tokio::spawn(async move { runner.run().await })
+        };
+
+        let (near, far) = connected_networks().await;
+        let near = near.with_keepalive(keepalive);
+        let (_near_task, _far_task) = (run(&near), run(&far));
+        assert_eq!(None, near.stats().latency);
+        near.send_data(b"hello".to_vec())
+            .await
+            .expect("Unable to send");
+        assert_eq!(Some(b"hello".to_vec()), far.next_data().await);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while near.stats().latency.is_none() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let stats = near.stats();
+        assert!(stats.latency.is_some());
+        assert!(stats.bytes_sent > 5);
+        assert!(stats.bytes_received > 0);
+        assert!(stats.connected_for >= Duration::from_millis(50));
+        assert!(far.stats().bytes_received >= 5);
+        assert_eq!(None, near.close_reason());
+
+        // Pongs stuck behind data this node has not taken yet are not
+        // missed pings.
+        let (near, far) = connected_networks().await;
+        let near = near.with_keepalive(keepalive).with_limits(Limits {
+            max_queued_bytes: 100,
+            ..Limits::default()
+        });
+        let (_near_task, _far_task) = (run(&near), run(&far));
+        for i in 0..3u8 {
+            far.send_data(vec![i; 60]).await.expect("Unable to send");
+        }
+        tokio::time::sleep(keepalive.interval * 6).await;
+        assert_eq!(None, near.close_reason());
+        for i in 0..3u8 {
+            assert_eq!(Some(vec![i; 60]), near.next_data().await);
+        }
+
+        // A peer that never answers is dropped after `max_missed` pings.
+        let (near, _far, address) = connected_streams().await;
+        let near = Network::new(Some(near), address).with_keepalive(keepalive);
+        tokio::time::timeout(Duration::from_secs(5), run(&near))
+            .await
+            .expect("The silent peer was not dropped")
+            .expect("Connection task failed");
+        assert_eq!(Some(CloseReason::Unresponsive), near.close_reason());
+    }
+
+    #[test]
+    fn relay_announces_once_and_re_requests_on_timeout() {
+        let identity = test_identity("miner");
+        let mut block = Block::new(Vec::new(), &identity.public_key, None, 0);
+        block.finalize();
+        let message = Message::new(&identity.public_key, &identity, Payload::from("hi"));
+        let (block, message) = (Object::Block(block), Object::Message(message));
+        let (alice, bob, carol): (SocketAddr, SocketAddr, SocketAddr) = (
+            "192.0.2.1:8675".parse().unwrap(),
+            "192.0.2.2:8675".parse().unwrap(),
+            "192.0.2.3:8675".parse().unwrap(),
+        );
+        let items = |outgoing: &[(SocketAddr, RelayMessage)]| {
+            let mut items: Vec<(SocketAddr, &'static str, Vec<InventoryItem>)> = outgoing
+                .iter()
+                .map(|(peer, message)| match message {
+                    RelayMessage::Inventory(items) => (*peer, "inv", items.clone()),
+                    RelayMessage::GetData(items) => (*peer, "get", items.clone()),
+                    RelayMessage::NotFound(items) => (*peer, "notfound", items.clone()),
+                    RelayMessage::Object(object) => (*peer, "object", vec![object.item()]),
+                })
+                .collect();
+            items.sort_by_key(|(peer, _, _)| *peer);
+            items
+        };
+        let replies = |handled: Result<Handled, Misbehavior>| match handled {
+            Ok(handled) => handled.replies,
+            Err(e) => panic!("Unexpected misbehavior: {}", e),
+        };
+
+        let start = Instant::now();
+        let mut relay = Relay::new(REQUEST_TIMEOUT);
+        for peer in [alice, bob, carol] {
+            relay.add_peer(peer);
+        }
+
+        // Our own objects are announced to every peer, once.
+        let announced = relay.publish(block.clone());
+        assert_eq!(
+            vec![
+                (alice, "inv", vec![block.item()]),
+                (bob, "inv", vec![block.item()]),
+                (carol, "inv", vec![block.item()]),
+            ],
+            items(&announced)
+        );
+        assert!(relay.publish(block.clone()).is_empty());
+        let served = replies(relay.handle(
+            bob,
+            RelayMessage::GetData(vec![block.item(), message.item()]),
+            start,
+        ));
+        assert_eq!(
+            vec![
+                (bob, "object", vec![block.item()]),
+                (bob, "notfound", vec![message.item()]),
+            ],
+            items(&served)
+        );
+
+        // Announced objects are fetched from one peer at a time.
+        let inventory = RelayMessage::Inventory(vec![message.item()]);
+        let requested = replies(relay.handle(alice, inventory.clone(), start));
+        assert_eq!(
+            vec![(alice, "get", vec![message.item()])],
+            items(&requested)
+        );
+        assert!(replies(relay.handle(bob, inventory, start)).is_empty());
+        assert!(matches!(
+            relay.handle(
+                carol,
+                RelayMessage::Object(Box::new(message.clone())),
+                start
+            ),
+            Err(Misbehavior::UnrequestedData)
+        ));
+
+        // Alice does not answer, so Bob is asked.
+        assert!(relay.expire(start + REQUEST_TIMEOUT / 2).is_empty());
+        let later = start + REQUEST_TIMEOUT;
+        let retried = relay.expire(later);
+        assert_eq!(vec![(bob, "get", vec![message.item()])], items(&retried));
+        assert!(matches!(
+            relay.handle(
+                alice,
+                RelayMessage::Object(Box::new(message.clone())),
+                later
+            ),
+            Err(Misbehavior::UnrequestedData)
+        ));
+        let handled = relay
+            .handle(bob, RelayMessage::Object(Box::new(message.clone())), later)
+            .expect("The object was requested");
+        assert!(handled.replies.is_empty());
+        assert!(handled.received.map(|object| object.item()) == Some(message.item()));
+
+        // Only Carol has not seen it yet.
+        let announced = relay.publish(message.clone());
+        assert_eq!(
+            vec![(carol, "inv", vec![message.item()])],
+            items(&announced)
+        );
+        let relayed = RelayMessage::decode(&announced[0].1.encode()).expect("Unable to decode");
+        assert!(matches!(relayed, RelayMessage::Inventory(items) if items == vec![message.item()]));
+        assert!(RelayMessage::decode(b"garbage").is_none());
+
+        // Requests waiting on a peer that leaves go to the next one.
+        let other = InventoryItem::Block(String::from("beef"));
+        let inventory = RelayMessage::Inventory(vec![other.clone()]);
+        replies(relay.handle(carol, inventory.clone(), later));
+        replies(relay.handle(alice, inventory, later));
+        let moved = relay.remove_peer(carol, later);
+        assert_eq!(vec![(alice, "get", vec![other.clone()])], items(&moved));
+        let given_up = replies(relay.handle(alice, RelayMessage::NotFound(vec![other]), later));
+        assert!(given_up.is_empty());
+
+        // Items nobody announced, such as missing chunks, are asked of each
+        // peer in turn.
+        let chunk = InventoryItem::Message(String::from("chunk"));
+        let asked = items(&relay.request(vec![chunk.clone()], later));
+        assert_eq!(1, asked.len());
+        let (first, kind, wanted) = asked[0].clone();
+        assert_eq!(("get", vec![chunk.clone()]), (kind, wanted));
+        let next = if first == alice { bob } else { alice };
+        assert!(relay.request(vec![chunk.clone()], later).is_empty());
+        let asked =
+            replies(relay.handle(first, RelayMessage::NotFound(vec![chunk.clone()]), later));
+        assert_eq!(vec![(next, "get", vec![chunk.clone()])], items(&asked));
+        assert!(replies(relay.handle(next, RelayMessage::NotFound(vec![chunk]), later)).is_empty());
+
+        // Objects must hash to the item they were sent for.
+        let mut forged = Block::new(Vec::new(), &identity.public_key, None, 1);
+        forged.finalize();
+        let wanted = InventoryItem::Block(forged.hash.clone());
+        forged.node_id = 2;
+        replies(relay.handle(alice, RelayMessage::Inventory(vec![wanted]), later));
+        assert!(matches!(
+            relay.handle(
+                alice,
+                RelayMessage::Object(Box::new(Object::Block(forged))),
+                later
+            ),
+            Err(Misbehavior::InvalidBlock)
+        ));
+        let original = Message::new(&identity.public_key, &identity, Payload::from("bye"));
+        let mut rewritten = original.clone();
+        rewritten.to = identity.encoded_public_key() + "x";
+        let mut pruned = original.clone();
+        pruned.prune();
+        let wanted = Object::Message(original).item();
+        replies(relay.handle(alice, RelayMessage::Inventory(vec![wanted]), later));
+        for message in [rewritten, pruned] {
+            assert!(matches!(
+                relay.handle(
+                    alice,
+                    RelayMessage::Object(Box::new(Object::Message(message))),
+                    later
+                ),
+                Err(Misbehavior::UnrequestedData)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn networks_run_over_memory_transports() {
+        let (alice, bob) = (test_identity("alice"), test_identity("bob"));
+        let (alice_address, bob_address): (SocketAddr, SocketAddr) = (
+            "10.0.0.1:8675".parse().unwrap(),
+            "10.0.0.2:8675".parse().unwrap(),
+        );
+        let (near, far) = MemoryTransport::pair(alice_address, bob_address);
+        assert_eq!(bob_address, near.peer_address().expect("No peer address"));
+        assert_eq!(alice_address, far.peer_address().expect("No peer address"));
+
+        let status = ChainStatus::default();
+        let (alice_hello, bob_hello) = (
+            Hello::new(&alice, &status, 8675, Capabilities::RELAY),
+            Hello::new(&bob, &status, 8675, Capabilities::RELAY),
+        );
+        let (alice_key, bob_key) = (NoiseKey::generate(), NoiseKey::generate());
+        let (near, far): (Box<dyn Transport>, Box<dyn Transport>) = (Box::new(near), Box::new(far));
+        let (near, far) = tokio::join!(
+            handshake(
+                near,
+                true,
+                &alice_key,
+                &alice_hello,
+                &alice,
+                HANDSHAKE_TIMEOUT
+            ),
+            handshake(far, false, &bob_key, &bob_hello, &bob, HANDSHAKE_TIMEOUT)
+        );
+        let (near, bob_info) = near.expect("Handshake failed");
+        let (far, alice_info) = far.expect("Handshake failed");
+        let near = Network::over(near)
+            .expect("No peer address")
+            .with_peer(bob_info);
+        let far = Network::over(far)
+            .expect("No peer address")
+            .with_peer(alice_info);
+        assert_eq!(bob_address, near.remote_address);
+        assert_eq!(alice_address, far.remote_address);
+        assert_eq!(Some(&bob.public_key), near.remote_public_key());
+
+        let run = |network: &Network| {
+            let mut runner = network.clone();
+            tokio::spawn(async move { runner.run().await })
+        };
+        let (near_task, far_task) = (run(&near), run(&far));
+        for i in 0..100u8 {
+            near.send_data(vec![i; 1000]).await.expect("Unable to send");
+        }
+        far.send_data(b"thanks".to_vec())
+            .await
+            .expect("Unable to send");
+        for i in 0..100u8 {
+            assert_eq!(Some(vec![i; 1000]), far.next_data().await);
+        }
+        assert_eq!(Some(b"thanks".to_vec()), near.next_data().await);
+
+        near.disconnect(DisconnectReason::Shutdown);
+        near_task.await.expect("Connection task failed");
+        far_task.await.expect("Connection task failed");
+        assert_eq!(
+            Some(CloseReason::Remote(DisconnectReason::Shutdown)),
+            far.close_reason()
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_nodes_converge_and_deliver_messages() {
+        const TIMEOUT: Duration = Duration::from_secs(30);
+        let all = [0, 1, 2, 3];
+
+        for topology in [Topology::Line, Topology::Star, Topology::Full] {
+            let simulation = Simulation::start(4, topology).await;
+            let tip = simulation.mine(3).await;
+            assert!(
+                simulation
+                    .wait_until(TIMEOUT, |simulation| simulation.converged(&all))
+                    .await,
+                "{:?} did not converge",
+                topology
+            );
+            assert_eq!(Some(tip), simulation.tip(0));
+        }
+
+        let simulation = Simulation::start(4, Topology::Ring).await;
+        simulation.set_latency(1, 2, Duration::from_millis(100));
+        simulation.mine(0).await;
+        assert!(
+            simulation
+                .wait_until(TIMEOUT, |simulation| simulation.converged(&all))
+                .await
+        );
+
+        // Blocks mined on one side of a partition reach the other side once
+        // it heals.
+        simulation.partition(&[0, 1]);
+        let before = simulation.tip(2);
+        let tip = simulation.mine(1).await;
+        assert!(
+            simulation
+                .wait_until(TIMEOUT, |simulation| simulation.converged(&[0, 1]))
+                .await
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(before, simulation.tip(2));
+        assert_eq!(before, simulation.tip(3));
+        simulation.heal();
+        assert!(
+            simulation
+                .wait_until(TIMEOUT, |simulation| simulation.converged(&all))
+                .await
+        );
+        assert_eq!(Some(tip), simulation.tip(3));
+
+        // A message is relayed to another node, mined there and read by its
+        // recipient from its own chain.
+        let text = String::from("hello around the ring");
+        simulation.send(0, 2, &text);
+        assert!(
+            simulation
+                .wait_until(TIMEOUT, |simulation| simulation.pending_messages(3) == 1)
+                .await
+        );
+        simulation.mine(3).await;
+        assert!(
+            simulation
+                .wait_until(TIMEOUT, |simulation| simulation.inbox(2).contains(&text))
+                .await
+        );
+        assert!(
+            simulation
+                .wait_until(TIMEOUT, |simulation| simulation.converged(&all))
+                .await
+        );
+        assert!(!simulation.inbox(1).contains(&text));
+    }
+
+    /// Both ends of an encrypted loopback connection, not yet running.
+    async fn connected_networks() -> (Network, Network) {
+        let (near, far, address) = connected_streams().await;
+
+        (
+            Network::new(Some(near), address),
+            Network::new(Some(far), address),
+        )
+    }
+
+    /// Both ends of an encrypted loopback connection and the address
+    /// connected to.
+    async fn connected_streams() -> (PeerStream, PeerStream, SocketAddr) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Unable to listen");
+        let address = listener.local_addr().expect("Unable to read address");
+        let (dialed, accepted) = tokio::join!(tokio::net::TcpStream::connect(address), async {
+            listener.accept().await.map(|(socket, _)| socket)
+        });
+        let dialed: Box<dyn Transport> = Box::new(dialed.expect("Unable to connect"));
+        let accepted: Box<dyn Transport> = Box::new(accepted.expect("Unable to accept"));
+        let (near_key, far_key) = (NoiseKey::generate(), NoiseKey::generate());
+        let (near, far) = tokio::join!(
+            transport::establish(dialed, &near_key, true),
+            transport::establish(accepted, &far_key, false)
+        );
+
+        (
+            near.expect("Handshake failed"),
+            far.expect("Handshake failed"),
+            address,
+        )
+    }
 
-        assert_eq!(&test_text, &message.text);
+    fn document_pem(identity: &Identity) -> String {
+        formats::encrypt_private_key(&identity.private_key, TEST_PASSPHRASE)
+            .expect("Unable to encrypt key")
+            .to_pem()
+            .to_string()
     }
 }
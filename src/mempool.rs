@@ -0,0 +1,86 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::block::{BLOCK_OVERHEAD, MAX_BLOCK_SIZE};
+use crate::Message;
+
+/// Most messages held while waiting to be mined.
+const MAX_MEMPOOL_MESSAGES: usize = 10_000;
+
+#[derive(Debug)]
+pub enum MempoolError {
+    Expired,
+    NotEncrypted,
+    Full,
+    /// The message would not fit in a block on its own.
+    TooLarge,
+}
+
+/// Messages accepted for inclusion in a future block, oldest first.
+pub struct Mempool {
+    messages: VecDeque<Message>,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Mempool {
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Accepts `message` unless it was never encrypted, is too large for a
+    /// block, or would already have expired in the next block on a chain at
+    /// `height` at time `now`.
+    pub fn submit(&mut self, message: Message, height: u32, now: u64) -> Result<(), MempoolError> {
+        if message.payload.is_some() || message.signing_key.is_none() {
+            return Err(MempoolError::NotEncrypted);
+        }
+        let size = bincode::serialized_size(&message).unwrap_or(u64::MAX);
+        if size > (MAX_BLOCK_SIZE - BLOCK_OVERHEAD) as u64 {
+            return Err(MempoolError::TooLarge);
+        }
+        if message.is_expired(height.saturating_add(1), now) {
+            return Err(MempoolError::Expired);
+        }
+        if self.messages.len() >= MAX_MEMPOOL_MESSAGES {
+            return Err(MempoolError::Full);
+        }
+
+        self.messages.push_back(message);
+
+        Ok(())
+    }
+
+    /// Removes and returns every pending message, dropping any that would
+    /// have expired in the next block on a chain at `height`.
+    pub fn drain(&mut self, height: u32, now: u64) -> Vec<Message> {
+        self.messages
+            .drain(..)
+            .filter(|message| !message.is_expired(height.saturating_add(1), now))
+            .collect()
+    }
+
+    /// Drops pending messages that `included` already commits to, as when a
+    /// block mined elsewhere is added to the chain.
+    pub fn remove_included(&mut self, included: &[Message]) {
+        if included.is_empty() {
+            return;
+        }
+        let digests: HashSet<String> = included.iter().map(Message::digest).collect();
+        self.messages
+            .retain(|message| !digests.contains(&message.digest()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -13,9 +13,14 @@ use aes::{
     Aes256, BlockEncrypt,
 };
 use hex::{decode as hex_decode, encode as hex_encode};
-use rand::rngs::OsRng;
-use rsa::{pkcs1::ToRsaPublicKey, PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
+use rsa::{pkcs1::ToRsaPublicKey, RsaPublicKey};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::identity::Identity;
+use crate::keys::{CryptoError, PublicKey};
+use crate::payload::{Compression, MessageRef, Payload, PayloadError};
 
 pub trait RsaPublicHelpers {
     fn print_key(&self) -> String;
@@ -31,147 +36,364 @@ impl RsaPublicHelpers for RsaPublicKey {
     }
 }
 
+/// Parses a key in the encoded form used by `Message::to` and `Message::from`.
+pub fn decode_public_key(encoded: &str) -> Option<PublicKey> {
+    PublicKey::decode(encoded)
+}
+
 type U16 = UInt<UInt<UInt<UInt<UInt<UTerm, B1>, B0>, B0>, B0>, B0>;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+pub enum MessageError {
+    MissingPayload,
+    MissingKey,
+    InvalidCiphertext,
+    Payload(PayloadError),
+    Rsa(rsa::errors::Error),
+}
+
+impl From<PayloadError> for MessageError {
+    fn from(e: PayloadError) -> Self {
+        MessageError::Payload(e)
+    }
+}
+
+impl From<rsa::errors::Error> for MessageError {
+    fn from(e: rsa::errors::Error) -> Self {
+        MessageError::Rsa(e)
+    }
+}
+
+impl From<CryptoError> for MessageError {
+    fn from(e: CryptoError) -> Self {
+        match e {
+            CryptoError::Rsa(e) => MessageError::Rsa(e),
+            CryptoError::InvalidWrappedKey => MessageError::InvalidCiphertext,
+        }
+    }
+}
+
+/// Seconds since the unix epoch, as used by `Expiry::Time`.
+pub fn current_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The point after which a message should no longer be relayed, shown or
+/// stored in full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    /// Expires once the chain has grown past this block id.
+    Height(u32),
+    /// Expires at this unix timestamp, in seconds.
+    Time(u64),
+}
+
+impl Expiry {
+    pub fn is_expired(&self, height: u32, now: u64) -> bool {
+        match self {
+            Expiry::Height(h) => height > *h,
+            Expiry::Time(t) => now >= *t,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Message {
+    pub to: String,
+    pub from: String,
+    /// The decrypted content. `None` while the message is encrypted.
+    pub payload: Option<Payload>,
+    /// The AES ciphertext of the sealed payload.
+    pub ciphertext: Vec<u8>,
+    /// The AES key, encrypted to the recipient's public key.
+    pub signing_key: Option<Vec<u8>>,
+    /// The AES key, encrypted to the sender's public key so the sender can
+    /// read back their own messages.
+    pub sender_key: Option<Vec<u8>>,
+    /// The message this one replies to. Sealed into the ciphertext on
+    /// encryption and restored by `decrypt`.
+    pub in_reply_to: Option<MessageRef>,
+    pub expires: Option<Expiry>,
+    /// Set once the body has been pruned, preserving the hash of the body the
+    /// digest was built from. Never sent to peers, who could otherwise claim
+    /// any body was pruned; chain parts save it alongside the blocks.
+    #[serde(skip)]
+    pruned_body: Option<String>,
+    /// The sender's signature over the addressing and payload, made by `new`.
+    /// Sealed into the ciphertext on encryption and restored by `decrypt`.
+    #[serde(skip)]
+    signature: Option<Vec<u8>>,
+}
+
+/// A message as saved in chain parts written before key algorithms were
+/// tagged, with the ciphertext and wrapped key hex encoded.
+#[derive(Deserialize)]
+pub struct LegacyMessage {
     pub to: String,
     pub from: String,
     pub text: String,
     pub signing_key: Option<String>,
 }
 
+impl From<LegacyMessage> for Message {
+    /// Keeps the addressing and encrypted body. The body predates payload
+    /// headers, so `decrypt` can't open it.
+    fn from(legacy: LegacyMessage) -> Self {
+        Message {
+            to: legacy.to,
+            from: legacy.from,
+            payload: None,
+            ciphertext: hex_decode(&legacy.text).unwrap_or_else(|_| legacy.text.into_bytes()),
+            signing_key: legacy.signing_key.and_then(|key| hex_decode(key).ok()),
+            sender_key: None,
+            in_reply_to: None,
+            expires: None,
+            pruned_body: None,
+            signature: None,
+        }
+    }
+}
+
 impl Message {
-    pub fn new(to: &RsaPublicKey, from: &RsaPublicKey, text: &str) -> Self {
+    /// A message from `from`, signed with its private key.
+    pub fn new(to: &PublicKey, from: &Identity, payload: Payload) -> Self {
+        let to = to.encode();
+        let from_key = from.encoded_public_key();
+        let signature = from
+            .private_key
+            .sign(&Message::signed_bytes(&to, &from_key, &payload));
+
         Message {
-            to: hex::encode(to.print_key()),
-            from: hex::encode(from.print_key()),
-            text: String::from(text),
+            to,
+            from: from_key,
+            payload: Some(payload),
+            ciphertext: Vec::new(),
             signing_key: None,
+            sender_key: None,
+            in_reply_to: None,
+            expires: None,
+            pruned_body: None,
+            signature: Some(signature),
         }
     }
 
-    pub fn encrypt(&mut self, public_key: &RsaPublicKey) -> Result<(), rsa::errors::Error> {
-        let mut rng = OsRng;
-        let padding = PaddingScheme::PKCS1v15Encrypt;
+    fn signed_bytes(to: &str, from: &str, payload: &Payload) -> Vec<u8> {
+        bincode::serialize(&(to, from, payload)).expect("Unable to serialize the payload")
+    }
 
-        let mut raw_key: [u8; 32] = [0u8; 32];
+    /// Whether the decrypted payload was signed by the key in `from`. Only
+    /// then can the sender be trusted, as anyone can put any key in `from`.
+    pub fn verify_sender(&self) -> bool {
+        match (
+            &self.payload,
+            &self.signature,
+            decode_public_key(&self.from),
+        ) {
+            (Some(payload), Some(signature), Some(sender)) => sender.verify(
+                &Message::signed_bytes(&self.to, &self.from, payload),
+                signature,
+            ),
+            _ => false,
+        }
+    }
+
+    pub fn reply_to(mut self, message: MessageRef) -> Self {
+        self.in_reply_to = Some(message);
+        self
+    }
+
+    pub fn with_expiry(mut self, expiry: Expiry) -> Self {
+        self.expires = Some(expiry);
+        self
+    }
+
+    pub fn is_expired(&self, height: u32, now: u64) -> bool {
+        match &self.expires {
+            Some(expiry) => expiry.is_expired(height, now),
+            None => false,
+        }
+    }
+
+    pub fn is_pruned(&self) -> bool {
+        self.pruned_body.is_some()
+    }
+
+    /// The hash a block commits to for this message. The addressing and
+    /// expiry are always hashed as they are, so only the body can be pruned
+    /// without changing it.
+    pub fn digest(&self) -> String {
+        let header = format!(
+            "to:\n{}\nfrom:\n{}\nexpires:\n{:?}",
+            self.to, self.from, self.expires
+        );
+        let mut hasher = Sha256::new();
+        hasher.update(Sha256::digest(header.as_bytes()));
+        hasher.update(self.body_digest().as_bytes());
+
+        hex_encode(hasher.finalize())
+    }
 
-        for index in 0..raw_key.len() {
-            raw_key[index] = rand::random();
+    /// The hash of the encrypted body, kept when the body is pruned.
+    fn body_digest(&self) -> String {
+        match &self.pruned_body {
+            Some(digest) => digest.to_owned(),
+            None => hex_encode(Sha256::digest(self.to_string().as_bytes())),
         }
+    }
+
+    /// The hash of the pruned body, if the body was pruned.
+    pub fn pruned_digest(&self) -> Option<&str> {
+        self.pruned_body.as_deref()
+    }
+
+    /// Drops the encrypted body, keeping the addressing, expiry and digest.
+    pub fn prune(&mut self) {
+        if self.is_pruned() {
+            return;
+        }
+
+        self.restore_pruned(self.body_digest());
+    }
+
+    /// Marks the body as pruned with the hash `prune` kept for it, as saved
+    /// to disk.
+    pub fn restore_pruned(&mut self, body_digest: String) {
+        self.pruned_body = Some(body_digest);
+        self.payload = None;
+        self.ciphertext = Vec::new();
+        self.signing_key = None;
+        self.sender_key = None;
+    }
 
-        let text_bytes = self.text.as_bytes();
-        let total_blocks_length: f32 = (text_bytes.len() as f32) / 16f32;
+    /// Encrypts the payload, deflate compressing it first when that saves space.
+    pub fn encrypt(&mut self, public_key: &PublicKey) -> Result<(), MessageError> {
+        self.encrypt_with(public_key, Compression::Deflate)
+    }
+
+    pub fn encrypt_with(
+        &mut self,
+        public_key: &PublicKey,
+        compression: Compression,
+    ) -> Result<(), MessageError> {
+        let payload_bytes = match &self.payload {
+            Some(payload) => payload.seal(
+                compression,
+                self.in_reply_to.as_ref(),
+                self.signature.as_deref(),
+            )?,
+            None => return Err(MessageError::MissingPayload),
+        };
+
+        let mut raw_key: [u8; 32] = [0u8; 32];
+
+        for byte in raw_key.iter_mut() {
+            *byte = rand::random();
+        }
 
         let key: &GenericArray<u8, _> =
             aes::cipher::generic_array::GenericArray::from_slice(&raw_key);
-        let cipher: Aes256 = aes::NewBlockCipher::new(&key);
+        let cipher: Aes256 = aes::NewBlockCipher::new(key);
 
         let mut blocks: Vec<GenericArray<u8, U16>> = Vec::new();
 
-        for index in 0..total_blocks_length.ceil() as usize {
+        for chunk in payload_bytes.chunks(16) {
             let mut arr = [0u8; 16];
-            for byte_index in 0..16 as usize {
-                if (index * 16) + byte_index > text_bytes.len() - 1 {
-                    continue;
-                }
-                arr[byte_index] = text_bytes[((index) * 16) + byte_index];
-            }
-
-            let block = aes::Block::clone_from_slice(&arr);
+            arr[..chunk.len()].copy_from_slice(chunk);
 
-            blocks.push(block);
+            blocks.push(aes::Block::clone_from_slice(&arr));
         }
 
         cipher.encrypt_blocks(&mut blocks[..]);
 
-        let mut new_text = String::new();
+        let mut ciphertext: Vec<u8> = Vec::with_capacity(blocks.len() * 16);
 
         for b in blocks {
-            new_text += &hex_encode(b);
+            ciphertext.extend_from_slice(b.as_slice());
         }
-        self.text = new_text;
 
-        let encrypted_signing_key;
-        encrypted_signing_key = hex_encode(
-            public_key
-                .encrypt(&mut rng, padding, key.as_slice())
-                .unwrap(),
-        );
+        let encrypted_signing_key = public_key.wrap_key(key.as_slice())?;
+        let encrypted_sender_key = match decode_public_key(&self.from) {
+            Some(sender) => Some(sender.wrap_key(key.as_slice())?),
+            None => None,
+        };
 
+        self.ciphertext = ciphertext;
+        self.payload = None;
+        self.in_reply_to = None;
+        self.signature = None;
         self.signing_key = Some(encrypted_signing_key);
+        self.sender_key = encrypted_sender_key;
 
-        return Ok(());
+        Ok(())
     }
 
-    pub fn decrypt(&mut self, private_key: &RsaPrivateKey) {
-        let padding = PaddingScheme::PKCS1v15Encrypt;
-        let encrypted_key = match &self.signing_key {
-            None => {
-                panic!("No key to decrypt")
-            }
-            Some(k) => hex_decode(k).expect("Unable to convert from hex"),
+    /// Decrypts the message as `identity`, using either the recipient's or the
+    /// sender's wrapped key.
+    pub fn decrypt(&mut self, identity: &Identity) -> Result<(), MessageError> {
+        let own_key = identity.encoded_public_key();
+        let wrapped_key = if own_key != self.to && own_key == self.from {
+            &self.sender_key
+        } else {
+            &self.signing_key
+        };
+        let encrypted_key = match wrapped_key {
+            None => return Err(MessageError::MissingKey),
+            Some(k) => k,
         };
 
-        let key = private_key
-            .decrypt(padding, &encrypted_key)
-            .expect("Unable to decrypt key");
+        let key = identity.private_key.unwrap_key(encrypted_key)?;
+        if key.len() != 32 {
+            return Err(MessageError::InvalidCiphertext);
+        }
 
         let mut cipher: Aes256 =
             aes::NewBlockCipher::new(aes::cipher::generic_array::GenericArray::from_slice(&key));
 
-        let encoded_message = hex_decode(&self.text).expect("Unable to convert text from hex");
-        let total_blocks_length: f32 = (encoded_message.len() as f32) / 16f32;
+        if !self.ciphertext.len().is_multiple_of(16) {
+            return Err(MessageError::InvalidCiphertext);
+        }
 
-        let mut blocks: Vec<GenericArray<u8, U16>> = Vec::new();
+        let mut payload_bytes: Vec<u8> = Vec::with_capacity(self.ciphertext.len());
 
-        for index in 0..total_blocks_length.ceil() as usize {
-            let mut arr = [0u8; 16];
-            for byte_index in 0..16 as usize {
-                if (index * 16) + byte_index > encoded_message.len() - 1 {
-                    continue;
-                }
-                arr[byte_index] = encoded_message[((index) * 16) + byte_index];
-            }
-
-            let mut block = aes::Block::clone_from_slice(&arr);
+        for chunk in self.ciphertext.chunks(16) {
+            let mut block = aes::Block::clone_from_slice(chunk);
             cipher.decrypt_block_mut(&mut block);
 
-            blocks.push(block);
-        }
-        let mut built_string = String::new();
-        for (index, b) in blocks.iter().enumerate() {
-            if index == blocks.len() - 1 {
-                let mut built_vec: Vec<u8> = Vec::new();
-                for ch in b {
-                    if ch != &0u8 {
-                        built_vec.push(ch.to_owned());
-                    }
-                }
-                built_string += &String::from_utf8(built_vec).expect("Unable to convert from utf8");
-            } else {
-                built_string +=
-                    &String::from_utf8(b.as_slice().to_vec()).expect("Unable to convert from utf8");
-            }
+            payload_bytes.extend_from_slice(block.as_slice());
         }
 
+        let (header, payload) = Payload::open(&payload_bytes)?;
+
+        self.payload = Some(payload);
+        self.in_reply_to = header.in_reply_to;
+        self.signature = header.signature;
+        self.ciphertext = Vec::new();
         self.signing_key = None;
-        self.text = built_string;
+        self.sender_key = None;
+
+        Ok(())
     }
 }
 
 impl ToString for Message {
     fn to_string(&self) -> String {
         format!(
-            "to:\n{}\nfrom:\n{}\ntext:\n{}\n\nsigning_key:\n{:?}",
+            "to:\n{}\nfrom:\n{}\nciphertext:\n{}\n\nsigning_key:\n{:?}\nsender_key:\n{:?}\nexpires:\n{:?}",
             self.to,
             self.from,
-            self.text,
+            hex_encode(&self.ciphertext),
             match &self.signing_key {
-                Some(key) => key,
-                None => "",
-            }
+                Some(key) => hex_encode(key),
+                None => String::new(),
+            },
+            match &self.sender_key {
+                Some(key) => hex_encode(key),
+                None => String::new(),
+            },
+            self.expires
         )
     }
 }
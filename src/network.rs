@@ -1,34 +1,359 @@
 use futures::future;
-use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
 use std::hash::Hash;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread::sleep;
-use std::time::Duration;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::{net::TcpStream, spawn};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::spawn;
+use tokio::sync::{mpsc, Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::bans::{Misbehavior, BAN_THRESHOLD};
+use crate::handshake::PeerInfo;
+use crate::keys::PublicKey;
+use crate::limits::{Limits, RateLimiter, MAX_QUEUED_BYTES};
+use crate::protocol::{
+    read_frame, write_frame, DisconnectReason, FrameError, PeerMessage, MAX_FRAME_SIZE,
+};
+use crate::transport::{NoiseStream, Transport};
+
+/// A peer connection after the Noise handshake, over any transport.
+pub type PeerStream = NoiseStream<Box<dyn Transport>>;
+
+/// The port nodes accept peer connections on.
+pub const DEFAULT_PORT: u16 = 8675;
+/// How many chunks of data each direction of a connection holds before
+/// senders wait, or the socket stops being read, until there is room.
+pub const QUEUE_CAPACITY: usize = 64;
+/// How long a closing connection may spend sending what is still queued
+/// and its `Disconnect` message.
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Room for `Pong` answers waiting to be written; pings beyond it go
+/// unanswered.
+const CONTROL_CAPACITY: usize = 8;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NetworkError {
+    /// The connection has shut down.
+    Closed,
+    /// The send queue is at `QUEUE_CAPACITY`.
+    Full,
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::Closed => write!(f, "The connection is closed"),
+            NetworkError::Full => write!(f, "The send queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+/// Why a connection ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// This node closed the connection, telling the peer why.
+    Local(DisconnectReason),
+    /// The peer closed the connection, giving a reason.
+    Remote(DisconnectReason),
+    /// The connection dropped without a reason being given.
+    Lost,
+    /// The peer stopped answering pings.
+    Unresponsive,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Local(reason) => write!(f, "disconnected: {}", reason),
+            CloseReason::Remote(reason) => write!(f, "the peer disconnected: {}", reason),
+            CloseReason::Lost => write!(f, "the connection was lost"),
+            CloseReason::Unresponsive => write!(f, "the peer stopped answering pings"),
+        }
+    }
+}
+
+/// Ends a connection from any clone of its `Network`, remembering why.
+struct Lifecycle {
+    cancel: CancellationToken,
+    reason: Arc<Mutex<Option<CloseReason>>>,
+    /// Sum of `Misbehavior::score` for everything the peer was reported for.
+    score: Arc<AtomicU32>,
+}
+
+impl Clone for Lifecycle {
+    fn clone(&self) -> Self {
+        Self {
+            cancel: self.cancel.clone(),
+            reason: self.reason.clone(),
+            score: self.score.clone(),
+        }
+    }
+}
+
+impl Lifecycle {
+    /// Records `reason` unless the connection is already closing, then
+    /// stops both halves of the connection.
+    fn close(&self, reason: CloseReason) {
+        self.reason
+            .lock()
+            .expect("Unable to lock close reason")
+            .get_or_insert(reason);
+        self.cancel.cancel();
+    }
+
+    fn reason(&self) -> Option<CloseReason> {
+        self.reason
+            .lock()
+            .expect("Unable to lock close reason")
+            .clone()
+    }
+
+    /// Adds `misbehavior` to the peer's score, closing the connection once
+    /// it reaches `BAN_THRESHOLD`. Returns whether it did.
+    fn report(&self, misbehavior: Misbehavior) -> bool {
+        let score = self
+            .score
+            .fetch_add(misbehavior.score(), Ordering::SeqCst)
+            .saturating_add(misbehavior.score());
+        if score < BAN_THRESHOLD {
+            return false;
+        }
+
+        let reason = DisconnectReason::ProtocolError(misbehavior.to_string());
+        self.close(CloseReason::Local(reason));
+        true
+    }
+}
+
+/// Waits for `blocked`, or returns `None` if the connection closes first.
+/// Pongs are not read meanwhile, so pings missed while the reader waits are
+/// not held against the peer.
+async fn unless_closed<F: Future>(
+    blocked: F,
+    activity: &Activity,
+    lifecycle: &Lifecycle,
+) -> Option<F::Output> {
+    tokio::pin!(blocked);
+    if let Poll::Ready(output) = futures::poll!(&mut blocked) {
+        return Some(output);
+    }
+
+    activity.stall(true);
+    let output = tokio::select! {
+        output = &mut blocked => Some(output),
+        _ = lifecycle.cancel.cancelled() => None,
+    };
+    activity.stall(false);
+    output
+}
+
+/// Sleeps for `wait`, without yielding when there is nothing to wait for.
+async fn throttle(wait: Duration) {
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Data from the peer, holding its share of `Limits::max_queued_bytes`
+/// until it is taken from the queue.
+type Received = (Vec<u8>, OwnedSemaphorePermit);
+
+/// How often a connection checks that its peer is still there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Time between pings.
+    pub interval: Duration,
+    /// Pings in a row that may go unanswered before the connection is
+    /// dropped.
+    pub max_missed: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            interval: Duration::from_secs(30),
+            max_missed: 3,
+        }
+    }
+}
+
+/// What is known about a connection's traffic, from `Network::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Time since the connection was made.
+    pub connected_for: Duration,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Round trip time of the last answered ping.
+    pub latency: Option<Duration>,
+}
+
+#[derive(Default)]
+struct PingState {
+    nonce: u64,
+    /// The ping waiting for an answer and when it was sent.
+    outstanding: Option<(u64, Instant)>,
+    missed: u32,
+    latency: Option<Duration>,
+    /// Whether the reader is waiting on the rate limits or the receive
+    /// queue, so answers to pings cannot be read.
+    blocked: bool,
+    /// Whether the reader has been blocked since the last ping.
+    stalled: bool,
+}
+
+/// Traffic counters and ping state shared by every clone of a `Network`.
+#[derive(Clone)]
+struct Activity {
+    connected_at: Instant,
+    bytes_received: Arc<AtomicU64>,
+    bytes_sent: Arc<AtomicU64>,
+    ping: Arc<Mutex<PingState>>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            connected_at: Instant::now(),
+            bytes_received: Arc::new(AtomicU64::new(0)),
+            bytes_sent: Arc::new(AtomicU64::new(0)),
+            ping: Arc::new(Mutex::new(PingState::default())),
+        }
+    }
+
+    /// Starts a new ping, or returns `None` once `max_missed` pings in a
+    /// row went unanswered. Pings the reader was blocked for are not
+    /// counted as missed.
+    fn ping(&self, max_missed: u32) -> Option<PeerMessage> {
+        let mut ping = self.ping.lock().expect("Unable to lock ping state");
+        let blocked = ping.blocked;
+        let stalled = std::mem::replace(&mut ping.stalled, blocked);
+        if ping.outstanding.is_some() && !stalled {
+            ping.missed += 1;
+            if ping.missed >= max_missed {
+                return None;
+            }
+        }
+
+        ping.nonce = ping.nonce.wrapping_add(1);
+        ping.outstanding = Some((ping.nonce, Instant::now()));
+        Some(PeerMessage::Ping(ping.nonce))
+    }
+
+    /// Records whether the reader is blocked on backpressure.
+    fn stall(&self, blocked: bool) {
+        let mut ping = self.ping.lock().expect("Unable to lock ping state");
+        ping.blocked = blocked;
+        ping.stalled |= blocked;
+    }
+
+    /// Records the answer to the outstanding ping. Answers to older pings
+    /// are ignored.
+    fn pong(&self, nonce: u64) {
+        let mut ping = self.ping.lock().expect("Unable to lock ping state");
+        if let Some((outstanding, sent)) = ping.outstanding {
+            if outstanding == nonce {
+                ping.latency = Some(sent.elapsed());
+                ping.outstanding = None;
+                ping.missed = 0;
+            }
+        }
+    }
+
+    fn stats(&self) -> PeerStats {
+        PeerStats {
+            connected_for: self.connected_at.elapsed(),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            latency: self.ping.lock().expect("Unable to lock ping state").latency,
+        }
+    }
+}
+
+/// Adds the bytes read or written through `inner` to `count`.
+struct Counted<S> {
+    inner: S,
+    count: Arc<AtomicU64>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        futures::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.count
+            .fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.count.fetch_add(written as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// The ends of the queues used by the socket tasks, taken by `run`.
+struct SocketEnds {
+    outgoing: mpsc::Receiver<Vec<u8>>,
+    incoming: mpsc::Sender<Received>,
+}
 
 struct MessageQueue {
-    receive_byte_queue: Arc<Mutex<Option<VecDeque<Vec<u8>>>>>,
-    send_byte_queue: Arc<Mutex<Option<VecDeque<Vec<u8>>>>>,
+    send_queue: mpsc::Sender<Vec<u8>>,
+    receive_queue: Arc<AsyncMutex<mpsc::Receiver<Received>>>,
+    socket_ends: Arc<Mutex<Option<SocketEnds>>>,
 }
 
 impl Clone for MessageQueue {
     fn clone(&self) -> Self {
         Self {
-            receive_byte_queue: self.receive_byte_queue.clone(),
-            send_byte_queue: self.send_byte_queue.clone(),
+            send_queue: self.send_queue.clone(),
+            receive_queue: self.receive_queue.clone(),
+            socket_ends: self.socket_ends.clone(),
         }
     }
 }
 
 pub struct Network {
     message_queue: MessageQueue,
-    stream: Arc<Mutex<Option<TcpStream>>>,
+    stream: Arc<Mutex<Option<PeerStream>>>,
     pub remote_address: SocketAddr,
+    peer: Option<PeerInfo>,
+    lifecycle: Lifecycle,
+    limits: Limits,
+    keepalive: Keepalive,
+    activity: Activity,
 }
 
-impl<'a> Hash for Network {
+impl Hash for Network {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.remote_address.hash(state);
     }
@@ -40,55 +365,133 @@ impl PartialEq for Network {
     }
 }
 
-impl Eq for Network {
-    fn assert_receiver_is_total_eq(&self) {}
-}
+impl Eq for Network {}
 
 impl Clone for Network {
     fn clone(&self) -> Self {
         Self {
             message_queue: self.message_queue.clone(),
             stream: self.stream.clone(),
-            remote_address: self.remote_address.clone(),
+            remote_address: self.remote_address,
+            peer: self.peer.clone(),
+            lifecycle: self.lifecycle.clone(),
+            limits: self.limits,
+            keepalive: self.keepalive,
+            activity: self.activity.clone(),
         }
     }
 }
 
-impl<'a> Network {
-    pub fn new(stream: Option<TcpStream>, address: SocketAddr) -> Self {
+impl Network {
+    /// A connection to `stream`, reporting the address of its transport.
+    pub fn over(stream: PeerStream) -> io::Result<Self> {
+        let address = stream.get_ref().peer_address()?;
+        Ok(Network::new(Some(stream), address))
+    }
+
+    pub fn new(stream: Option<PeerStream>, address: SocketAddr) -> Self {
+        let (send_queue, outgoing) = mpsc::channel(QUEUE_CAPACITY);
+        let (incoming, receive_queue) = mpsc::channel(QUEUE_CAPACITY);
+
         Self {
             message_queue: MessageQueue {
-                receive_byte_queue: Arc::new(Mutex::new(Some(VecDeque::new()))),
-                send_byte_queue: Arc::new(Mutex::new(Some(VecDeque::new()))),
+                send_queue,
+                receive_queue: Arc::new(AsyncMutex::new(receive_queue)),
+                socket_ends: Arc::new(Mutex::new(Some(SocketEnds { outgoing, incoming }))),
             },
             stream: Arc::new(Mutex::new(stream)),
             remote_address: address,
+            peer: None,
+            lifecycle: Lifecycle {
+                cancel: CancellationToken::new(),
+                reason: Arc::new(Mutex::new(None)),
+                score: Arc::new(AtomicU32::new(0)),
+            },
+            limits: Limits::default(),
+            keepalive: Keepalive::default(),
+            activity: Activity::new(),
         }
     }
 
-    pub async fn run(&mut self) {
-        let should_shutdown = Arc::new(Mutex::new(false));
-        let should_shutdown_clone = should_shutdown.clone();
+    /// Closes the connection with `DisconnectReason::Shutdown` when
+    /// `shutdown` is cancelled.
+    pub fn with_shutdown(mut self, shutdown: &CancellationToken) -> Self {
+        self.lifecycle.cancel = shutdown.child_token();
+        self
+    }
+
+    /// Throttles the peer to `limits` instead of `Limits::default()`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = Limits {
+            max_queued_bytes: limits.max_queued_bytes.min(MAX_QUEUED_BYTES),
+            ..limits
+        };
+        self
+    }
+
+    /// Pings the peer as set out in `keepalive` instead of
+    /// `Keepalive::default()`.
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Records who is on the other end once the handshake has completed.
+    pub fn with_peer(mut self, peer: PeerInfo) -> Self {
+        self.peer = Some(peer);
+        self
+    }
 
+    pub fn peer(&self) -> Option<&PeerInfo> {
+        self.peer.as_ref()
+    }
+
+    /// The node key the peer signed the encrypted session with.
+    pub fn remote_public_key(&self) -> Option<&PublicKey> {
+        self.peer.as_ref().map(|peer| &peer.public_key)
+    }
+
+    pub async fn run(&mut self) {
         let stream = self
             .stream
             .lock()
             .unwrap()
             .take()
-            .expect("There is no TcpStream to work on!");
+            .expect("There is no stream to work on!");
+        let SocketEnds { outgoing, incoming } = self
+            .message_queue
+            .socket_ends
+            .lock()
+            .unwrap()
+            .take()
+            .expect("The connection is already running");
 
-        let (mut r, mut w) = io::split(stream);
+        let (r, w) = io::split(stream);
+        let mut r = Counted {
+            inner: r,
+            count: self.activity.bytes_received.clone(),
+        };
+        let mut w = Counted {
+            inner: w,
+            count: self.activity.bytes_sent.clone(),
+        };
+        // Carries answers to the peer's pings from the reader to the writer.
+        let (control, replies) = mpsc::channel(CONTROL_CAPACITY);
 
         // Receive Logic
-        let receive_queue = (self.message_queue).clone().receive_byte_queue;
+        let lifecycle = self.lifecycle.clone();
+        let activity = self.activity.clone();
+        let limits = self.limits;
         let read_handle = spawn(async move {
-            Network::do_read(&mut r, receive_queue, should_shutdown.clone()).await;
+            Network::do_read(&mut r, incoming, control, lifecycle, activity, limits).await;
         });
 
-        let send_queue = (self.message_queue).clone().send_byte_queue;
         // Write Logic
+        let lifecycle = self.lifecycle.clone();
+        let activity = self.activity.clone();
+        let keepalive = self.keepalive;
         let write_handle = spawn(async move {
-            Network::do_write(&mut w, send_queue, should_shutdown_clone.clone()).await;
+            Network::do_write(&mut w, outgoing, replies, lifecycle, activity, keepalive).await;
         });
 
         let (read_result, write_result) = future::join(read_handle, write_handle).await;
@@ -100,88 +503,211 @@ impl<'a> Network {
     }
 
     async fn do_read(
-        reader: &mut io::ReadHalf<TcpStream>,
-        read_queue: Arc<Mutex<Option<VecDeque<Vec<u8>>>>>,
-        should_shutdown: Arc<Mutex<bool>>,
+        reader: &mut Counted<io::ReadHalf<PeerStream>>,
+        read_queue: mpsc::Sender<Received>,
+        control: mpsc::Sender<PeerMessage>,
+        lifecycle: Lifecycle,
+        activity: Activity,
+        limits: Limits,
     ) {
-        let mut buffer = [0u8; 1024];
-        let mut bytes_read: usize;
+        let mut bytes = RateLimiter::new(limits.bytes_per_second, Instant::now());
+        let mut messages = RateLimiter::new(limits.messages_per_second, Instant::now());
+        let queued_bytes = Arc::new(Semaphore::new(limits.max_queued_bytes));
         loop {
-            bytes_read = match reader.read(&mut buffer).await {
-                Ok(amount) => amount,
-                Err(_) => 0,
+            let message = tokio::select! {
+                message = read_frame(reader, MAX_FRAME_SIZE) => message,
+                _ = lifecycle.cancel.cancelled() => return,
             };
 
-            if bytes_read == 0 {
-                *should_shutdown.lock().unwrap() = true;
-                break;
-            }
+            let data = match message {
+                Ok(PeerMessage::Data(data)) => data,
+                Ok(PeerMessage::Disconnect(reason)) => {
+                    lifecycle.close(CloseReason::Remote(reason));
+                    return;
+                }
+                // Control frames count against the message rate like data,
+                // so a peer cannot have pings answered faster than it may
+                // send.
+                Ok(PeerMessage::Ping(nonce)) => {
+                    let wait = messages.take(1, Instant::now());
+                    if unless_closed(throttle(wait), &activity, &lifecycle)
+                        .await
+                        .is_none()
+                    {
+                        return;
+                    }
+                    // A peer pinging faster than the answers go out is not
+                    // answered every time.
+                    let _ = control.try_send(PeerMessage::Pong(nonce));
+                    continue;
+                }
+                Ok(PeerMessage::Pong(nonce)) => {
+                    activity.pong(nonce);
+                    let wait = messages.take(1, Instant::now());
+                    if unless_closed(throttle(wait), &activity, &lifecycle)
+                        .await
+                        .is_none()
+                    {
+                        return;
+                    }
+                    continue;
+                }
+                Err(FrameError::Io(_)) => {
+                    lifecycle.close(CloseReason::Lost);
+                    return;
+                }
+                // The body was not read, so the next frame cannot be found.
+                Err(e @ FrameError::TooLarge(_)) => {
+                    lifecycle.report(Misbehavior::OversizedFrame);
+                    let reason = DisconnectReason::ProtocolError(e.to_string());
+                    lifecycle.close(CloseReason::Local(reason));
+                    return;
+                }
+                Err(FrameError::Malformed) => {
+                    if lifecycle.report(Misbehavior::MalformedMessage) {
+                        return;
+                    }
+                    continue;
+                }
+            };
 
-            read_queue
-                .as_ref()
-                .lock()
-                .as_mut()
-                .unwrap()
-                .as_mut()
-                .expect("Unable to get a VecDeque from the Mutex in the reader")
-                .push_front(buffer[0..bytes_read].to_vec());
+            // Waits while the peer is over its rate or the queue is full,
+            // which stops reading from the socket and so slows the peer down.
+            let now = Instant::now();
+            let wait = bytes
+                .take(data.len() as u64, now)
+                .max(messages.take(1, now));
+            let permits = data.len().clamp(1, limits.max_queued_bytes.max(1)) as u32;
+            let queued = async {
+                throttle(wait).await;
+                let permit = queued_bytes
+                    .clone()
+                    .acquire_many_owned(permits)
+                    .await
+                    .expect("The queued byte count is never closed");
+                read_queue.send((data, permit)).await
+            };
+            if unless_closed(queued, &activity, &lifecycle).await.is_none() {
+                return;
+            }
         }
     }
 
     async fn do_write(
-        writer: &mut io::WriteHalf<TcpStream>,
-        write_queue: Arc<Mutex<Option<VecDeque<Vec<u8>>>>>,
-        should_shutdown: Arc<Mutex<bool>>,
+        writer: &mut Counted<io::WriteHalf<PeerStream>>,
+        mut write_queue: mpsc::Receiver<Vec<u8>>,
+        mut replies: mpsc::Receiver<PeerMessage>,
+        lifecycle: Lifecycle,
+        activity: Activity,
+        keepalive: Keepalive,
     ) {
+        let start = tokio::time::Instant::now() + keepalive.interval;
+        let mut pings = tokio::time::interval_at(start, keepalive.interval);
+        pings.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         loop {
-            {
-                let shutdown = *should_shutdown.lock().unwrap();
-                if shutdown {
-                    break;
-                }
-            }
-            let result;
-            {
-                result = write_queue
-                    .as_ref()
-                    .lock()
-                    .expect("Unable to aquire lock for write deque")
-                    .as_mut()
-                    .expect("Write deque is unavailable")
-                    .pop_back();
-            }
-            match result {
-                None => sleep(Duration::from_millis(500)),
-                Some(data) => match writer.write_all(&data[..]).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        println!("An error occured writing to an output stream");
-                        *should_shutdown.lock().unwrap() = true;
+            let message = tokio::select! {
+                data = write_queue.recv() => match data {
+                    Some(data) => PeerMessage::Data(data),
+                    None => break,
+                },
+                Some(reply) = replies.recv() => reply,
+                _ = pings.tick() => match activity.ping(keepalive.max_missed) {
+                    Some(ping) => ping,
+                    None => {
+                        lifecycle.close(CloseReason::Unresponsive);
+                        return;
                     }
                 },
+                _ = lifecycle.cancel.cancelled() => break,
+            };
+
+            if write_frame(writer, &message).await.is_err() {
+                println!("An error occured writing to an output stream");
+                lifecycle.close(CloseReason::Lost);
+                return;
             }
         }
+
+        // Ending without a reason means the node is stopping, or nothing
+        // holds the connection any more.
+        lifecycle.close(CloseReason::Local(DisconnectReason::Shutdown));
+        if let Some(CloseReason::Local(reason)) = lifecycle.reason() {
+            let goodbye = async {
+                while let Ok(data) = write_queue.try_recv() {
+                    write_frame(writer, &PeerMessage::Data(data)).await?;
+                }
+                write_frame(writer, &PeerMessage::Disconnect(reason)).await?;
+                writer.shutdown().await.map_err(FrameError::Io)
+            };
+            // The connection is dropped either way.
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, goodbye).await;
+        }
     }
 
-    pub fn get_next_data(&mut self) -> Option<Vec<u8>> {
-        let receive_queue_arc = &self.message_queue.receive_byte_queue;
+    /// Closes the connection, telling the peer `reason` after sending what
+    /// is already queued.
+    pub fn disconnect(&self, reason: DisconnectReason) {
+        self.lifecycle.close(CloseReason::Local(reason));
+    }
 
-        receive_queue_arc
-            .lock()
-            .expect("Unable to aquire lock for receive queue")
-            .as_mut()
-            .expect("Receive queue is not available")
-            .pop_back()
+    /// Scores the peer for `misbehavior`, disconnecting it once its score
+    /// reaches `BAN_THRESHOLD`. Returns whether the connection is closing.
+    pub fn report(&self, misbehavior: Misbehavior) -> bool {
+        self.lifecycle.report(misbehavior)
     }
 
-    pub fn send_data(&mut self, data: Vec<u8>) {
-        let send_queue_arc = &self.message_queue.send_byte_queue;
+    /// The peer's misbehavior score; it should be banned once this reaches
+    /// `BAN_THRESHOLD`.
+    pub fn misbehavior_score(&self) -> u32 {
+        self.lifecycle.score.load(Ordering::SeqCst)
+    }
 
-        send_queue_arc
-            .lock()
-            .expect("Unable to aquire lock for queue")
-            .as_mut()
-            .expect("VecDeque is not available")
-            .push_front(data);
+    /// Traffic on the connection so far and the last measured latency.
+    pub fn stats(&self) -> PeerStats {
+        self.activity.stats()
+    }
+
+    /// Why the connection ended, or `None` while it is open.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.lifecycle.reason()
+    }
+
+    /// Returns data received from the peer if any is waiting.
+    pub fn get_next_data(&self) -> Option<Vec<u8>> {
+        self.message_queue
+            .receive_queue
+            .try_lock()
+            .ok()?
+            .try_recv()
+            .ok()
+            .map(|(data, _)| data)
+    }
+
+    /// Waits for the next data received from the peer, or `None` once the
+    /// connection has shut down and everything received has been taken.
+    pub async fn next_data(&self) -> Option<Vec<u8>> {
+        let received = self.message_queue.receive_queue.lock().await.recv().await;
+        received.map(|(data, _)| data)
+    }
+
+    /// Queues `data` to be written to the peer, waiting while the queue is
+    /// full.
+    pub async fn send_data(&self, data: Vec<u8>) -> Result<(), NetworkError> {
+        self.message_queue
+            .send_queue
+            .send(data)
+            .await
+            .map_err(|_| NetworkError::Closed)
+    }
+
+    /// Queues `data` to be written to the peer without waiting.
+    pub fn try_send_data(&self, data: Vec<u8>) -> Result<(), NetworkError> {
+        self.message_queue
+            .send_queue
+            .try_send(data)
+            .map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => NetworkError::Full,
+                mpsc::error::TrySendError::Closed(_) => NetworkError::Closed,
+            })
     }
 }
@@ -0,0 +1,458 @@
+use crate::chunk::{Manifest, ManifestV1};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Largest serialized payload accepted for encryption, in bytes.
+pub const MAX_PAYLOAD_SIZE: usize = 256 * 1024;
+/// Largest serialized payload that may be split into chunks by `chunk::split`.
+pub const MAX_CHUNKED_PAYLOAD_SIZE: usize = 64 * 1024 * 1024;
+/// Version written into every `PayloadHeader`. Payloads sealed under earlier
+/// versions can still be opened.
+pub const PAYLOAD_VERSION: u8 = 3;
+/// Set in `PayloadHeader::flags` when the payload body is deflate compressed.
+pub const FLAG_COMPRESSED: u8 = 0b0000_0001;
+
+/// Largest attachment body accepted, leaving room for the payload metadata.
+/// Attachments over `MAX_PAYLOAD_SIZE` have to be sent with `chunk::split`.
+pub const MAX_ATTACHMENT_SIZE: usize = MAX_CHUNKED_PAYLOAD_SIZE - 4 * 1024;
+
+#[derive(Debug)]
+pub enum PayloadError {
+    TooLarge { size: usize, max: usize },
+    UnsupportedVersion(u8),
+    Serialization(bincode::Error),
+    Io(io::Error),
+}
+
+impl From<io::Error> for PayloadError {
+    fn from(e: io::Error) -> Self {
+        PayloadError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Deflate,
+}
+
+/// Points at a message on the chain by the block it was mined in and its
+/// position within that block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MessageRef {
+    pub block_hash: String,
+    pub index: u32,
+}
+
+/// Precedes the serialized payload inside the encrypted body of a `Message`,
+/// telling the recipient how the bytes that follow were encoded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PayloadHeader {
+    pub version: u8,
+    pub flags: u8,
+    /// The message this one answers. Encrypted along with the payload so only
+    /// the participants can see how messages are threaded.
+    pub in_reply_to: Option<MessageRef>,
+    /// The sender's signature, see `Message::verify_sender`. Encrypted so
+    /// only the participants can check it.
+    pub signature: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    header: PayloadHeader,
+    body: Vec<u8>,
+}
+
+/// `Envelope` as sealed under version 1, before the header carried replies.
+#[derive(Deserialize)]
+struct EnvelopeV1 {
+    version: u8,
+    flags: u8,
+    body: Vec<u8>,
+}
+
+/// `Envelope` as sealed under version 2, before the header carried the
+/// sender's signature.
+#[derive(Deserialize)]
+struct EnvelopeV2 {
+    version: u8,
+    flags: u8,
+    in_reply_to: Option<MessageRef>,
+    body: Vec<u8>,
+}
+
+/// `Payload` as sealed under version 1, when references named only a block.
+/// They are taken to point at the block's first message.
+#[derive(Deserialize)]
+enum PayloadV1 {
+    Text(String),
+    Attachment(Attachment),
+    Reply {
+        block_hash: String,
+        text: String,
+    },
+    Reaction {
+        block_hash: String,
+        reaction: String,
+    },
+    Receipt {
+        block_hash: String,
+        kind: ReceiptKind,
+    },
+    Chunk(Vec<u8>),
+    Manifest(ManifestV1),
+}
+
+/// `Payload` as sealed under version 2, when replies named the message they
+/// answer in the payload as well as in the header.
+#[derive(Deserialize)]
+enum PayloadV2 {
+    Text(String),
+    Attachment(Attachment),
+    Reply {
+        message: MessageRef,
+        text: String,
+    },
+    Reaction {
+        message: MessageRef,
+        reaction: String,
+    },
+    Receipt {
+        message: MessageRef,
+        kind: ReceiptKind,
+    },
+    Chunk(Vec<u8>),
+    Manifest(ManifestV1),
+}
+
+impl PayloadV1 {
+    /// The payload, and the message it replies to if it is a reply.
+    fn upgrade(self) -> (Payload, Option<MessageRef>) {
+        let first = |block_hash| MessageRef {
+            block_hash,
+            index: 0,
+        };
+
+        match self {
+            PayloadV1::Text(text) => (Payload::Text(text), None),
+            PayloadV1::Attachment(attachment) => (Payload::Attachment(attachment), None),
+            PayloadV1::Reply { block_hash, text } => (Payload::Text(text), Some(first(block_hash))),
+            PayloadV1::Reaction {
+                block_hash,
+                reaction,
+            } => (
+                Payload::Reaction {
+                    message: first(block_hash),
+                    reaction,
+                },
+                None,
+            ),
+            PayloadV1::Receipt { block_hash, kind } => (
+                Payload::Receipt {
+                    message: first(block_hash),
+                    kind,
+                },
+                None,
+            ),
+            PayloadV1::Chunk(bytes) => (Payload::Chunk(bytes), None),
+            PayloadV1::Manifest(manifest) => (Payload::Manifest(manifest.into()), None),
+        }
+    }
+}
+
+impl PayloadV2 {
+    /// The payload, and the message it replies to if it is a reply.
+    fn upgrade(self) -> (Payload, Option<MessageRef>) {
+        match self {
+            PayloadV2::Text(text) => (Payload::Text(text), None),
+            PayloadV2::Attachment(attachment) => (Payload::Attachment(attachment), None),
+            PayloadV2::Reply { message, text } => (Payload::Text(text), Some(message)),
+            PayloadV2::Reaction { message, reaction } => {
+                (Payload::Reaction { message, reaction }, None)
+            }
+            PayloadV2::Receipt { message, kind } => (Payload::Receipt { message, kind }, None),
+            PayloadV2::Chunk(bytes) => (Payload::Chunk(bytes), None),
+            PayloadV2::Manifest(manifest) => (Payload::Manifest(manifest.into()), None),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub mime_type: String,
+    pub filename: String,
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    pub fn new(mime_type: &str, filename: &str, data: Vec<u8>) -> Result<Self, PayloadError> {
+        if data.len() > MAX_ATTACHMENT_SIZE {
+            return Err(PayloadError::TooLarge {
+                size: data.len(),
+                max: MAX_ATTACHMENT_SIZE,
+            });
+        }
+
+        Ok(Attachment {
+            mime_type: String::from(mime_type),
+            filename: String::from(filename),
+            data,
+        })
+    }
+
+    /// Reads `path` into an attachment, refusing files over `MAX_ATTACHMENT_SIZE`
+    /// before any of the contents are loaded.
+    pub fn from_file(path: &Path, mime_type: &str) -> Result<Self, PayloadError> {
+        let size = fs::metadata(path)?.len() as usize;
+        if size > MAX_ATTACHMENT_SIZE {
+            return Err(PayloadError::TooLarge {
+                size,
+                max: MAX_ATTACHMENT_SIZE,
+            });
+        }
+
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("attachment"));
+
+        Attachment::new(mime_type, &filename, fs::read(path)?)
+    }
+
+    /// Writes the attachment into `directory` and returns the path it was saved to.
+    ///
+    /// Only the final component of the sender-supplied filename is used, and an
+    /// existing file is never overwritten; a numeric suffix is added instead.
+    pub fn write_to(&self, directory: &Path) -> Result<PathBuf, PayloadError> {
+        fs::create_dir_all(directory)?;
+
+        let safe_name = Path::new(&self.filename)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .filter(|name| !name.is_empty() && name != "..")
+            .unwrap_or_else(|| String::from("attachment"));
+
+        let mut path = directory.join(&safe_name);
+        let mut counter: u32 = 1;
+        while path.exists() {
+            path = directory.join(format!("{}.{}", safe_name, counter));
+            counter += 1;
+        }
+
+        fs::write(&path, &self.data)?;
+
+        Ok(path)
+    }
+}
+
+impl std::fmt::Debug for Attachment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Attachment")
+            .field("mime_type", &self.mime_type)
+            .field("filename", &self.filename)
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+/// The plaintext content of a `Message`. It is serialized with bincode and the
+/// resulting bytes are what gets encrypted. Replies are marked by
+/// `Message::in_reply_to` rather than the payload.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    Text(String),
+    Attachment(Attachment),
+    Reaction {
+        message: MessageRef,
+        reaction: String,
+    },
+    Receipt {
+        message: MessageRef,
+        kind: ReceiptKind,
+    },
+    /// One content-addressed piece of a payload too large for a single message.
+    Chunk(Vec<u8>),
+    /// Lists the chunks that make up a large payload, in order.
+    Manifest(Manifest),
+}
+
+impl Payload {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PayloadError> {
+        let bytes = bincode::serialize(self).map_err(PayloadError::Serialization)?;
+
+        if bytes.len() > MAX_PAYLOAD_SIZE {
+            return Err(PayloadError::TooLarge {
+                size: bytes.len(),
+                max: MAX_PAYLOAD_SIZE,
+            });
+        }
+
+        Ok(bytes)
+    }
+
+    /// Trailing bytes after the encoded payload (such as cipher block padding)
+    /// are ignored.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PayloadError> {
+        bincode::deserialize(bytes).map_err(PayloadError::Serialization)
+    }
+
+    /// A one line description for listing messages.
+    pub fn summary(&self) -> String {
+        match self {
+            Payload::Text(text) => text.clone(),
+            Payload::Attachment(attachment) => format!(
+                "[attachment {} ({} bytes)]",
+                attachment.filename,
+                attachment.data.len()
+            ),
+            Payload::Reaction { reaction, .. } => format!("[reaction {}]", reaction),
+            Payload::Receipt { kind, .. } => format!("[{:?} receipt]", kind),
+            Payload::Chunk(_) => String::from("[chunk]"),
+            Payload::Manifest(_) => String::from("[large payload]"),
+        }
+    }
+
+    /// The message this payload refers to, for the variants that carry one.
+    pub fn target(&self) -> Option<&MessageRef> {
+        match self {
+            Payload::Reaction { message, .. } => Some(message),
+            Payload::Receipt { message, .. } => Some(message),
+            _ => None,
+        }
+    }
+
+    /// Encodes the payload behind a `PayloadHeader`, ready to be encrypted.
+    ///
+    /// With `Compression::Deflate` the body is only stored compressed when that
+    /// actually makes it smaller, so the flag is left unset for incompressible data.
+    pub fn seal(
+        &self,
+        compression: Compression,
+        in_reply_to: Option<&MessageRef>,
+        signature: Option<&[u8]>,
+    ) -> Result<Vec<u8>, PayloadError> {
+        let bytes = self.to_bytes()?;
+        let mut header = PayloadHeader {
+            version: PAYLOAD_VERSION,
+            flags: 0,
+            in_reply_to: in_reply_to.cloned(),
+            signature: signature.map(<[u8]>::to_vec),
+        };
+
+        let body = match compression {
+            Compression::None => bytes,
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&bytes)?;
+                let compressed = encoder.finish()?;
+
+                if compressed.len() < bytes.len() {
+                    header.flags |= FLAG_COMPRESSED;
+                    compressed
+                } else {
+                    bytes
+                }
+            }
+        };
+
+        bincode::serialize(&Envelope { header, body }).map_err(PayloadError::Serialization)
+    }
+
+    /// Reverses `seal`, for payloads sealed under any version up to
+    /// `PAYLOAD_VERSION`. Decompression stops at `MAX_PAYLOAD_SIZE` so a small
+    /// ciphertext cannot expand into an unbounded allocation.
+    pub fn open(bytes: &[u8]) -> Result<(PayloadHeader, Self), PayloadError> {
+        // The version is the first byte under every version.
+        let (mut header, body) = match bytes.first() {
+            Some(&version) if version == 0 || version > PAYLOAD_VERSION => {
+                return Err(PayloadError::UnsupportedVersion(version));
+            }
+            Some(1) => {
+                let envelope: EnvelopeV1 =
+                    bincode::deserialize(bytes).map_err(PayloadError::Serialization)?;
+                let header = PayloadHeader {
+                    version: envelope.version,
+                    flags: envelope.flags,
+                    in_reply_to: None,
+                    signature: None,
+                };
+                (header, envelope.body)
+            }
+            Some(2) => {
+                let envelope: EnvelopeV2 =
+                    bincode::deserialize(bytes).map_err(PayloadError::Serialization)?;
+                let header = PayloadHeader {
+                    version: envelope.version,
+                    flags: envelope.flags,
+                    in_reply_to: envelope.in_reply_to,
+                    signature: None,
+                };
+                (header, envelope.body)
+            }
+            _ => {
+                let envelope: Envelope =
+                    bincode::deserialize(bytes).map_err(PayloadError::Serialization)?;
+                (envelope.header, envelope.body)
+            }
+        };
+
+        let body = if header.flags & FLAG_COMPRESSED == 0 {
+            body
+        } else {
+            Payload::inflate(&body)?
+        };
+
+        let (payload, in_reply_to) = match header.version {
+            1 => bincode::deserialize::<PayloadV1>(&body)
+                .map_err(PayloadError::Serialization)?
+                .upgrade(),
+            2 => bincode::deserialize::<PayloadV2>(&body)
+                .map_err(PayloadError::Serialization)?
+                .upgrade(),
+            _ => (Payload::from_bytes(&body)?, None),
+        };
+        header.in_reply_to = header.in_reply_to.or(in_reply_to);
+
+        Ok((header, payload))
+    }
+
+    fn inflate(body: &[u8]) -> Result<Vec<u8>, PayloadError> {
+        let mut decompressed: Vec<u8> = Vec::new();
+        DeflateDecoder::new(body)
+            .take(MAX_PAYLOAD_SIZE as u64 + 1)
+            .read_to_end(&mut decompressed)?;
+
+        if decompressed.len() > MAX_PAYLOAD_SIZE {
+            return Err(PayloadError::TooLarge {
+                size: decompressed.len(),
+                max: MAX_PAYLOAD_SIZE,
+            });
+        }
+
+        Ok(decompressed)
+    }
+
+    /// Writes the payload's attachment, if it has one, into `directory`.
+    pub fn save_attachment(&self, directory: &Path) -> Result<Option<PathBuf>, PayloadError> {
+        match self {
+            Payload::Attachment(attachment) => attachment.write_to(directory).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl From<&str> for Payload {
+    fn from(text: &str) -> Self {
+        Payload::Text(String::from(text))
+    }
+}
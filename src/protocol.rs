@@ -0,0 +1,100 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::block::MAX_BLOCK_SIZE;
+
+/// Largest frame accepted once the handshake is done, leaving room for a
+/// full block and its encoding.
+pub const MAX_FRAME_SIZE: usize = 2 * MAX_BLOCK_SIZE;
+
+/// Why a node is closing a connection, sent to the peer before it goes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The node is shutting down.
+    Shutdown,
+    /// The peer broke the protocol.
+    ProtocolError(String),
+    Other(String),
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Shutdown => write!(f, "shutting down"),
+            DisconnectReason::ProtocolError(error) => write!(f, "protocol error: {}", error),
+            DisconnectReason::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// Everything peers send each other after the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    /// Data queued with `Network::send_data`.
+    Data(Vec<u8>),
+    /// The last message on a connection.
+    Disconnect(DisconnectReason),
+    /// Asks the peer to answer with a `Pong` carrying the same number.
+    Ping(u64),
+    Pong(u64),
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    /// The frame announced more bytes than the reader accepts.
+    TooLarge(usize),
+    Malformed,
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::TooLarge(length) => write!(f, "A {} byte frame is too large", length),
+            FrameError::Malformed => write!(f, "The frame could not be parsed"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Writes `message` as a big endian u32 length followed by its bincode
+/// encoding, and flushes it.
+pub async fn write_frame<S, T>(stream: &mut S, message: &T) -> Result<(), FrameError>
+where
+    S: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let bytes = bincode::serialize(message).map_err(|_| FrameError::Malformed)?;
+    stream.write_u32(bytes.len() as u32).await?;
+    stream.write_all(&bytes).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+/// Reads a frame written by `write_frame`, refusing any longer than
+/// `max_size` before reading its body.
+pub async fn read_frame<S, T>(stream: &mut S, max_size: usize) -> Result<T, FrameError>
+where
+    S: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let length = stream.read_u32().await? as usize;
+    if length > max_size {
+        return Err(FrameError::TooLarge(length));
+    }
+
+    let mut bytes = vec![0u8; length];
+    stream.read_exact(&mut bytes).await?;
+    bincode::deserialize(&bytes).map_err(|_| FrameError::Malformed)
+}
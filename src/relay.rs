@@ -0,0 +1,435 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::bans::Misbehavior;
+use crate::{Block, Message, Network};
+
+/// How long a peer has to answer a `GetData` before the item is asked for
+/// from another peer that announced it.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most items in one `Inventory`, `GetData` or `NotFound`.
+pub const MAX_INVENTORY: usize = 1000;
+/// Items remembered per peer as already known to it.
+const KNOWN_INVENTORY_SIZE: usize = 4096;
+/// Objects kept to answer `GetData` requests.
+const OBJECT_CACHE_SIZE: usize = 1024;
+
+/// Names a block by its hash or a message by its digest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum InventoryItem {
+    Block(String),
+    Message(String),
+}
+
+/// A block or message passed between peers.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Object {
+    Block(Block),
+    Message(Message),
+}
+
+impl Object {
+    pub fn item(&self) -> InventoryItem {
+        match self {
+            Object::Block(block) => InventoryItem::Block(block.hash.clone()),
+            Object::Message(message) => InventoryItem::Message(message.digest()),
+        }
+    }
+
+    /// The item the object's content hashes to, or `None` for a pruned
+    /// message, whose body can no longer be checked. Unlike `item`, this
+    /// does not trust the hash a block carries.
+    fn content_item(&self) -> Option<InventoryItem> {
+        match self {
+            Object::Block(block) => Some(InventoryItem::Block(block.computed_hash())),
+            Object::Message(message) if message.is_pruned() => None,
+            Object::Message(message) => Some(InventoryItem::Message(message.digest())),
+        }
+    }
+}
+
+/// What peers send each other as `Network` data to share blocks and
+/// messages. Objects are announced by their inventory items and only sent
+/// when asked for, so each crosses each connection at most once.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum RelayMessage {
+    /// Items the sender has and the receiver may not.
+    Inventory(Vec<InventoryItem>),
+    /// Asks for the objects named by the items.
+    GetData(Vec<InventoryItem>),
+    Object(Box<Object>),
+    /// Answers a `GetData` for items the sender does not have.
+    NotFound(Vec<InventoryItem>),
+}
+
+impl RelayMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Unable to serialize relay message")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+/// Messages for peers, by address.
+pub type Outgoing = Vec<(SocketAddr, RelayMessage)>;
+
+/// The result of `Relay::handle`.
+#[derive(Default)]
+pub struct Handled {
+    pub replies: Outgoing,
+    /// An object the peer sent because it was asked for. It should be
+    /// checked, then passed to `Relay::publish` if it is valid.
+    pub received: Option<Object>,
+}
+
+/// The most recent items a peer is known to have, so they are not
+/// announced to it again.
+#[derive(Default)]
+struct KnownInventory {
+    order: VecDeque<InventoryItem>,
+    items: HashSet<InventoryItem>,
+}
+
+impl KnownInventory {
+    fn insert(&mut self, item: &InventoryItem) {
+        if !self.items.insert(item.clone()) {
+            return;
+        }
+        self.order.push_back(item.clone());
+        if self.order.len() > KNOWN_INVENTORY_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+    }
+
+    fn contains(&self, item: &InventoryItem) -> bool {
+        self.items.contains(item)
+    }
+}
+
+struct Request {
+    peer: SocketAddr,
+    sent: Instant,
+}
+
+/// Decides what to announce, request and send to each peer. It only keeps
+/// state; the caller passes in what peers send and delivers what it returns,
+/// see `send_all`.
+pub struct Relay {
+    known: HashMap<SocketAddr, KnownInventory>,
+    in_flight: HashMap<InventoryItem, Request>,
+    /// Peers that announced an item being fetched, asked in turn when a
+    /// request fails.
+    sources: HashMap<InventoryItem, VecDeque<SocketAddr>>,
+    objects: HashMap<InventoryItem, Object>,
+    object_order: VecDeque<InventoryItem>,
+    timeout: Duration,
+}
+
+impl Relay {
+    pub fn new(timeout: Duration) -> Self {
+        Relay {
+            known: HashMap::new(),
+            in_flight: HashMap::new(),
+            sources: HashMap::new(),
+            objects: HashMap::new(),
+            object_order: VecDeque::new(),
+            timeout,
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: SocketAddr) {
+        self.known.entry(peer).or_default();
+    }
+
+    /// Forgets `peer`, asking other peers for whatever it was sending.
+    pub fn remove_peer(&mut self, peer: SocketAddr, now: Instant) -> Outgoing {
+        self.known.remove(&peer);
+        for sources in self.sources.values_mut() {
+            sources.retain(|source| *source != peer);
+        }
+
+        let stalled: Vec<InventoryItem> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.peer == peer)
+            .map(|(item, _)| item.clone())
+            .collect();
+        self.request_elsewhere(stalled, now)
+    }
+
+    pub fn has(&self, item: &InventoryItem) -> bool {
+        self.objects.contains_key(item)
+    }
+
+    /// Keeps `object` to serve requests for it and announces it to every
+    /// peer not known to have it.
+    pub fn publish(&mut self, object: Object) -> Outgoing {
+        let item = object.item();
+        if !self.objects.contains_key(&item) {
+            self.objects.insert(item.clone(), object);
+            self.object_order.push_back(item.clone());
+            if self.object_order.len() > OBJECT_CACHE_SIZE {
+                if let Some(oldest) = self.object_order.pop_front() {
+                    self.objects.remove(&oldest);
+                }
+            }
+        }
+
+        let mut outgoing = Outgoing::new();
+        for (peer, known) in self.known.iter_mut() {
+            if !known.contains(&item) {
+                known.insert(&item);
+                outgoing.push((*peer, RelayMessage::Inventory(vec![item.clone()])));
+            }
+        }
+        outgoing
+    }
+
+    /// Asks peers for `items` that were not announced, such as the messages
+    /// carrying missing chunks. Each is asked of one peer at a time until one
+    /// sends it.
+    pub fn request(&mut self, items: Vec<InventoryItem>, now: Instant) -> Outgoing {
+        let peers: VecDeque<SocketAddr> = self.known.keys().copied().collect();
+        let mut wanted = Vec::new();
+        for item in items {
+            if self.has(&item) || self.in_flight.contains_key(&item) {
+                continue;
+            }
+            self.sources.insert(item.clone(), peers.clone());
+            wanted.push(item);
+        }
+
+        self.request_elsewhere(wanted, now)
+    }
+
+    /// Works out the answer to `message` from `peer`. Peers that break the
+    /// relay protocol get the misbehavior to report them for.
+    pub fn handle(
+        &mut self,
+        peer: SocketAddr,
+        message: RelayMessage,
+        now: Instant,
+    ) -> Result<Handled, Misbehavior> {
+        let mut handled = Handled::default();
+        match message {
+            RelayMessage::Inventory(items) => {
+                check_length(&items)?;
+                let mut wanted = Vec::new();
+                for item in items {
+                    self.known_to(peer).insert(&item);
+                    if self.has(&item) {
+                        continue;
+                    }
+                    let sources = self.sources.entry(item.clone()).or_default();
+                    if !sources.contains(&peer) {
+                        sources.push_back(peer);
+                    }
+                    if !self.in_flight.contains_key(&item) {
+                        self.in_flight
+                            .insert(item.clone(), Request { peer, sent: now });
+                        wanted.push(item);
+                    }
+                }
+                if !wanted.is_empty() {
+                    handled.replies.push((peer, RelayMessage::GetData(wanted)));
+                }
+            }
+            RelayMessage::GetData(items) => {
+                check_length(&items)?;
+                let mut missing = Vec::new();
+                for item in items {
+                    match self.objects.get(&item).cloned() {
+                        Some(object) => {
+                            self.known_to(peer).insert(&item);
+                            handled
+                                .replies
+                                .push((peer, RelayMessage::Object(Box::new(object))));
+                        }
+                        None => missing.push(item),
+                    }
+                }
+                if !missing.is_empty() {
+                    handled
+                        .replies
+                        .push((peer, RelayMessage::NotFound(missing)));
+                }
+            }
+            RelayMessage::Object(object) => {
+                let item = object.item();
+                match self.in_flight.get(&item) {
+                    Some(request) if request.peer == peer => {}
+                    _ => return Err(Misbehavior::UnrequestedData),
+                }
+                if object.content_item().as_ref() != Some(&item) {
+                    return Err(match *object {
+                        Object::Block(_) => Misbehavior::InvalidBlock,
+                        Object::Message(_) => Misbehavior::UnrequestedData,
+                    });
+                }
+                self.in_flight.remove(&item);
+                self.sources.remove(&item);
+                self.known_to(peer).insert(&item);
+                handled.received = Some(*object);
+            }
+            RelayMessage::NotFound(items) => {
+                check_length(&items)?;
+                let mut failed = Vec::new();
+                for item in items {
+                    if matches!(self.in_flight.get(&item), Some(request) if request.peer == peer) {
+                        if let Some(sources) = self.sources.get_mut(&item) {
+                            sources.retain(|source| *source != peer);
+                        }
+                        failed.push(item);
+                    }
+                }
+                handled.replies = self.request_elsewhere(failed, now);
+            }
+        }
+
+        Ok(handled)
+    }
+
+    /// Asks another peer for every item whose request has gone unanswered
+    /// for longer than the timeout.
+    pub fn expire(&mut self, now: Instant) -> Outgoing {
+        let mut expired = Vec::new();
+        for (item, request) in &self.in_flight {
+            if now.saturating_duration_since(request.sent) >= self.timeout {
+                if let Some(sources) = self.sources.get_mut(item) {
+                    sources.retain(|source| *source != request.peer);
+                }
+                expired.push(item.clone());
+            }
+        }
+
+        self.request_elsewhere(expired, now)
+    }
+
+    /// Requests each item from the next peer that announced it, or gives
+    /// up on it when none is left.
+    fn request_elsewhere(&mut self, items: Vec<InventoryItem>, now: Instant) -> Outgoing {
+        let mut requests: HashMap<SocketAddr, Vec<InventoryItem>> = HashMap::new();
+        for item in items {
+            let next = self
+                .sources
+                .get(&item)
+                .and_then(|sources| sources.front().copied());
+            match next {
+                Some(peer) => {
+                    self.in_flight
+                        .insert(item.clone(), Request { peer, sent: now });
+                    requests.entry(peer).or_default().push(item);
+                }
+                None => {
+                    self.in_flight.remove(&item);
+                    self.sources.remove(&item);
+                }
+            }
+        }
+
+        requests
+            .into_iter()
+            .map(|(peer, items)| (peer, RelayMessage::GetData(items)))
+            .collect()
+    }
+
+    fn known_to(&mut self, peer: SocketAddr) -> &mut KnownInventory {
+        self.known.entry(peer).or_default()
+    }
+}
+
+fn check_length(items: &[InventoryItem]) -> Result<(), Misbehavior> {
+    if items.len() > MAX_INVENTORY {
+        return Err(Misbehavior::MalformedMessage);
+    }
+
+    Ok(())
+}
+
+/// Queues each message for its peer. Messages for peers that have gone, or
+/// whose queue is full, are dropped; requests for them time out and are
+/// sent elsewhere.
+pub fn send_all(peers: &Mutex<HashMap<SocketAddr, Box<Network>>>, outgoing: Outgoing) {
+    let peers = peers.lock().expect("Unable to lock peers");
+    for (address, message) in outgoing {
+        if let Some(peer) = peers.get(&address) {
+            let _ = peer.try_send_data(message.encode());
+        }
+    }
+}
+
+/// Answers relay messages from `connection` until it closes, passing the
+/// objects it was asked for to `received`. Peers breaking the protocol are
+/// reported, which disconnects them once their score is high enough.
+pub async fn serve(
+    connection: Network,
+    relay: Arc<Mutex<Relay>>,
+    peers: Arc<Mutex<HashMap<SocketAddr, Box<Network>>>>,
+    received: mpsc::Sender<(Object, SocketAddr)>,
+) {
+    let address = connection.remote_address;
+    while let Some(data) = connection.next_data().await {
+        let handled = match RelayMessage::decode(&data) {
+            Some(message) => {
+                relay
+                    .lock()
+                    .expect("Unable to lock relay")
+                    .handle(address, message, Instant::now())
+            }
+            None => Err(Misbehavior::MalformedMessage),
+        };
+
+        match handled {
+            Ok(handled) => {
+                send_all(&peers, handled.replies);
+                if let Some(object) = handled.received {
+                    if received.send((object, address)).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(misbehavior) => {
+                if connection.report(misbehavior) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Runs `connection` until it closes, relaying blocks and messages with the
+/// peer while it is in `peers`.
+pub async fn run_connection(
+    connection: Network,
+    relay: Arc<Mutex<Relay>>,
+    peers: Arc<Mutex<HashMap<SocketAddr, Box<Network>>>>,
+    received: mpsc::Sender<(Object, SocketAddr)>,
+) {
+    let address = connection.remote_address;
+    let mut runner = connection.clone();
+    let served = serve(connection.clone(), relay.clone(), peers.clone(), received);
+    peers
+        .lock()
+        .expect("Unable to lock peers")
+        .insert(address, Box::new(connection));
+    relay
+        .lock()
+        .expect("Unable to lock relay")
+        .add_peer(address);
+
+    tokio::join!(runner.run(), served);
+
+    peers.lock().expect("Unable to lock peers").remove(&address);
+    let outgoing = relay
+        .lock()
+        .expect("Unable to lock relay")
+        .remove_peer(address, Instant::now());
+    send_all(&peers, outgoing);
+}
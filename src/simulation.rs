@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::chain::ChainStatus;
+use crate::handshake::{handshake, HANDSHAKE_TIMEOUT};
+use crate::network::DEFAULT_PORT;
+use crate::relay::{self, Object, REQUEST_TIMEOUT};
+use crate::{
+    Capabilities, Chain, Hello, Identity, Inbox, KeyAlgorithm, MemoryTransport, Message, Network,
+    NoiseKey, Payload, PrivateKey, Relay, Transport,
+};
+
+/// How often nodes take in relayed objects, cut links check whether they
+/// have been healed and `Simulation::wait_until` checks its condition.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Bytes a link reads from a node at once.
+const LINK_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Numbers simulations so those running at once use separate directories.
+static SIMULATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// How the nodes of a simulation are connected to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    /// Each node is connected to the one before it.
+    Line,
+    /// A line whose last node is connected to the first.
+    Ring,
+    /// Every node is connected to the first.
+    Star,
+    /// Every node is connected to every other.
+    Full,
+}
+
+impl Topology {
+    /// The pairs of connected nodes among `nodes` nodes, lowest index first.
+    fn links(self, nodes: usize) -> Vec<(usize, usize)> {
+        match self {
+            Topology::Line => (1..nodes).map(|node| (node - 1, node)).collect(),
+            Topology::Ring => {
+                let mut links = Topology::Line.links(nodes);
+                if nodes > 2 {
+                    links.push((0, nodes - 1));
+                }
+                links
+            }
+            Topology::Star => (1..nodes).map(|node| (0, node)).collect(),
+            Topology::Full => (0..nodes)
+                .flat_map(|first| (first + 1..nodes).map(move |second| (first, second)))
+                .collect(),
+        }
+    }
+}
+
+/// The conditions on a link, shared with the tasks carrying its traffic.
+#[derive(Default)]
+struct LinkState {
+    latency_ms: AtomicU64,
+    /// Traffic is held, not lost, while a link is cut.
+    cut: AtomicBool,
+}
+
+/// Passes what is read from `from` to `to` once the link's latency has gone
+/// by and the link is not cut, until `from` closes.
+async fn carry<R, W>(mut from: R, mut to: W, state: Arc<LinkState>)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (queue, mut queued) = tokio::sync::mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+
+    let delay = state.clone();
+    let reading = async move {
+        let mut buffer = vec![0; LINK_BUFFER_SIZE];
+        loop {
+            let read = match from.read(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => read,
+            };
+            let latency = Duration::from_millis(delay.latency_ms.load(Ordering::Relaxed));
+            if queue
+                .send((Instant::now() + latency, buffer[..read].to_vec()))
+                .is_err()
+            {
+                return;
+            }
+        }
+    };
+    let delivering = async {
+        while let Some((due, bytes)) = queued.recv().await {
+            tokio::time::sleep_until(due).await;
+            while state.cut.load(Ordering::Relaxed) {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            if to.write_all(&bytes).await.is_err() {
+                return;
+            }
+        }
+    };
+
+    // `queue` is dropped once `from` closes, so the traffic still held is
+    // delivered before `to` is closed.
+    tokio::join!(reading, delivering);
+}
+
+/// A node of a simulation, with its own identity, chain directory and
+/// peers.
+struct Node {
+    identity: Identity,
+    address: SocketAddr,
+    noise_key: NoiseKey,
+    chain: Arc<Mutex<Chain>>,
+    status: Arc<Mutex<ChainStatus>>,
+    relay: Arc<Mutex<Relay>>,
+    peers: Arc<Mutex<HashMap<SocketAddr, Box<Network>>>>,
+    relayed: mpsc::Sender<(Object, SocketAddr)>,
+}
+
+impl Node {
+    fn start(index: usize, directory: &Path, shutdown: &CancellationToken) -> Self {
+        let private_key =
+            PrivateKey::generate(KeyAlgorithm::Ed25519).expect("Unable to generate key");
+        let identity = Identity::new(&format!("node-{}", index), private_key);
+        let address = SocketAddr::from(([10, 0, 0, index as u8 + 1], DEFAULT_PORT));
+
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let mut chain = Chain::open(&directory.join(format!("node-{}", index)), peers.clone())
+            .expect("Unable to open the chain");
+        let relay = Arc::new(Mutex::new(Relay::new(REQUEST_TIMEOUT)));
+        let (relayed, received) = mpsc::channel();
+        chain.connect_relay(relay.clone(), received);
+        let status = chain.status();
+        let chain = Arc::new(Mutex::new(chain));
+
+        // Takes in what peers sent, as `Chain::init` does between blocks.
+        let driven = chain.clone();
+        let stop = shutdown.clone();
+        tokio::task::spawn_blocking(move || {
+            while !stop.is_cancelled() {
+                driven
+                    .lock()
+                    .expect("Unable to lock the chain")
+                    .receive_relayed();
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        });
+
+        Node {
+            identity,
+            address,
+            noise_key: NoiseKey::generate(),
+            chain,
+            status,
+            relay,
+            peers,
+            relayed,
+        }
+    }
+
+    async fn handshake(
+        &self,
+        transport: Box<dyn Transport>,
+        initiator: bool,
+        shutdown: &CancellationToken,
+    ) -> Network {
+        let local = Hello::new(
+            &self.identity,
+            &self.status.lock().expect("Unable to lock chain status"),
+            DEFAULT_PORT,
+            Capabilities::RELAY | Capabilities::MINING,
+        );
+        let (stream, peer) = handshake(
+            transport,
+            initiator,
+            &self.noise_key,
+            &local,
+            &self.identity,
+            HANDSHAKE_TIMEOUT,
+        )
+        .await
+        .expect("Handshake failed");
+
+        Network::over(stream)
+            .expect("No peer address")
+            .with_peer(peer)
+            .with_shutdown(shutdown)
+    }
+
+    fn run(&self, connection: Network) {
+        tokio::spawn(relay::run_connection(
+            connection,
+            self.relay.clone(),
+            self.peers.clone(),
+            self.relayed.clone(),
+        ));
+    }
+}
+
+/// Nodes running in one process and connected over in-memory links whose
+/// latency can be changed and which can be cut, so tests can check how
+/// blocks and messages spread between them. The nodes' directories are
+/// removed when the simulation is dropped.
+pub struct Simulation {
+    nodes: Vec<Node>,
+    links: HashMap<(usize, usize), Arc<LinkState>>,
+    directory: PathBuf,
+    shutdown: CancellationToken,
+}
+
+impl Simulation {
+    /// Starts `nodes` nodes, at most 254, connected as `topology`.
+    pub async fn start(nodes: usize, topology: Topology) -> Self {
+        assert!(nodes <= 254, "Nodes are addressed 10.0.0.1 to 10.0.0.254");
+        let directory = std::env::temp_dir().join(format!(
+            "biddy-simulation-{}-{}",
+            std::process::id(),
+            SIMULATIONS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let shutdown = CancellationToken::new();
+        let nodes = (0..nodes)
+            .map(|index| Node::start(index, &directory, &shutdown))
+            .collect();
+
+        let mut simulation = Simulation {
+            nodes,
+            links: HashMap::new(),
+            directory,
+            shutdown,
+        };
+        for (first, second) in topology.links(simulation.nodes.len()) {
+            simulation.connect(first, second).await;
+        }
+
+        simulation
+    }
+
+    async fn connect(&mut self, first: usize, second: usize) {
+        let (near, far) = (&self.nodes[first], &self.nodes[second]);
+        let (near_end, near_wire) = MemoryTransport::pair(near.address, far.address);
+        let (far_end, far_wire) = MemoryTransport::pair(far.address, near.address);
+
+        let state = Arc::new(LinkState::default());
+        let (near_reader, near_writer) = tokio::io::split(near_wire);
+        let (far_reader, far_writer) = tokio::io::split(far_wire);
+        tokio::spawn(carry(near_reader, far_writer, state.clone()));
+        tokio::spawn(carry(far_reader, near_writer, state.clone()));
+        self.links.insert((first, second), state);
+
+        let (near_end, far_end): (Box<dyn Transport>, Box<dyn Transport>) =
+            (Box::new(near_end), Box::new(far_end));
+        let (near_connection, far_connection) = tokio::join!(
+            near.handshake(near_end, true, &self.shutdown),
+            far.handshake(far_end, false, &self.shutdown)
+        );
+        near.run(near_connection);
+        far.run(far_connection);
+    }
+
+    /// Delays traffic both ways between two connected nodes by `latency`.
+    pub fn set_latency(&self, first: usize, second: usize, latency: Duration) {
+        let link = (first.min(second), first.max(second));
+        self.links
+            .get(&link)
+            .expect("The nodes are not connected")
+            .latency_ms
+            .store(latency.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Cuts every link between the nodes in `group` and the others.
+    pub fn partition(&self, group: &[usize]) {
+        for ((first, second), state) in &self.links {
+            if group.contains(first) != group.contains(second) {
+                state.cut.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Restores every cut link, delivering the traffic held on it.
+    pub fn heal(&self) {
+        for state in self.links.values() {
+            state.cut.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Mines the messages waiting on `node`, or an empty block when there
+    /// are none, returning the hash of the last block mined.
+    pub async fn mine(&self, node: usize) -> String {
+        let chain = self.nodes[node].chain.clone();
+        let author = self.nodes[node].identity.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut chain = chain.lock().expect("Unable to lock the chain");
+            let mined = chain.mine_pending(&author).expect("Unable to mine");
+            match mined.last() {
+                Some(hash) => hash.clone(),
+                None => chain
+                    .mine_block(Vec::new(), &author)
+                    .expect("Unable to mine"),
+            }
+        })
+        .await
+        .expect("Unable to join the miner")
+    }
+
+    /// Queues a message with `text` from `from` to `to` on `from`'s chain.
+    pub fn send(&self, from: usize, to: usize, text: &str) {
+        let (sender, recipient) = (&self.nodes[from].identity, &self.nodes[to].identity);
+        let mut message = Message::new(&recipient.public_key, sender, Payload::from(text));
+        message
+            .encrypt(&recipient.public_key)
+            .expect("Unable to encrypt");
+
+        self.chain(from)
+            .submit_message(message)
+            .expect("Unable to submit the message");
+    }
+
+    /// The hash of the block at the tip of `node`'s chain.
+    pub fn tip(&self, node: usize) -> Option<String> {
+        self.chain(node).latest_hash().map(String::from)
+    }
+
+    pub fn pending_messages(&self, node: usize) -> usize {
+        self.chain(node).pending_messages()
+    }
+
+    /// The text of every message on `node`'s chain that it can read.
+    pub fn inbox(&self, node: usize) -> Vec<String> {
+        let mut inbox = Inbox::new(&self.nodes[node].identity);
+        self.chain(node)
+            .scan_inbox(&mut inbox)
+            .expect("Unable to scan the chain");
+
+        inbox
+            .all_messages()
+            .iter()
+            .map(|entry| entry.payload.summary())
+            .collect()
+    }
+
+    /// Whether every node in `nodes` has the same block at the tip of its
+    /// chain.
+    pub fn converged(&self, nodes: &[usize]) -> bool {
+        let tip = match nodes.first() {
+            Some(&node) => self.tip(node),
+            None => return true,
+        };
+
+        tip.is_some() && nodes.iter().all(|&node| self.tip(node) == tip)
+    }
+
+    /// Waits up to `timeout` for `condition` to hold, returning whether it
+    /// did.
+    pub async fn wait_until<F>(&self, timeout: Duration, condition: F) -> bool
+    where
+        F: Fn(&Simulation) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        while !condition(self) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+
+        true
+    }
+
+    fn chain(&self, node: usize) -> std::sync::MutexGuard<'_, Chain> {
+        self.nodes[node]
+            .chain
+            .lock()
+            .expect("Unable to lock the chain")
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
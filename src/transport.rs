@@ -0,0 +1,359 @@
+use snow::{Builder, TransportState};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Every peer connection runs this Noise handshake: both sides prove they
+/// hold a static X25519 key, then traffic is sealed with ChaCha20-Poly1305.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Largest Noise message, including its authentication tag.
+const MAX_MESSAGE_SIZE: usize = 65535;
+const TAG_SIZE: usize = 16;
+/// Most plaintext sealed into a single frame.
+const MAX_PAYLOAD_SIZE: usize = MAX_MESSAGE_SIZE - TAG_SIZE;
+/// Each frame starts with the length of its Noise message as a big endian u16.
+const LENGTH_SIZE: usize = 2;
+/// Bytes an in-memory connection holds in each direction before writes wait.
+const MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A byte stream to a peer that connections can run over, before any
+/// encryption.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {
+    /// The address the other end is reached at.
+    fn peer_address(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for TcpStream {
+    fn peer_address(&self) -> io::Result<SocketAddr> {
+        self.peer_addr()
+    }
+}
+
+/// One end of a connection held in memory, so many nodes can talk to each
+/// other inside a single process.
+pub struct MemoryTransport {
+    stream: DuplexStream,
+    peer_address: SocketAddr,
+}
+
+impl MemoryTransport {
+    /// Connects a node at `first` to a node at `second`, returning the end
+    /// each of them holds.
+    pub fn pair(first: SocketAddr, second: SocketAddr) -> (Self, Self) {
+        let (near, far) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+
+        (
+            MemoryTransport {
+                stream: near,
+                peer_address: second,
+            },
+            MemoryTransport {
+                stream: far,
+                peer_address: first,
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn peer_address(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_address)
+    }
+}
+
+impl AsyncRead for MemoryTransport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+fn builder() -> Builder<'static> {
+    Builder::new(NOISE_PARAMS.parse().expect("Noise parameters are valid"))
+}
+
+fn invalid_data(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// The static X25519 key a node uses for the Noise handshake. It is tied to
+/// the node's identity by the signed `Hello`, so a fresh one is generated
+/// every time the node starts.
+#[derive(Clone)]
+pub struct NoiseKey {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl NoiseKey {
+    pub fn generate() -> Self {
+        let keypair = builder()
+            .generate_keypair()
+            .expect("Unable to generate a Noise key");
+
+        NoiseKey {
+            private: keypair.private,
+            public: keypair.public,
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(io::Error),
+    Noise(snow::Error),
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+impl From<snow::Error> for TransportError {
+    fn from(e: snow::Error) -> Self {
+        TransportError::Noise(e)
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(e) => write!(f, "{}", e),
+            TransportError::Noise(e) => write!(f, "The Noise handshake failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+/// A connection whose traffic is encrypted and authenticated with the keys
+/// agreed in the Noise handshake. Writes are cut into frames of at most
+/// 64 KiB; reads return the decrypted bytes in order.
+pub struct NoiseStream<S> {
+    stream: S,
+    transport: TransportState,
+    handshake_hash: Vec<u8>,
+    /// The frame being read, including its length prefix.
+    incoming: Vec<u8>,
+    received: usize,
+    /// Decrypted bytes not yet returned to the reader.
+    plaintext: Vec<u8>,
+    consumed: usize,
+    /// The sealed frame being written, including its length prefix.
+    outgoing: Vec<u8>,
+    sent: usize,
+}
+
+/// Runs the Noise handshake over `stream`, as the side that opened the
+/// connection if `initiator` is set.
+pub async fn establish<S>(
+    mut stream: S,
+    key: &NoiseKey,
+    initiator: bool,
+) -> Result<NoiseStream<S>, TransportError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let builder = builder().local_private_key(&key.private);
+    let mut state = if initiator {
+        builder.build_initiator()?
+    } else {
+        builder.build_responder()?
+    };
+
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let length = state.write_message(&[], &mut buffer)?;
+            stream.write_u16(length as u16).await?;
+            stream.write_all(&buffer[..length]).await?;
+            stream.flush().await?;
+        } else {
+            let length = stream.read_u16().await? as usize;
+            let mut message = vec![0u8; length];
+            stream.read_exact(&mut message).await?;
+            state.read_message(&message, &mut buffer)?;
+        }
+    }
+
+    Ok(NoiseStream {
+        stream,
+        handshake_hash: state.get_handshake_hash().to_vec(),
+        transport: state.into_transport_mode()?,
+        incoming: vec![0u8; LENGTH_SIZE + MAX_MESSAGE_SIZE],
+        received: 0,
+        plaintext: Vec::new(),
+        consumed: 0,
+        outgoing: Vec::new(),
+        sent: 0,
+    })
+}
+
+impl<S> NoiseStream<S> {
+    /// Identifies this session and is the same on both ends, so signing it
+    /// proves who holds the other end.
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
+    }
+
+    /// The underlying connection.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// The static Noise key the peer proved it holds.
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.transport.get_remote_static()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> NoiseStream<S> {
+    /// Reads and decrypts the next frame into `plaintext`. Returns `false`
+    /// if the connection was closed between frames.
+    fn poll_read_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            let needed = if self.received < LENGTH_SIZE {
+                LENGTH_SIZE
+            } else {
+                let length = u16::from_be_bytes([self.incoming[0], self.incoming[1]]) as usize;
+                if length < TAG_SIZE {
+                    return Poll::Ready(Err(io::ErrorKind::InvalidData.into()));
+                }
+                LENGTH_SIZE + length
+            };
+
+            if self.received == needed && needed > LENGTH_SIZE {
+                self.plaintext.resize(MAX_MESSAGE_SIZE, 0);
+                let length = self
+                    .transport
+                    .read_message(&self.incoming[LENGTH_SIZE..needed], &mut self.plaintext)
+                    .map_err(invalid_data)?;
+                self.plaintext.truncate(length);
+                self.consumed = 0;
+                self.received = 0;
+                return Poll::Ready(Ok(true));
+            }
+
+            let mut buffer = ReadBuf::new(&mut self.incoming[self.received..needed]);
+            futures::ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buffer))?;
+            let read = buffer.filled().len();
+            if read == 0 {
+                if self.received == 0 {
+                    return Poll::Ready(Ok(false));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.received += read;
+        }
+    }
+
+    /// Writes out what is left of the last sealed frame.
+    fn poll_write_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.sent < self.outgoing.len() {
+            let written = futures::ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.outgoing[self.sent..])
+            )?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sent += written;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.consumed < this.plaintext.len() {
+                let length = buf.remaining().min(this.plaintext.len() - this.consumed);
+                buf.put_slice(&this.plaintext[this.consumed..this.consumed + length]);
+                this.consumed += length;
+                return Poll::Ready(Ok(()));
+            }
+
+            if !futures::ready!(this.poll_read_frame(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        futures::ready!(this.poll_write_frame(cx))?;
+
+        let length = buf.len().min(MAX_PAYLOAD_SIZE);
+        this.outgoing.resize(LENGTH_SIZE + MAX_MESSAGE_SIZE, 0);
+        let sealed = this
+            .transport
+            .write_message(&buf[..length], &mut this.outgoing[LENGTH_SIZE..])
+            .map_err(invalid_data)?;
+        this.outgoing[..LENGTH_SIZE].copy_from_slice(&(sealed as u16).to_be_bytes());
+        this.outgoing.truncate(LENGTH_SIZE + sealed);
+        this.sent = 0;
+
+        // The frame is accepted once sealed; whatever does not go out now
+        // is written by the next write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_write_frame(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(length))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_write_frame(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_write_frame(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
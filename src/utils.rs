@@ -1,86 +1,676 @@
-use rand::rngs::OsRng;
+use bip39::Mnemonic;
 use rsa::{
-    pkcs1::{FromRsaPrivateKey, FromRsaPublicKey, ToRsaPrivateKey, ToRsaPublicKey},
-    RsaPrivateKey, RsaPublicKey,
+    pkcs1::{FromRsaPublicKey, ToRsaPublicKey},
+    RsaPublicKey,
 };
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 
-pub fn get_keys() -> (RsaPrivateKey, RsaPublicKey) {
-    let current_dir = std::fs::read_dir(".");
-    let public_key: RsaPublicKey;
-    let private_key: RsaPrivateKey;
+use crate::contacts::{ContactBook, ContactError};
+use crate::formats::{
+    self, ContactCard, ExportFormat, FormatError, ENCRYPTED_PRIVATE_KEY_HEADER,
+    PKCS1_PRIVATE_KEY_HEADER,
+};
+use crate::identity::Identity;
+use crate::keys::{KeyAlgorithm, PrivateKey, PublicKey, ED25519_KEY_PREFIX};
 
-    let mut has_private_key = false;
-    let mut has_public_key = false;
+const DEFAULT_KEYSTORE_LOCATION: &str = "./keystore";
+const ACTIVE_IDENTITY_FILE: &str = "active";
+const PRIVATE_KEY_EXTENSION: &str = "key";
+const PUBLIC_KEY_EXTENSION: &str = "pub";
+const CONTACT_BOOK_FILE: &str = "contacts.json";
+/// Where contact cards were saved one per file before the contact book.
+const LEGACY_CONTACTS_DIRECTORY: &str = "contacts";
+const LEGACY_CONTACTS_MIGRATED: &str = "contacts.migrated";
+const BAN_LIST_FILE: &str = "bans.json";
 
-    match current_dir {
-        Ok(dir) => {
-            for d in dir {
-                let file = d.expect("Unable to read directory");
-                if file.file_name() == "biddykey.pub" {
-                    has_public_key = true;
-                }
-                if file.file_name() == "biddykey" {
-                    has_private_key = true;
+const LEGACY_PRIVATE_KEY_PATH: &str = "./biddykey";
+const LEGACY_PUBLIC_KEY_PATH: &str = "./biddykey.pub";
+
+/// Identity used when none has been selected.
+pub const DEFAULT_IDENTITY: &str = "default";
+
+/// Environment variable overriding the keystore directory.
+pub const KEYSTORE_ENV: &str = "BIDDYKEY_KEYSTORE";
+/// Environment variable selecting the active identity.
+pub const IDENTITY_ENV: &str = "BIDDYKEY_IDENTITY";
+/// Environment variable holding the passphrase for the private key.
+pub const PASSPHRASE_ENV: &str = "BIDDYKEY_PASSPHRASE";
+/// Environment variable naming a file whose first line is the passphrase.
+pub const PASSPHRASE_FILE_ENV: &str = "BIDDYKEY_PASSPHRASE_FILE";
+/// Environment variable holding the new passphrase for `change_passphrase`.
+pub const NEW_PASSPHRASE_ENV: &str = "BIDDYKEY_NEW_PASSPHRASE";
+/// Environment variable holding the recovery phrase for `restore`.
+pub const MNEMONIC_ENV: &str = "BIDDYKEY_MNEMONIC";
+/// Environment variable holding the optional passphrase of a recovery phrase.
+pub const RECOVERY_PASSPHRASE_ENV: &str = "BIDDYKEY_RECOVERY_PASSPHRASE";
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    PermissionDenied(PathBuf),
+    /// A key file exists but could not be parsed.
+    Malformed(PathBuf),
+    /// The private key could not be decrypted, usually because the
+    /// passphrase is wrong.
+    WrongPassphrase(PathBuf),
+    /// The stored public key does not belong to the stored private key.
+    KeyMismatch(String),
+    InvalidName(String),
+    NotFound(String),
+    AlreadyExists(String),
+    EmptyPassphrase,
+    PassphraseMismatch,
+    /// Reading the passphrase from the terminal or passphrase file failed.
+    Passphrase(std::io::Error),
+    Rsa(rsa::errors::Error),
+    Encryption,
+    InvalidMnemonic(bip39::Error),
+    /// Imported or exported key data is invalid.
+    Format(FormatError),
+}
+
+impl KeyError {
+    fn io(path: &Path, error: std::io::Error) -> Self {
+        match error.kind() {
+            ErrorKind::PermissionDenied => KeyError::PermissionDenied(path.to_path_buf()),
+            _ => KeyError::Io {
+                path: path.to_path_buf(),
+                error,
+            },
+        }
+    }
+}
+
+impl From<rsa::errors::Error> for KeyError {
+    fn from(e: rsa::errors::Error) -> Self {
+        KeyError::Rsa(e)
+    }
+}
+
+impl From<FormatError> for KeyError {
+    fn from(e: FormatError) -> Self {
+        KeyError::Format(e)
+    }
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            KeyError::PermissionDenied(path) => {
+                write!(f, "{}: permission denied", path.display())
+            }
+            KeyError::Malformed(path) => write!(f, "{} is not a valid key file", path.display()),
+            KeyError::WrongPassphrase(path) => write!(
+                f,
+                "Unable to decrypt {}, is the passphrase correct?",
+                path.display()
+            ),
+            KeyError::KeyMismatch(name) => write!(
+                f,
+                "The public key of {:?} does not match its private key",
+                name
+            ),
+            KeyError::InvalidName(name) => write!(
+                f,
+                "{:?} is not a valid identity name, use only letters, digits, '-' and '_'",
+                name
+            ),
+            KeyError::NotFound(name) => write!(f, "There is no identity named {:?}", name),
+            KeyError::AlreadyExists(name) => {
+                write!(f, "An identity named {:?} already exists", name)
+            }
+            KeyError::EmptyPassphrase => write!(f, "The passphrase is empty"),
+            KeyError::PassphraseMismatch => write!(f, "The passphrases do not match"),
+            KeyError::Passphrase(e) => write!(f, "Unable to read the passphrase: {}", e),
+            KeyError::Rsa(e) => write!(f, "{}", e),
+            KeyError::Encryption => write!(f, "Unable to encrypt the private key"),
+            KeyError::InvalidMnemonic(e) => write!(f, "Invalid recovery phrase: {}", e),
+            KeyError::Format(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// A directory of named identities. Each identity is stored as `<name>.key`
+/// (passphrase encrypted PKCS#8) and `<name>.pub` (PKCS#1), and the `active`
+/// file records which identity is used when none is given. The contact book
+/// is kept in `contacts.json`.
+pub struct Keystore {
+    directory: PathBuf,
+}
+
+impl Keystore {
+    pub fn open(directory: &Path) -> Result<Self, KeyError> {
+        std::fs::create_dir_all(directory).map_err(|e| KeyError::io(directory, e))?;
+
+        Ok(Keystore {
+            directory: directory.to_path_buf(),
+        })
+    }
+
+    /// Opens the keystore named by `BIDDYKEY_KEYSTORE`, or `./keystore`. A key
+    /// pair left in the working directory by older versions is moved in as the
+    /// default identity.
+    pub fn open_default() -> Result<Self, KeyError> {
+        let directory = match std::env::var(KEYSTORE_ENV) {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => PathBuf::from(DEFAULT_KEYSTORE_LOCATION),
+        };
+        let keystore = Keystore::open(&directory)?;
+        keystore.migrate_legacy_keys()?;
+
+        Ok(keystore)
+    }
+
+    fn migrate_legacy_keys(&self) -> Result<(), KeyError> {
+        let legacy_private = Path::new(LEGACY_PRIVATE_KEY_PATH);
+        if !legacy_private.exists() || self.contains(DEFAULT_IDENTITY) {
+            return Ok(());
+        }
+
+        std::fs::rename(legacy_private, self.private_key_path(DEFAULT_IDENTITY))
+            .map_err(|e| KeyError::io(legacy_private, e))?;
+
+        let legacy_public = Path::new(LEGACY_PUBLIC_KEY_PATH);
+        if legacy_public.exists() {
+            std::fs::rename(legacy_public, self.public_key_path(DEFAULT_IDENTITY))
+                .map_err(|e| KeyError::io(legacy_public, e))?;
+        }
+
+        println!(
+            "Moved {} into the keystore as identity {:?}",
+            LEGACY_PRIVATE_KEY_PATH, DEFAULT_IDENTITY
+        );
+
+        Ok(())
+    }
+
+    fn private_key_path(&self, name: &str) -> PathBuf {
+        self.directory
+            .join(format!("{}.{}", name, PRIVATE_KEY_EXTENSION))
+    }
+
+    fn public_key_path(&self, name: &str) -> PathBuf {
+        self.directory
+            .join(format!("{}.{}", name, PUBLIC_KEY_EXTENSION))
+    }
+
+    /// Reads the stored public key of `name` without needing its passphrase.
+    pub fn public_key(&self, name: &str) -> Result<PublicKey, KeyError> {
+        validate_name(name)?;
+
+        get_public_key(&self.public_key_path(name))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.private_key_path(name).exists()
+    }
+
+    /// Names of every identity in the keystore, sorted.
+    pub fn identities(&self) -> Result<Vec<String>, KeyError> {
+        let mut names: Vec<String> = Vec::new();
+        let dir =
+            std::fs::read_dir(&self.directory).map_err(|e| KeyError::io(&self.directory, e))?;
+
+        for d in dir {
+            let path = d.map_err(|e| KeyError::io(&self.directory, e))?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(PRIVATE_KEY_EXTENSION) {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                names.push(String::from(name));
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    /// The identity named by `BIDDYKEY_IDENTITY`, then the `active` file,
+    /// falling back to `DEFAULT_IDENTITY`.
+    pub fn active_identity(&self) -> Result<String, KeyError> {
+        if let Ok(name) = std::env::var(IDENTITY_ENV) {
+            return Ok(name);
+        }
+
+        let path = self.directory.join(ACTIVE_IDENTITY_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(name) if !name.trim().is_empty() => Ok(String::from(name.trim())),
+            Ok(_) => Ok(String::from(DEFAULT_IDENTITY)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::from(DEFAULT_IDENTITY)),
+            Err(e) => Err(KeyError::io(&path, e)),
+        }
+    }
+
+    pub fn set_active_identity(&self, name: &str) -> Result<(), KeyError> {
+        validate_name(name)?;
+        if !self.contains(name) {
+            return Err(KeyError::NotFound(String::from(name)));
+        }
+
+        let path = self.directory.join(ACTIVE_IDENTITY_FILE);
+        std::fs::write(&path, name).map_err(|e| KeyError::io(&path, e))
+    }
+
+    /// Checks that a new identity can be stored under `name`.
+    pub fn check_available(&self, name: &str) -> Result<(), KeyError> {
+        validate_name(name)?;
+        if self.contains(name) {
+            return Err(KeyError::AlreadyExists(String::from(name)));
+        }
+
+        Ok(())
+    }
+
+    /// Generates a new key pair of `algorithm` and stores it under `name`.
+    pub fn create_identity(
+        &self,
+        name: &str,
+        passphrase: &str,
+        algorithm: KeyAlgorithm,
+    ) -> Result<Identity, KeyError> {
+        self.check_available(name)?;
+
+        let identity = Identity::new(name, PrivateKey::generate(algorithm)?);
+        self.store_identity(&identity, passphrase)?;
+
+        Ok(identity)
+    }
+
+    /// Writes `identity` into the keystore, replacing any key of the same name.
+    pub fn store_identity(&self, identity: &Identity, passphrase: &str) -> Result<(), KeyError> {
+        validate_name(&identity.name)?;
+
+        write_private_key(
+            &self.private_key_path(&identity.name),
+            &identity.private_key,
+            passphrase,
+        )?;
+        generate_public_key(&self.public_key_path(&identity.name), &identity.private_key)?;
+
+        Ok(())
+    }
+
+    pub fn load_identity(&self, name: &str, passphrase: &str) -> Result<Identity, KeyError> {
+        validate_name(name)?;
+
+        let private_path = self.private_key_path(name);
+        if !private_path.exists() {
+            return Err(KeyError::NotFound(String::from(name)));
+        }
+
+        let private_key = get_private_key(&private_path, passphrase)?;
+        let public_path = self.public_key_path(name);
+        if !public_path.exists() {
+            generate_public_key(&public_path, &private_key)?;
+        } else if get_public_key(&public_path)? != private_key.public_key() {
+            return Err(KeyError::KeyMismatch(String::from(name)));
+        }
+
+        Ok(Identity::new(name, private_key))
+    }
+
+    /// Derives an identity from a recovery phrase and its optional
+    /// `recovery_passphrase`, and stores it as `name` under `passphrase`. An
+    /// existing identity of that name is only replaced when it holds the same
+    /// key, so restoring twice is harmless.
+    pub fn restore_identity(
+        &self,
+        name: &str,
+        mnemonic: &Mnemonic,
+        recovery_passphrase: &str,
+        passphrase: &str,
+    ) -> Result<Identity, KeyError> {
+        validate_name(name)?;
+
+        let private_key = PrivateKey::from_mnemonic(mnemonic, recovery_passphrase);
+        let identity = Identity::new(name, private_key);
+        if self.contains(name) && self.public_key(name)? != identity.public_key {
+            return Err(KeyError::AlreadyExists(String::from(name)));
+        }
+        self.store_identity(&identity, passphrase)?;
+
+        Ok(identity)
+    }
+
+    /// Exports the identity `name` in a private key format, encrypted under
+    /// its keystore passphrase.
+    pub fn export_private_key(
+        &self,
+        name: &str,
+        passphrase: &str,
+        format: ExportFormat,
+    ) -> Result<Vec<u8>, KeyError> {
+        let identity = self.load_identity(name, passphrase)?;
+        let document = formats::encrypt_private_key(&identity.private_key, passphrase)?;
+
+        match format {
+            ExportFormat::Pkcs8Pem => Ok(document.to_pem().as_bytes().to_vec()),
+            ExportFormat::Pkcs8Der => Ok(document.as_ref().to_vec()),
+            ExportFormat::OpenSsh | ExportFormat::Contact => self.export_public_key(name, format),
+        }
+    }
+
+    /// Exports the public key of `name` as an OpenSSH line or a contact card.
+    pub fn export_public_key(&self, name: &str, format: ExportFormat) -> Result<Vec<u8>, KeyError> {
+        let public_key = self.public_key(name)?;
+
+        let exported = match format {
+            ExportFormat::Contact => ContactCard::new(name, &public_key).to_json(),
+            _ => formats::to_openssh(&public_key, name),
+        };
+
+        Ok(format!("{}\n", exported).into_bytes())
+    }
+
+    /// Stores a private key read by `formats::decode_private_key` as the new
+    /// identity `name`, encrypted under `passphrase`.
+    pub fn import_identity(
+        &self,
+        name: &str,
+        data: &[u8],
+        passphrase: &str,
+    ) -> Result<Identity, KeyError> {
+        validate_name(name)?;
+        if self.contains(name) {
+            return Err(KeyError::AlreadyExists(String::from(name)));
+        }
+
+        let identity = Identity::new(name, formats::decode_private_key(data, passphrase)?);
+        self.store_identity(&identity, passphrase)?;
+
+        Ok(identity)
+    }
+
+    /// The contact book kept alongside the identities. Cards saved in
+    /// `contacts/` by older versions are added to it the first time.
+    pub fn contact_book(&self) -> Result<ContactBook, ContactError> {
+        let mut contacts = ContactBook::open(&self.directory.join(CONTACT_BOOK_FILE))?;
+        self.migrate_legacy_contacts(&mut contacts)?;
+
+        Ok(contacts)
+    }
+
+    /// Adds every card in the legacy contacts directory to `contacts`, then
+    /// renames the directory so it is only read once. A card whose name is
+    /// taken is added under its address instead.
+    fn migrate_legacy_contacts(&self, contacts: &mut ContactBook) -> Result<(), ContactError> {
+        let legacy = self.directory.join(LEGACY_CONTACTS_DIRECTORY);
+        if !legacy.is_dir() {
+            return Ok(());
+        }
+        let io = |path: &Path, error| ContactError::Io {
+            path: path.to_path_buf(),
+            error,
+        };
+
+        let mut moved = 0;
+        for entry in std::fs::read_dir(&legacy).map_err(|e| io(&legacy, e))? {
+            let path = entry.map_err(|e| io(&legacy, e))?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+
+            let json = std::fs::read_to_string(&path).map_err(|e| io(&path, e))?;
+            // Cards that no longer verify were never listed, so they are
+            // left behind.
+            let card = match ContactCard::from_json(&json) {
+                Ok(card) => card,
+                Err(_) => continue,
+            };
+            if let Ok(public_key) = card.verify() {
+                if contacts.learn(&card.name, &public_key)
+                    || contacts.learn(&card.address, &public_key)
+                {
+                    moved += 1;
                 }
             }
         }
-        Err(_) => {}
+
+        contacts.save()?;
+        let migrated = self.directory.join(LEGACY_CONTACTS_MIGRATED);
+        std::fs::rename(&legacy, &migrated).map_err(|e| io(&legacy, e))?;
+        println!(
+            "Moved {} contacts from {} into {}",
+            moved,
+            legacy.display(),
+            CONTACT_BOOK_FILE
+        );
+
+        Ok(())
     }
 
-    if !has_public_key && has_private_key {
-        private_key = get_private_key().expect("Unable to get private key");
-        public_key = generate_public_key(&private_key).expect("Unable to generate public key");
-    } else if !has_private_key {
-        private_key = generate_private_key().expect("Unable to generate private key");
-        public_key = generate_public_key(&private_key).expect("Unable to generate public key");
-    } else {
-        private_key = get_private_key().expect("Unable to get private key");
-        public_key = get_public_key().expect("Unable to get public key");
+    /// Where the node keeps the addresses it refuses connections from.
+    pub fn ban_list_path(&self) -> PathBuf {
+        self.directory.join(BAN_LIST_FILE)
+    }
+
+    /// Loads `name`, creating it with a new `algorithm` key if it does not
+    /// exist yet.
+    pub fn load_or_create_identity(
+        &self,
+        name: &str,
+        passphrase: &str,
+        algorithm: KeyAlgorithm,
+    ) -> Result<Identity, KeyError> {
+        if self.contains(name) {
+            self.load_identity(name, passphrase)
+        } else {
+            self.create_identity(name, passphrase, algorithm)
+        }
+    }
+
+    /// Re-encrypts the private key of `name` under a new passphrase without
+    /// changing the key itself.
+    pub fn change_passphrase(
+        &self,
+        name: &str,
+        current: &str,
+        new_passphrase: &str,
+    ) -> Result<(), KeyError> {
+        let identity = self.load_identity(name, current)?;
+
+        write_private_key(
+            &self.private_key_path(name),
+            &identity.private_key,
+            new_passphrase,
+        )
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), KeyError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !valid {
+        return Err(KeyError::InvalidName(String::from(name)));
+    }
+
+    Ok(())
+}
+
+/// Reads the key passphrase from `BIDDYKEY_PASSPHRASE`, then from the file named
+/// by `BIDDYKEY_PASSPHRASE_FILE`, and finally by prompting on the terminal.
+pub fn read_passphrase() -> Result<String, KeyError> {
+    read_passphrase_from(PASSPHRASE_ENV, "Key passphrase: ")
+}
+
+fn read_passphrase_from(env_var: &str, prompt: &str) -> Result<String, KeyError> {
+    if let Ok(passphrase) = std::env::var(env_var) {
+        return non_empty(passphrase);
+    }
+
+    if env_var == PASSPHRASE_ENV {
+        if let Ok(path) = std::env::var(PASSPHRASE_FILE_ENV) {
+            let contents =
+                std::fs::read_to_string(&path).map_err(|e| KeyError::io(Path::new(&path), e))?;
+            let passphrase = contents.lines().next().unwrap_or("");
+
+            return non_empty(String::from(passphrase));
+        }
+    }
+
+    non_empty(rpassword::read_password_from_tty(Some(prompt)).map_err(KeyError::Passphrase)?)
+}
+
+fn non_empty(passphrase: String) -> Result<String, KeyError> {
+    if passphrase.is_empty() {
+        return Err(KeyError::EmptyPassphrase);
+    }
+
+    Ok(passphrase)
+}
+
+/// Reads a recovery phrase from `BIDDYKEY_MNEMONIC`, or prompts for it.
+pub fn read_mnemonic() -> Result<Mnemonic, KeyError> {
+    let phrase = match std::env::var(MNEMONIC_ENV) {
+        Ok(phrase) => phrase,
+        Err(_) => rpassword::read_password_from_tty(Some("Recovery phrase: "))
+            .map_err(KeyError::Passphrase)?,
     };
 
-    return (private_key, public_key);
+    Mnemonic::parse(phrase.trim().to_lowercase()).map_err(KeyError::InvalidMnemonic)
 }
 
-fn generate_public_key(private_key: &RsaPrivateKey) -> Result<RsaPublicKey, std::io::Error> {
-    let public_key = RsaPublicKey::from(private_key);
+/// Reads the optional passphrase of a recovery phrase from
+/// `BIDDYKEY_RECOVERY_PASSPHRASE`, or prompts for it, twice when `confirm` is
+/// set. It may be empty.
+pub fn read_recovery_passphrase(confirm: bool) -> Result<String, KeyError> {
+    if let Ok(passphrase) = std::env::var(RECOVERY_PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+
+    let first = rpassword::read_password_from_tty(Some("Recovery passphrase (optional): "))
+        .map_err(KeyError::Passphrase)?;
+    if confirm {
+        let second = rpassword::read_password_from_tty(Some("Repeat recovery passphrase: "))
+            .map_err(KeyError::Passphrase)?;
+        if first != second {
+            return Err(KeyError::PassphraseMismatch);
+        }
+    }
 
-    let path = std::path::Path::new("./biddykey.pub");
+    Ok(first)
+}
 
-    public_key
-        .write_pkcs1_pem_file(path)
-        .expect("Unable to write public key to file");
+/// Reads a new passphrase from `BIDDYKEY_NEW_PASSPHRASE`, or prompts for it twice.
+pub fn read_new_passphrase() -> Result<String, KeyError> {
+    if let Ok(passphrase) = std::env::var(NEW_PASSPHRASE_ENV) {
+        return non_empty(passphrase);
+    }
 
-    return Ok(public_key);
+    let first = read_passphrase_from(NEW_PASSPHRASE_ENV, "New passphrase: ")?;
+    let second = rpassword::read_password_from_tty(Some("Repeat new passphrase: "))
+        .map_err(KeyError::Passphrase)?;
+    if first != second {
+        return Err(KeyError::PassphraseMismatch);
+    }
+
+    Ok(first)
+}
+
+/// Writes the public key next to the private key: PKCS#1 PEM for RSA, and
+/// the tagged encoding of `PublicKey::encode` for Ed25519.
+fn generate_public_key(path: &Path, private_key: &PrivateKey) -> Result<PublicKey, KeyError> {
+    let public_key = private_key.public_key();
+
+    let contents = match &public_key {
+        PublicKey::Rsa(key) => key
+            .to_pkcs1_pem()
+            .map_err(|_| KeyError::Malformed(path.to_path_buf()))?,
+        PublicKey::Ed25519 { .. } => public_key.encode(),
+    };
+    std::fs::write(path, contents.as_bytes()).map_err(|e| KeyError::io(path, e))?;
+
+    Ok(public_key)
+}
+
+/// Stores the private key as a passphrase encrypted PKCS#8 PEM, readable only
+/// by the current user.
+fn write_private_key(
+    path: &Path,
+    private_key: &PrivateKey,
+    passphrase: &str,
+) -> Result<(), KeyError> {
+    let pem = formats::encrypt_private_key(private_key, passphrase)
+        .map_err(|_| KeyError::Encryption)?
+        .to_pem();
+
+    let temp_path = path.with_extension("tmp");
+    write_private_file(&temp_path, pem.as_bytes()).map_err(|e| KeyError::io(&temp_path, e))?;
+    std::fs::rename(&temp_path, path).map_err(|e| KeyError::io(path, e))?;
+
+    Ok(())
 }
 
-fn generate_private_key() -> Result<RsaPrivateKey, std::io::Error> {
-    let mut rng = OsRng;
+/// Writes `contents` to a new file at `path` that is created readable only by
+/// the current user, so the key is never on disk with wider permissions. A
+/// leftover file from an interrupted write is replaced.
+fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    use std::io::Write;
 
-    let private_key = RsaPrivateKey::new(&mut rng, 4096).expect("Unable to generate Private Key");
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
 
-    let path = std::path::Path::new("./biddykey");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
 
-    private_key
-        .write_pkcs1_pem_file(path)
-        .expect("Unable to write private key to file");
+        options.mode(0o600);
+    }
 
-    return Ok(private_key);
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
 }
 
-fn get_public_key() -> Result<RsaPublicKey, std::io::Error> {
-    let path = std::path::Path::new("./biddykey.pub");
+fn get_public_key(path: &Path) -> Result<PublicKey, KeyError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| KeyError::io(path, e))?;
 
-    let public_key =
-        RsaPublicKey::read_pkcs1_pem_file(path).expect("Unable to read public key from file");
+    let public_key = if contents.trim_start().starts_with(ED25519_KEY_PREFIX) {
+        PublicKey::decode(contents.trim())
+    } else {
+        RsaPublicKey::from_pkcs1_pem(&contents)
+            .ok()
+            .map(PublicKey::Rsa)
+    };
 
-    return Ok(public_key);
+    public_key.ok_or_else(|| KeyError::Malformed(path.to_path_buf()))
 }
 
-fn get_private_key() -> Result<RsaPrivateKey, std::io::Error> {
-    let path = std::path::Path::new("./biddykey");
+/// Loads a private key, decrypting it with `passphrase`. A key still stored in
+/// the old unencrypted PKCS#1 form is read and immediately rewritten encrypted.
+fn get_private_key(path: &Path, passphrase: &str) -> Result<PrivateKey, KeyError> {
+    let pem = std::fs::read_to_string(path).map_err(|e| KeyError::io(path, e))?;
 
-    let private_key =
-        RsaPrivateKey::read_pkcs1_pem_file(path).expect("Unable to read private key from file");
+    let legacy = pem.trim_start().starts_with(PKCS1_PRIVATE_KEY_HEADER);
+    if !legacy && !pem.trim_start().starts_with(ENCRYPTED_PRIVATE_KEY_HEADER) {
+        return Err(KeyError::Malformed(path.to_path_buf()));
+    }
+
+    let private_key = match formats::decode_private_key(pem.as_bytes(), passphrase) {
+        Ok(private_key) => private_key,
+        Err(FormatError::WrongPassphrase) => {
+            return Err(KeyError::WrongPassphrase(path.to_path_buf()))
+        }
+        Err(_) => return Err(KeyError::Malformed(path.to_path_buf())),
+    };
+    if legacy {
+        write_private_key(path, &private_key, passphrase)?;
+    }
 
-    return Ok(private_key);
+    Ok(private_key)
 }